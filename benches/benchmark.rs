@@ -1,7 +1,7 @@
 use std::fs::File;
 use std::io::{BufReader, Cursor};
 
-use backhand::{FilesystemReader, FilesystemWriter};
+use backhand::{FilesystemReader, FilesystemWriter, NodeHeader};
 use criterion::*;
 use test_assets::TestAssetDef;
 
@@ -106,5 +106,31 @@ pub fn bench_read(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_read_write, bench_read);
+pub fn bench_read_large_tree(c: &mut Criterion) {
+    let mut group = c.benchmark_group("large_tree");
+    group.sampling_mode(SamplingMode::Flat);
+    group.sample_size(10);
+
+    // many directories of many files, making for a large directory table
+    let header = NodeHeader::default();
+    let mut fs = FilesystemWriter::default();
+    for d in 0..500 {
+        let dir = format!("dir_{d:04}");
+        fs.push_dir(&dir, header).unwrap();
+        for f in 0..100 {
+            fs.push_file(Cursor::new(vec![]), format!("{dir}/file_{f:04}"), header).unwrap();
+        }
+    }
+    let mut image = Cursor::new(vec![]);
+    fs.write(&mut image).unwrap();
+    let image = image.into_inner();
+
+    group.bench_function("read_50000_files", |b| {
+        b.iter(|| black_box(FilesystemReader::from_reader(Cursor::new(&image)).unwrap()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_read_write, bench_read, bench_read_large_tree);
 criterion_main!(benches);
@@ -0,0 +1,99 @@
+#[path = "../../common/common.rs"]
+mod common;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use backhand::kind::Kind;
+use backhand::Squashfs;
+use clap::builder::PossibleValuesParser;
+use clap::Parser;
+use common::after_help;
+
+// -musl malloc is slow, use jemalloc
+#[cfg(all(target_env = "musl", target_pointer_width = "64"))]
+#[global_allocator]
+static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+/// tool to check the integrity of squashfs filesystems
+///
+/// Exits with a non-zero status if any problem is found
+#[derive(Parser, Debug)]
+#[command(author,
+          version,
+          name = "fsck-backhand",
+          after_help = after_help(false),
+          max_term_width = 98,
+)]
+struct Args {
+    /// Squashfs input image
+    image: PathBuf,
+
+    /// Skip BYTES at the start of IMAGE
+    #[arg(short, long, default_value_t = 0, name = "BYTES")]
+    offset: u64,
+
+    /// Kind(type of image) to parse
+    #[arg(short,
+          long,
+          default_value = "le_v4_0",
+          value_parser = PossibleValuesParser::new(
+          [
+              "be_v4_0",
+              "le_v4_0",
+              "avm_be_v4_0",
+          ]
+    ))]
+    kind: String,
+
+    /// Only print the problems found, not the summary
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+fn main() -> ExitCode {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+    let kind = Kind::from_target(&args.kind).unwrap();
+
+    let file = match File::open(&args.image) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            eprintln!("[!] {}: {e}", args.image.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let report = match Squashfs::verify(file, args.offset, kind) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("[!] {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for problem in &report.problems {
+        eprintln!("[!] {problem}");
+    }
+    if !args.quiet {
+        println!(
+            "checked {} metadata blocks, {} inodes, {} directories, {} data blocks, {} fragments",
+            report.metadata_blocks,
+            report.inodes,
+            report.directories,
+            report.data_blocks,
+            report.fragments,
+        );
+    }
+
+    if report.is_ok() {
+        if !args.quiet {
+            println!("no problems found in {}", args.image.display());
+        }
+        ExitCode::SUCCESS
+    } else {
+        eprintln!("{} problems found in {}", report.problems.len(), args.image.display());
+        ExitCode::FAILURE
+    }
+}
@@ -1,31 +1,35 @@
 #[path = "../../common/common.rs"]
 mod common;
+use std::borrow::Cow;
 use std::collections::HashSet;
-use std::fs::{self, File, Permissions};
-use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom};
 use std::iter::Iterator;
-use std::os::unix::prelude::{OsStrExt, PermissionsExt};
-use std::path::{Component, Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::sync::Mutex;
 
+use backhand::compression::CompressionOptions;
 use backhand::kind::Kind;
 use backhand::{
-    BufReadSeek, FilesystemReader, InnerNode, Node, NodeHeader, Squashfs, SquashfsBlockDevice,
-    SquashfsCharacterDevice, SquashfsDir, SquashfsFileReader, SquashfsSymlink,
+    BackhandError, BufReadSeek, Damage, ExtractEvent, ExtractOptions, FilesystemReader, InnerNode,
+    Node, Pattern, Squashfs, SquashfsFileReader, SquashfsSymlink, SuperBlock, TarSink,
 };
 use clap::builder::PossibleValuesParser;
-use clap::{CommandFactory, Parser};
+use clap::{ArgAction, CommandFactory, Parser};
 use clap_complete::{generate, Shell};
 use common::after_help;
 use console::Term;
 use indicatif::{HumanDuration, ProgressBar, ProgressStyle};
-use libc::lchown;
 use nix::libc::geteuid;
-use nix::sys::stat::{dev_t, mknod, mode_t, umask, utimensat, utimes, Mode, SFlag, UtimensatFlags};
-use nix::sys::time::{TimeSpec, TimeVal};
-use rayon::prelude::*;
+use nix::sys::stat::{umask, Mode};
+use serde_json::{json, Map, Value};
 use std::time::{Duration, Instant};
+use tracing_subscriber::EnvFilter;
 
 // -musl malloc is slow, use jemalloc
 #[cfg(all(target_env = "musl", target_pointer_width = "64"))]
@@ -42,17 +46,10 @@ pub fn required_root(a: &str) -> Result<PathBuf, String> {
     }
 }
 
-fn find_offset(file: &mut BufReader<File>, kind: &Kind) -> Option<u64> {
-    let mut magic = [0_u8; 4];
-    while file.read_exact(&mut magic).is_ok() {
-        if magic == kind.magic() {
-            let found = file.stream_position().unwrap() - magic.len() as u64;
-            file.rewind().unwrap();
-            return Some(found);
-        }
-    }
-    file.rewind().unwrap();
-    None
+/// Offset of the first valid image of `kind` in `file`
+fn find_offset(file: &File, kind: &str) -> Option<u64> {
+    let images = Squashfs::scan(file).ok()?;
+    images.into_iter().find(|image| image.name == kind).map(|image| image.offset)
 }
 
 pub fn extracted(pb: &ProgressBar, s: &str) {
@@ -104,9 +101,37 @@ struct Args {
     #[arg(short, long)]
     auto_offset: bool,
 
+    /// Find all images of any kind in FILESYSTEM, and list or extract each of them
+    ///
+    /// Each image is extracted to {arg.dest}/{offset of image}, --offset and --kind are ignored.
+    #[arg(long, conflicts_with_all = ["auto_offset", "stat"])]
+    scan: bool,
+
     /// List filesystem, do not write to DEST (ignores --quiet)
-    #[arg(short, long)]
-    list: bool,
+    ///
+    /// Given twice (-ll), also list the type, permissions, uid/gid, size and modification time of
+    /// each node, along with the target of symlinks and the major,minor numbers of devices.
+    #[arg(short, long, action = ArgAction::Count)]
+    list: u8,
+
+    /// Write the contents of the selected files to stdout, instead of extracting to DEST
+    ///
+    /// Files are written in the order of --path-filter, a --path-filter that is a symlink to a
+    /// file is followed.
+    #[arg(long, conflicts_with_all = ["list", "tar", "stat", "scan"])]
+    stdout: bool,
+
+    /// Write the selected files and dirs to stdout as a tar archive, instead of extracting to DEST
+    #[arg(long, conflicts_with_all = ["list", "stat", "scan"])]
+    tar: bool,
+
+    /// Print --list and --stat as JSON
+    ///
+    /// --list prints one object per line for each node, --stat prints a single object. Paths that
+    /// aren't valid UTF-8 are printed lossily, with their bytes in "path_bytes" or "target_bytes".
+    /// With --scan, the objects of nodes have the offset of their image in "image_offset".
+    #[arg(long)]
+    json: bool,
 
     /// Extract to [PATHNAME]
     #[arg(short, long, default_value = "squashfs-root", name = "PATHNAME")]
@@ -123,14 +148,45 @@ struct Args {
     /// exact file, only that file will be extracted.
     ///
     /// Like normal operation, these will be extracted as {arg.dest}{arg.path_filter}{files} with
-    /// correct file permissions.
-    #[arg(long, default_value = "/", value_parser = required_root)]
-    path_filter: PathBuf,
+    /// correct file permissions. Can be given multiple times.
+    #[arg(long, value_parser = required_root)]
+    path_filter: Vec<PathBuf>,
+
+    /// Don't extract files matching GLOB, such as "*.bak" or "/www/**/cache"
+    ///
+    /// A glob without "/" matches file names in any directory, otherwise full paths. Excluding a
+    /// dir excludes all files under it. Can be given multiple times.
+    #[arg(long, name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Don't extract files with a full path matching REGEX, can be given multiple times
+    #[cfg(feature = "regex")]
+    #[arg(long, name = "REGEX")]
+    exclude_regex: Vec<String>,
+
+    /// Don't extract files matching the patterns in FILE, one per line
+    ///
+    /// Lines are globs like --exclude, or regexes like --exclude-regex when prefixed with
+    /// "regex:". Empty lines and lines starting with "#" are ignored.
+    #[arg(long, name = "FILE")]
+    exclude_from: Vec<PathBuf>,
 
     /// If file already exists then overwrite
     #[arg(short, long)]
     force: bool,
 
+    /// Extract images found in extracted files, up to DEPTH levels of nested images
+    ///
+    /// Each image is extracted next to its file, to {file}.extracted. Ignored with --list.
+    #[arg(short,
+          long,
+          name = "DEPTH",
+          num_args = 0..=1,
+          default_value_t = 0,
+          default_missing_value = "8"
+    )]
+    recursive: usize,
+
     /// Display filesystem superblock information (ignores --quiet)
     #[arg(short, long)]
     stat: bool,
@@ -148,6 +204,13 @@ struct Args {
     ))]
     kind: String,
 
+    /// Extract as much as possible of a damaged image
+    ///
+    /// Unreadable directories and inodes are skipped, and unreadable data blocks are replaced
+    /// with zeros. Everything lost is reported at the end, exiting with a non-zero status.
+    #[arg(long)]
+    salvage: bool,
+
     /// Emit shell completion scripts
     #[arg(long)]
     completions: Option<Shell>,
@@ -155,12 +218,263 @@ struct Args {
     /// Silence all progress bar and RUST_LOG output
     #[arg(long)]
     quiet: bool,
+
+    /// Set when parsed like squashfs-tools, see [`compat_args`]
+    #[arg(skip)]
+    compat: Compat,
+}
+
+/// Write files of an image to stdout, used as `unsquashfs-backhand cat`
+#[derive(Parser)]
+#[command(version, name = "unsquashfs-backhand cat", max_term_width = 98)]
+struct CatArgs {
+    /// Squashfs file
+    filesystem: PathBuf,
+
+    /// Files to write, in order. Symlinks are followed.
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+
+    /// Skip BYTES at the start of FILESYSTEM
+    #[arg(short, long, default_value_t = 0, name = "BYTES")]
+    offset: u64,
+
+    /// Kind(type of image) to parse
+    #[arg(short,
+          long,
+          default_value = "le_v4_0",
+          value_parser = PossibleValuesParser::new(
+          [
+              "be_v4_0",
+              "le_v4_0",
+              "avm_be_v4_0",
+          ]
+    ))]
+    kind: String,
+}
+
+/// Options of the squashfs-tools grammar without an equivalent in [`Args`]
+#[derive(Default)]
+struct Compat {
+    enabled: bool,
+    /// Only print the modification time of the image, for `-mkfs-time`
+    mkfs_time: bool,
+}
+
+const COMPAT_USAGE: &str = "\
+SYNTAX: unsquashfs-compat [options] filesystem [files to extract]
+        unsquashfs-backhand --compat [options] filesystem [files to extract]
+
+Options and arguments, parsed like squashfs-tools unsquashfs:
+\t-v[ersion]\t\tprint version and exit
+\t-d[est] <pathname>\textract to <pathname>, default \"squashfs-root\"
+\t-o[ffset] <bytes>\tskip <bytes> at start of filesystem
+\t-f[orce]\t\tif file already exists then overwrite
+\t-i[nfo]\t\t\tprint files as they are extracted
+\t-li[nfo]\t\tsame as -info
+\t-l[s]\t\t\tlist filesystem, but don't extract files
+\t-ll[s]\t\t\tlist filesystem with file attributes, uids and gids are numeric
+\t-lln[umeric]\t\tsame as -lls
+\t-s[tat]\t\t\tdisplay filesystem superblock information, times are in UTC
+\t-mkfs-time\t\tdisplay filesystem creation time in seconds
+\t-fstime\t\t\tsame as -mkfs-time
+\t-e[f] <extract file>\tlist of files to extract, one per line
+\t-p[rocessors] <number>\tuse <number> processors
+\t-q[uiet]\t\tno output, except errors
+\t-n[o-progress]\t\tsame as -quiet
+\t-ig[nore-errors]\terrors extracting files are never fatal, the default
+\t-no-exit[-code]\t\tignored, the exit code is only non-zero for fatal errors
+\t-no[-xattrs]\t\tignored, xattrs are never extracted
+\t-da[ta-queue] <size>\tignored
+\t-fr[ag-queue] <size>\tignored
+\t-h[elp]\t\t\tprint this message and exit
+";
+
+/// Arguments to parse like squashfs-tools unsquashfs, when called as `unsquashfs-compat` or with
+/// `--compat` as first argument
+fn compat_argv() -> Option<Vec<OsString>> {
+    let mut argv: Vec<OsString> = env::args_os().collect();
+    let argv0 = argv.first().map(Path::new).and_then(Path::file_name);
+    if argv0 == Some(OsStr::new("unsquashfs-compat")) {
+        return Some(argv.split_off(1));
+    }
+    if argv.get(1).map_or(false, |arg| arg == "--compat") {
+        return Some(argv.split_off(2));
+    }
+    None
+}
+
+/// Parse `argv` like squashfs-tools unsquashfs, by translating it to the arguments of [`Args`]
+///
+/// Returns `Err` with the exit code if the process must exit, such as for `-help`.
+fn compat_args(argv: &[OsString]) -> Result<Args, ExitCode> {
+    let mut native: Vec<OsString> = vec!["unsquashfs-backhand".into()];
+    let mut compat = Compat { enabled: true, ..Compat::default() };
+    let mut processors = None;
+
+    // options come before FILESYSTEM
+    let mut argv = argv.iter();
+    let filesystem = loop {
+        let arg = match argv.next() {
+            Some(arg) => arg,
+            None => break None,
+        };
+        let option = match arg.to_str() {
+            Some(option) if option.starts_with('-') => option,
+            _ => break Some(arg),
+        };
+        let mut value = || {
+            argv.next().cloned().ok_or_else(|| {
+                eprintln!("unsquashfs: {option} missing argument");
+                ExitCode::FAILURE
+            })
+        };
+        let flag = match option {
+            "-v" | "-version" => "--version",
+            "-f" | "-force" => "--force",
+            "-i" | "-info" | "-li" | "-linfo" => "--info",
+            "-l" | "-ls" => "--list",
+            "-ll" | "-lls" | "-lln" | "-llnumeric" => "-ll",
+            "-s" | "-stat" => "--stat",
+            "-q" | "-quiet" | "-n" | "-no-progress" => "--quiet",
+            "-mkfs-time" | "-fstime" => {
+                compat.mkfs_time = true;
+                "--stat"
+            }
+            "-d" | "-dest" => {
+                native.extend(["--dest".into(), value()?]);
+                continue;
+            }
+            "-o" | "-offset" => {
+                native.extend(["--offset".into(), value()?]);
+                continue;
+            }
+            "-e" | "-ef" => {
+                for path in read_extract_file(&value()?)? {
+                    native.extend(["--path-filter".into(), path.into_os_string()]);
+                }
+                continue;
+            }
+            "-p" | "-processors" => {
+                processors = Some(value()?);
+                continue;
+            }
+            // the defaults of backhand
+            "-ig" | "-ignore-errors" | "-no-exit" | "-no-exit-code" | "-no" | "-no-xattrs" => {
+                continue
+            }
+            // queue sizes of squashfs-tools
+            "-da" | "-data-queue" | "-fr" | "-frag-queue" => {
+                value()?;
+                continue;
+            }
+            "-h" | "-help" => {
+                print!("{COMPAT_USAGE}");
+                return Err(ExitCode::SUCCESS);
+            }
+            _ => {
+                eprintln!("unsquashfs: invalid option {option}\n");
+                eprint!("{COMPAT_USAGE}");
+                return Err(ExitCode::FAILURE);
+            }
+        };
+        // clap rejects flags given twice
+        if !native.iter().any(|arg| arg == flag) {
+            native.push(flag.into());
+        }
+    };
+
+    let filesystem = match filesystem {
+        Some(filesystem) => filesystem.as_os_str(),
+        None if native.iter().any(|arg| arg == "--version") => OsStr::new(""),
+        None => {
+            eprint!("{COMPAT_USAGE}");
+            return Err(ExitCode::FAILURE);
+        }
+    };
+    for path in argv {
+        native.extend(["--path-filter".into(), Path::new("/").join(path).into_os_string()]);
+    }
+    native.extend(["--".into(), filesystem.to_os_string()]);
+
+    if let Some(processors) = processors {
+        let threads = processors.to_str().and_then(|n| n.parse::<usize>().ok());
+        let threads = match threads {
+            Some(threads) if threads > 0 => threads,
+            _ => {
+                eprintln!("unsquashfs: -processors should be a positive integer");
+                return Err(ExitCode::FAILURE);
+            }
+        };
+        if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+            eprintln!("unsquashfs: -processors: {e}");
+            return Err(ExitCode::FAILURE);
+        }
+    }
+
+    let mut args = Args::try_parse_from(native).unwrap_or_else(|e| e.exit());
+    args.compat = compat;
+    Ok(args)
+}
+
+/// Paths listed in the `-ef` file at `path`, one per line
+fn read_extract_file(path: &OsStr) -> Result<Vec<PathBuf>, ExitCode> {
+    match fs::read_to_string(path) {
+        Ok(paths) => Ok(paths
+            .lines()
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(|path| Path::new("/").join(path))
+            .collect()),
+        Err(e) => {
+            eprintln!("unsquashfs: failed to read extract file {}: {e}", Path::new(path).display());
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+/// Options selecting the extracted files, and how to extract them
+fn extract_options(args: &Args) -> Result<ExtractOptions, BackhandError> {
+    let mut options = ExtractOptions::new().force(args.force).recursive(args.recursive);
+    for path in &args.path_filter {
+        options = options.path_filter(path);
+    }
+    for glob in &args.exclude {
+        options = options.exclude(Pattern::glob(glob)?);
+    }
+    #[cfg(feature = "regex")]
+    for regex in &args.exclude_regex {
+        options = options.exclude(Pattern::regex(regex)?);
+    }
+    for path in &args.exclude_from {
+        for pattern in Pattern::from_file(path)? {
+            options = options.exclude(pattern);
+        }
+    }
+    Ok(options)
 }
 
 fn main() -> ExitCode {
-    let mut args = Args::parse();
+    if env::args_os().nth(1).map_or(false, |arg| arg == "cat") {
+        return cat(&CatArgs::parse_from(env::args_os().skip(1)));
+    }
+    let mut args = match compat_argv() {
+        Some(argv) => match compat_args(&argv) {
+            Ok(args) => args,
+            Err(code) => return code,
+        },
+        None => Args::parse(),
+    };
     if !args.quiet {
-        tracing_subscriber::fmt::init();
+        if args.stdout || args.tar {
+            // keep stdout for the data
+            tracing_subscriber::fmt()
+                .with_env_filter(EnvFilter::from_default_env())
+                .with_writer(io::stderr)
+                .init();
+        } else {
+            tracing_subscriber::fmt::init();
+        }
     }
 
     if let Some(completions) = args.completions {
@@ -172,10 +486,10 @@ fn main() -> ExitCode {
 
     let kind = Kind::from_target(&args.kind).unwrap();
 
-    let mut file = BufReader::new(File::open(args.filesystem.as_ref().unwrap()).unwrap());
+    let file = BufReader::new(File::open(args.filesystem.as_ref().unwrap()).unwrap());
 
     let blue_bold: console::Style = console::Style::new().blue().bold();
-    let red_bold: console::Style = console::Style::new().blue().bold();
+    let red_bold: console::Style = console::Style::new().red().bold();
     let pb = ProgressBar::new_spinner();
 
     if args.auto_offset {
@@ -184,7 +498,7 @@ fn main() -> ExitCode {
             let line = format!("{:>14}", blue_bold.apply_to("Searching for magic"));
             pb.set_message(line);
         }
-        if let Some(found_offset) = find_offset(&mut file, &kind) {
+        if let Some(found_offset) = find_offset(file.get_ref(), &args.kind) {
             if !args.quiet {
                 let line =
                     format!("{:>14} 0x{:08x}", blue_bold.apply_to("Found magic"), found_offset,);
@@ -205,12 +519,88 @@ fn main() -> ExitCode {
         return ExitCode::SUCCESS;
     }
 
-    let squashfs = Squashfs::from_reader_with_offset_and_kind(file, args.offset, kind).unwrap();
     let root_process = unsafe { geteuid() == 0 };
     if root_process {
         umask(Mode::from_bits(0).unwrap());
     }
 
+    // use positional reads, so extracting files in parallel doesn't contend on the reader
+    let file = file.into_inner();
+    if args.scan {
+        return scan(&args, &file);
+    }
+    if unsquashfs(&args, &file, args.offset, kind, &args.dest) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// List or extract the images found in `file`
+fn scan(args: &Args, file: &File) -> ExitCode {
+    let blue_bold: console::Style = console::Style::new().blue().bold();
+    let red_bold: console::Style = console::Style::new().red().bold();
+
+    let images = match Squashfs::scan(file) {
+        Ok(images) => images,
+        Err(e) => {
+            eprintln!("{:>16} {e}", red_bold.apply_to("Failed to scan"));
+            return ExitCode::FAILURE;
+        }
+    };
+    if images.is_empty() {
+        println!("{:>16}", red_bold.apply_to("No images found"));
+        return ExitCode::FAILURE;
+    }
+
+    let mut success = true;
+    let mut last_offset = None;
+    for image in images {
+        // a big endian image can be valid for multiple kinds, only use the first one
+        if last_offset == Some(image.offset) {
+            continue;
+        }
+        last_offset = Some(image.offset);
+        // only print the records of the nodes with --json
+        if !args.json {
+            println!(
+                "{:>16} {} image at {:#010x}, {} bytes",
+                blue_bold.apply_to("Found"),
+                image.name,
+                image.offset,
+                image.bytes_used
+            );
+        }
+        let dest = args.dest.join(format!("{:#010x}", image.offset));
+        success &= unsquashfs(args, file, image.offset, image.kind, &dest);
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// List or extract the image at `offset` in `file` to `dest`, returns `false` if it failed
+fn unsquashfs(args: &Args, file: &File, offset: u64, kind: Kind, dest: &Path) -> bool {
+    let blue_bold: console::Style = console::Style::new().blue().bold();
+    let red_bold: console::Style = console::Style::new().red().bold();
+
+    let squashfs = if args.salvage {
+        Squashfs::from_read_at_salvage(file, offset, kind)
+    } else {
+        Squashfs::from_read_at_with_offset_and_kind(file, offset, kind)
+    };
+    // with --scan, images have only passed the checks of their superblock
+    let squashfs = match squashfs {
+        Ok(squashfs) => squashfs,
+        Err(e) => {
+            eprintln!("{:>14} image at {offset:#010x}: {e}", red_bold.apply_to("Failed"));
+            return false;
+        }
+    };
+
     // Start new spinner as we extract all the inode and other information from the image
     // This can be very time consuming
     let start = Instant::now();
@@ -220,183 +610,580 @@ fn main() -> ExitCode {
         let line = format!("{:>14}", blue_bold.apply_to("Reading image"));
         pb.set_message(line);
     }
-    let filesystem = squashfs.into_filesystem_reader().unwrap();
+    let filesystem = match squashfs.into_filesystem_reader() {
+        Ok(filesystem) => filesystem,
+        Err(e) => {
+            pb.finish_and_clear();
+            eprintln!("{:>14} image at {offset:#010x}: {e}", red_bold.apply_to("Failed"));
+            return false;
+        }
+    };
     if !args.quiet {
         let line = format!("{:>14}", blue_bold.apply_to("Read image"));
         pb.finish_with_message(line);
     }
 
-    // if we can find a parent, then a filter must be applied and the exact parent dirs must be
-    // found above it
-    let mut files: Vec<&Node<SquashfsFileReader>> = vec![];
-    if args.path_filter.parent().is_some() {
-        let mut current = PathBuf::new();
-        current.push("/");
-        for part in args.path_filter.iter() {
-            current.push(part);
-            if let Some(exact) = filesystem.files().find(|&a| a.fullpath == current) {
-                files.push(exact);
-            } else {
-                if !args.quiet {
-                    let line = format!(
-                        "{:>14}",
-                        red_bold.apply_to("Invalid --path-filter, path doesn't exist")
-                    );
-                    pb.finish_with_message(line);
-                }
-                return ExitCode::FAILURE;
+    // gather all selected files and their parent dirs
+    let options = match extract_options(args) {
+        Ok(options) => options,
+        Err(e) => {
+            if !args.quiet {
+                let line = format!("{:>14} {e}", red_bold.apply_to("Invalid --exclude"));
+                pb.finish_with_message(line);
             }
+            return false;
         }
-        // remove the final node, this is a file and will be caught in the following statement
-        files.pop();
-    }
-
-    // gather all files and dirs
-    let files_len = files.len();
-    let nodes = files
-        .into_iter()
-        .chain(filesystem.files().filter(|a| a.fullpath.starts_with(&args.path_filter)));
+    };
+    let nodes = match filesystem.selected_nodes(&options) {
+        Ok(nodes) => nodes,
+        Err(_) => {
+            if !args.quiet {
+                let line = format!(
+                    "{:>14}",
+                    red_bold.apply_to("Invalid --path-filter, path doesn't exist")
+                );
+                pb.finish_with_message(line);
+            }
+            return false;
+        }
+    };
 
     // extract or list
-    if args.list {
-        list(nodes);
+    if args.list > 0 {
+        list(args, offset, nodes.into_iter());
+    } else if args.stdout {
+        if !write_stdout(args, &filesystem, &nodes) {
+            return false;
+        }
+    } else if args.tar {
+        if !write_tar(&filesystem, options) {
+            return false;
+        }
     } else {
-        // This could be expensive, only pass this in when not quiet
-        let n_nodes = if !args.quiet {
-            Some(
-                files_len
-                    + filesystem
-                        .files()
-                        .filter(|a| a.fullpath.starts_with(&args.path_filter))
-                        .count(),
-            )
-        } else {
-            None
-        };
+        let n_nodes = nodes.len();
+        extract_all(args, &filesystem, options, dest, n_nodes, start);
+    }
 
-        extract_all(
-            &args,
-            &filesystem,
-            root_process,
-            nodes.collect::<Vec<&Node<SquashfsFileReader>>>().into_par_iter(),
-            n_nodes,
-            start,
-        );
+    // damage of data blocks is only found while extracting
+    let damage = filesystem.take_damage();
+    if !damage.is_empty() {
+        damaged(&damage);
+        return false;
     }
 
-    ExitCode::SUCCESS
+    true
 }
 
-fn list<'a>(nodes: impl Iterator<Item = &'a Node<SquashfsFileReader>>) {
-    for node in nodes {
-        let path = &node.fullpath;
-        println!("{}", path.display());
+/// Write the files at the paths of `args` to stdout, following symlinks
+fn cat(args: &CatArgs) -> ExitCode {
+    let kind = Kind::from_target(&args.kind).unwrap();
+    let filesystem = File::open(&args.filesystem).map_err(BackhandError::from).and_then(|file| {
+        FilesystemReader::from_read_at_with_offset_and_kind(file, args.offset, kind)
+    });
+    let filesystem = match filesystem {
+        Ok(filesystem) => filesystem,
+        Err(e) => {
+            eprintln!("unsquashfs: {}: {e}", args.filesystem.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut stdout = io::stdout().lock();
+    let mut code = ExitCode::SUCCESS;
+    for path in &args.paths {
+        let path = Path::new("/").join(path);
+        let result = filesystem.metadata(&path).and_then(|node| match &node.inner {
+            InnerNode::File(file) => write_file(&filesystem, node, file, &mut stdout),
+            _ => Err(BackhandError::NotAFile),
+        });
+        if let Err(e) = result {
+            eprintln!("unsquashfs: {}: {e}", path.display());
+            code = ExitCode::FAILURE;
+        }
     }
+    code
 }
 
-fn stat(args: Args, mut file: BufReader<File>, kind: Kind) {
-    file.seek(SeekFrom::Start(args.offset)).unwrap();
-    let mut reader: Box<dyn BufReadSeek> = Box::new(file);
-    let (superblock, compression_options) =
-        Squashfs::superblock_and_compression_options(&mut reader, &kind).unwrap();
+/// Write the selected files to stdout for --stdout, returns `false` if one failed
+fn write_stdout(
+    args: &Args,
+    filesystem: &FilesystemReader,
+    nodes: &[&Node<SquashfsFileReader>],
+) -> bool {
+    let is_file = |node: &&Node<SquashfsFileReader>| matches!(node.inner, InnerNode::File(_));
+    let mut files = vec![];
+    if args.path_filter.is_empty() {
+        files.extend(nodes.iter().copied().filter(is_file));
+    }
+    for path in &args.path_filter {
+        match filesystem.metadata(path) {
+            // follow the symlink given as --path-filter, unless it's excluded
+            Ok(node) if is_file(&node) => {
+                if nodes.iter().any(|selected| selected.fullpath == *path) {
+                    files.push(node);
+                }
+            }
+            _ => files.extend(
+                nodes
+                    .iter()
+                    .copied()
+                    .filter(|node| node.fullpath.starts_with(path))
+                    .filter(is_file),
+            ),
+        }
+    }
 
-    // show info about flags
-    println!("{superblock:#08x?}");
+    let red_bold: console::Style = console::Style::new().red().bold();
+    let mut stdout = io::stdout().lock();
+    let mut success = true;
+    for node in files {
+        if let InnerNode::File(file) = &node.inner {
+            if let Err(e) = write_file(filesystem, node, file, &mut stdout) {
+                eprintln!("{:>16} {} : {e}", red_bold.apply_to("Failed"), node.fullpath.display());
+                success = false;
+            }
+        }
+    }
+    success
+}
 
-    // show info about compression options
-    println!("Compression Options: {compression_options:#x?}");
+/// Write the contents of `file` of `node` to `writer`
+fn write_file(
+    filesystem: &FilesystemReader,
+    node: &Node<SquashfsFileReader>,
+    file: &SquashfsFileReader,
+    writer: &mut impl io::Write,
+) -> Result<(), BackhandError> {
+    let (mut buf_read, mut buf_decompress) = filesystem.alloc_read_buffers();
+    let file = filesystem.file(&file.basic).with_path(&node.fullpath);
+    let mut reader = file.reader(&mut buf_read, &mut buf_decompress);
+    io::copy(&mut reader, writer)?;
+    writer.flush()?;
+    Ok(())
+}
 
-    // show info about flags
-    if superblock.inodes_uncompressed() {
-        println!("flag: inodes uncompressed");
+/// Write the nodes selected by `options` to stdout as a tar archive, for --tar
+fn write_tar(filesystem: &FilesystemReader, options: ExtractOptions) -> bool {
+    let red_bold: console::Style = console::Style::new().red().bold();
+    let sink = TarSink::new(BufWriter::new(io::stdout()));
+    // extract in order, so the archive is the same every time
+    let report = match filesystem.extract_with_sink(&sink, &options.parallel(false)) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{:>16} {e}", red_bold.apply_to("Failed"));
+            return false;
+        }
+    };
+    for (path, error) in &report.errors {
+        eprintln!("{:>16} {} : {error}", red_bold.apply_to("Failed"), path.display());
     }
+    if let Err(e) = sink.finish() {
+        eprintln!("{:>16} {e}", red_bold.apply_to("Failed"));
+        return false;
+    }
+    report.is_ok()
+}
 
-    if superblock.data_block_stored_uncompressed() {
-        println!("flag: data blocks stored uncompressed");
+fn list<'a>(args: &Args, offset: u64, nodes: impl Iterator<Item = &'a Node<SquashfsFileReader>>) {
+    // with --scan, records of all images are printed together
+    let image_offset = if args.scan { Some(offset) } else { None };
+    for node in nodes {
+        // like squashfs-tools, list the paths the nodes would be extracted to
+        let path = if args.compat.enabled {
+            match node.fullpath.strip_prefix("/") {
+                Ok(relative) if relative.as_os_str().is_empty() => args.dest.clone(),
+                Ok(relative) => args.dest.join(relative),
+                Err(_) => args.dest.join(&node.fullpath),
+            }
+        } else {
+            node.fullpath.clone()
+        };
+        if args.json {
+            println!("{}", json_node(node, image_offset));
+        } else if args.list > 1 {
+            println!("{}", long_node(node, &path));
+        } else {
+            println!("{}", path.display());
+        }
     }
+}
 
-    if superblock.fragments_stored_uncompressed() {
-        println!("flag: fragments stored uncompressed");
+/// Type of `node`, as named in the JSON output
+fn node_type(node: &Node<SquashfsFileReader>) -> &'static str {
+    match node.inner {
+        InnerNode::File(_) => "file",
+        InnerNode::Symlink(_) => "symlink",
+        InnerNode::Dir(_) => "dir",
+        InnerNode::CharacterDevice(_) => "char_device",
+        InnerNode::BlockDevice(_) => "block_device",
     }
+}
 
-    if superblock.fragments_are_not_used() {
-        println!("flag: fragments are not used");
+/// Type and permissions of `node`, such as `drwxr-xr-x`
+fn mode_string(node: &Node<SquashfsFileReader>) -> String {
+    let mut mode = String::with_capacity(10);
+    mode.push(match node.inner {
+        InnerNode::File(_) => '-',
+        InnerNode::Symlink(_) => 'l',
+        InnerNode::Dir(_) => 'd',
+        InnerNode::CharacterDevice(_) => 'c',
+        InnerNode::BlockDevice(_) => 'b',
+    });
+    let permissions = node.header.permissions;
+    // (shift of the rwx bits, special bit, char if executable, char if not)
+    let classes = [(6, 0o4000, 's', 'S'), (3, 0o2000, 's', 'S'), (0, 0o1000, 't', 'T')];
+    for (shift, special, exec, no_exec) in classes {
+        let bits = permissions >> shift;
+        mode.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        mode.push(match (bits & 0o1 != 0, permissions & special != 0) {
+            (true, true) => exec,
+            (false, true) => no_exec,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
     }
+    mode
+}
+
+/// Major and minor numbers of the linux encoded `device_number`
+fn major_minor(device_number: u32) -> (u32, u32) {
+    let major = (device_number >> 8) & 0xfff;
+    let minor = (device_number & 0xff) | ((device_number >> 12) & 0xfff00);
+    (major, minor)
+}
+
+/// Year, month and day of `days` since the unix epoch
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// `mtime` as `YYYY-MM-DD HH:MM` in UTC
+fn format_mtime(mtime: u32) -> String {
+    let (year, month, day) = civil_from_days(i64::from(mtime / 86400));
+    let minutes = mtime % 86400 / 60;
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// `time` like `ctime(3)` in UTC, such as `Tue Nov 14 22:13:20 2023`
+fn format_ctime(time: u32) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let days = time / 86400;
+    let (year, month, day) = civil_from_days(i64::from(days));
+    let seconds = time % 86400;
+    format!(
+        "{} {} {day:>2} {:02}:{:02}:{:02} {year}",
+        // the epoch is a thursday
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
 
-    if superblock.fragments_are_always_generated() {
-        println!("flag: fragments are always generated");
+/// Line of `-ll` for `node` at `path`, like `unsquashfs -lln` of squashfs-tools
+fn long_node(node: &Node<SquashfsFileReader>, path: &Path) -> String {
+    let size = match &node.inner {
+        InnerNode::File(file) => file.basic.file_size.to_string(),
+        InnerNode::Symlink(SquashfsSymlink { link }) => link.as_os_str().len().to_string(),
+        InnerNode::Dir(_) => "0".to_string(),
+        InnerNode::CharacterDevice(device) => {
+            let (major, minor) = major_minor(device.device_number);
+            format!("{major},{minor:>4}")
+        }
+        InnerNode::BlockDevice(device) => {
+            let (major, minor) = major_minor(device.device_number);
+            format!("{major},{minor:>4}")
+        }
+    };
+    let owner = format!("{}/{}", node.header.uid, node.header.gid);
+    let mut line = format!(
+        "{} {owner:<11} {size:>10} {} {}",
+        mode_string(node),
+        format_mtime(node.header.mtime),
+        path.display()
+    );
+    if let InnerNode::Symlink(SquashfsSymlink { link }) = &node.inner {
+        write!(line, " -> {}", link.display()).unwrap();
     }
+    line
+}
 
-    if superblock.data_has_been_duplicated() {
-        println!("flag: data has been duplicated");
+/// Add `path` to `record` as `name`, paths that aren't valid UTF-8 are also kept as their bytes
+/// in `{name}_bytes`
+fn json_path(record: &mut Map<String, Value>, name: &str, path: &Path) {
+    let lossy = path.to_string_lossy();
+    if let Cow::Owned(_) = lossy {
+        record.insert(format!("{name}_bytes"), json!(path.as_os_str().as_bytes()));
     }
+    record.insert(name.to_string(), json!(lossy));
+}
 
-    if superblock.nfs_export_table_exists() {
-        println!("flag: nfs export table exists");
+/// Record of `--list --json` for `node`, in the image at `image_offset` if given
+fn json_node(node: &Node<SquashfsFileReader>, image_offset: Option<u64>) -> Value {
+    let header = &node.header;
+    let mut record = Map::new();
+    if let Some(image_offset) = image_offset {
+        record.insert("image_offset".to_string(), json!(image_offset));
     }
+    json_path(&mut record, "path", &node.fullpath);
+    record.insert("type".to_string(), json!(node_type(node)));
+    record.insert("mode".to_string(), json!(mode_string(node)));
+    record.insert("permissions".to_string(), json!(header.permissions));
+    record.insert("uid".to_string(), json!(header.uid));
+    record.insert("gid".to_string(), json!(header.gid));
+    record.insert("mtime".to_string(), json!(header.mtime));
+    let device_number = match &node.inner {
+        InnerNode::File(file) => {
+            record.insert("size".to_string(), json!(file.basic.file_size));
+            None
+        }
+        InnerNode::Symlink(SquashfsSymlink { link }) => {
+            json_path(&mut record, "target", link);
+            None
+        }
+        InnerNode::Dir(_) => None,
+        InnerNode::CharacterDevice(device) => Some(device.device_number),
+        InnerNode::BlockDevice(device) => Some(device.device_number),
+    };
+    if let Some(device_number) = device_number {
+        let (major, minor) = major_minor(device_number);
+        record.insert("major".to_string(), json!(major));
+        record.insert("minor".to_string(), json!(minor));
+    }
+    Value::Object(record)
+}
 
-    if superblock.xattrs_are_stored_uncompressed() {
-        println!("flag: xattrs are stored uncompressed");
+fn damaged(damage: &[Damage]) {
+    let red_bold: console::Style = console::Style::new().red().bold();
+    for damage in damage {
+        eprintln!("{:>16} {damage}", red_bold.apply_to("Damaged"));
     }
+    eprintln!(
+        "{:>16} {} damaged parts of the image were skipped",
+        red_bold.apply_to("Salvaged"),
+        damage.len()
+    );
+}
 
-    if superblock.compressor_options_are_present() {
-        println!("flag: compressor options are present");
+fn stat(args: Args, mut file: BufReader<File>, kind: Kind) {
+    file.seek(SeekFrom::Start(args.offset)).unwrap();
+    let mut reader: Box<dyn BufReadSeek> = Box::new(file);
+    let (superblock, compression_options) =
+        Squashfs::superblock_and_compression_options(&mut reader, &kind).unwrap();
+
+    let flags = [
+        ("inodes uncompressed", superblock.inodes_uncompressed()),
+        ("data blocks stored uncompressed", superblock.data_block_stored_uncompressed()),
+        ("fragments stored uncompressed", superblock.fragments_stored_uncompressed()),
+        ("fragments are not used", superblock.fragments_are_not_used()),
+        ("fragments are always generated", superblock.fragments_are_always_generated()),
+        ("data has been duplicated", superblock.data_has_been_duplicated()),
+        ("nfs export table exists", superblock.nfs_export_table_exists()),
+        ("xattrs are stored uncompressed", superblock.xattrs_are_stored_uncompressed()),
+        ("compressor options are present", superblock.compressor_options_are_present()),
+        ("ids uncompressed", superblock.ids_uncompressed()),
+    ];
+    let flags = flags.into_iter().filter(|(_, set)| *set).map(|(flag, _)| flag);
+
+    if args.compat.mkfs_time {
+        println!("{}", superblock.mod_time);
+        return;
+    }
+    if args.compat.enabled {
+        compat_stat(&args, &superblock, compression_options);
+        return;
+    }
+    if args.json {
+        println!("{}", json_stat(&args, &superblock, compression_options, flags));
+        return;
+    }
+
+    // show info about flags
+    println!("{superblock:#08x?}");
+
+    // show info about compression options
+    println!("Compression Options: {compression_options:#x?}");
+
+    // show info about flags
+    for flag in flags {
+        println!("flag: {flag}");
     }
 }
 
-fn set_attributes(
-    pb: &ProgressBar,
+/// `-stat` of squashfs-tools unsquashfs
+fn compat_stat(
     args: &Args,
-    path: &Path,
-    header: &NodeHeader,
-    root_process: bool,
-    is_file: bool,
+    superblock: &SuperBlock,
+    compression_options: Option<CompressionOptions>,
 ) {
-    // TODO Use (file_set_times) when not nightly: https://github.com/rust-lang/rust/issues/98245
-    let timeval = TimeVal::new(header.mtime as _, 0);
-    utimes(path, &timeval, &timeval).unwrap();
-
-    let mut mode = u32::from(header.permissions);
-
-    // Only chown when root
-    if root_process {
-        // TODO: Use (unix_chown) when not nightly: https://github.com/rust-lang/rust/issues/88989
-        let path_bytes = PathBuf::from(path).as_os_str().as_bytes().as_ptr().cast::<i8>();
-        unsafe {
-            lchown(path_bytes as *const _, header.uid, header.gid);
-        }
-    } else if is_file {
-        // bitwise-not if not rooted (disable write permissions for user/group). Following
-        // squashfs-tools/unsquashfs behavior
-        mode &= !0o022;
-    }
-
-    // set permissions
-    //
-    // NOTE: In squashfs-tools/unsquashfs they remove the write bits for user and group?
-    // I don't know if there is a reason for that but I keep the permissions the same if possible
-    if let Err(e) = fs::set_permissions(path, Permissions::from_mode(mode)) {
-        if e.kind() == std::io::ErrorKind::PermissionDenied {
-            // try without sticky bit
-            if fs::set_permissions(path, Permissions::from_mode(mode & !1000)).is_err()
-                && !args.quiet
-            {
-                let line = format!("{} : could not set permissions", path.to_str().unwrap());
-                failed(pb, &line);
+    let not = |set: bool| if set { "" } else { "not " };
+    let un = |set: bool| if set { "un" } else { "" };
+
+    println!(
+        "Found a valid SQUASHFS {}:{} superblock on {}.",
+        superblock.version_major,
+        superblock.version_minor,
+        args.filesystem.as_ref().unwrap().display()
+    );
+    println!("Creation or last append time {}", format_ctime(superblock.mod_time));
+    let bytes = superblock.bytes_used as f64;
+    println!(
+        "Filesystem size {} bytes ({:.2} Kbytes / {:.2} Mbytes)",
+        superblock.bytes_used,
+        bytes / 1024.0,
+        bytes / (1024.0 * 1024.0)
+    );
+    let compressor = format!("{:?}", superblock.compressor).to_lowercase();
+    println!("Compression {compressor}");
+    match compression_options {
+        Some(CompressionOptions::Gzip(gzip)) => {
+            println!("\tcompression-level {}", gzip.compression_level);
+            println!("\twindow-size {}", gzip.window_size);
+            let strategies = ["default", "filtered", "huffman_only", "run_length_encoded", "fixed"];
+            let selected: Vec<&str> = strategies
+                .into_iter()
+                .enumerate()
+                .filter(|(bit, _)| gzip.strategies & (1 << bit) != 0)
+                .map(|(_, strategy)| strategy)
+                .collect();
+            if !selected.is_empty() {
+                println!("\tStrategies selected: {}", selected.join(" "));
+            }
+        }
+        Some(CompressionOptions::Lzo(lzo)) => {
+            let algorithms = ["lzo1x_1", "lzo1x_1_11", "lzo1x_1_12", "lzo1x_1_15", "lzo1x_999"];
+            let algorithm = algorithms.get(lzo.algorithm as usize).unwrap_or(&"unknown");
+            println!("\talgorithm {algorithm}");
+            if *algorithm == "lzo1x_999" {
+                println!("\tcompression level {}", lzo.compression_level);
             }
         }
+        Some(CompressionOptions::Xz(xz)) => println!("\tDictionary size {}", xz.dictionary_size),
+        Some(CompressionOptions::Lz4(lz4)) if lz4.flags & 1 != 0 => {
+            println!("\tHigh Compression option specified")
+        }
+        Some(CompressionOptions::Zstd(zstd)) => {
+            println!("\tcompression-level {}", zstd.compression_level)
+        }
+        _ => (),
     }
+    println!("Block size {}", superblock.block_size);
+    println!("Filesystem is {}exportable via NFS", not(superblock.nfs_export_table_exists()));
+    println!("Inodes are {}compressed", un(superblock.inodes_uncompressed()));
+    println!("Data is {}compressed", un(superblock.data_block_stored_uncompressed()));
+    println!("Uids/Gids (Id table) are {}compressed", un(superblock.ids_uncompressed()));
+    if superblock.fragments_are_not_used() {
+        println!("Fragments are not stored");
+    } else {
+        println!("Fragments are {}compressed", un(superblock.fragments_stored_uncompressed()));
+        println!(
+            "Always-use-fragments option is {}specified",
+            not(superblock.fragments_are_always_generated())
+        );
+    }
+    if superblock.no_xattrs_in_archive() {
+        println!("Xattrs are not stored");
+    } else {
+        println!("Xattrs are {}compressed", un(superblock.xattrs_are_stored_uncompressed()));
+    }
+    println!("Duplicates are {}removed", not(superblock.data_has_been_duplicated()));
+    println!("Number of fragments {}", superblock.frag_count);
+    println!("Number of inodes {}", superblock.inode_count);
+    println!("Number of ids {}", superblock.id_count);
+}
+
+/// Record of `--stat --json`
+fn json_stat<'a>(
+    args: &Args,
+    superblock: &SuperBlock,
+    compression_options: Option<CompressionOptions>,
+    flags: impl Iterator<Item = &'a str>,
+) -> Value {
+    // unset optional tables are null
+    let table = |offset: u64| match offset {
+        u64::MAX => Value::Null,
+        offset => json!(offset),
+    };
+    let compressor = format!("{:?}", superblock.compressor).to_lowercase();
+    let superblock = json!({
+        "inode_count": superblock.inode_count,
+        "mod_time": superblock.mod_time,
+        "block_size": superblock.block_size,
+        "frag_count": superblock.frag_count,
+        "compressor": compressor,
+        "block_log": superblock.block_log,
+        "flags": superblock.flags,
+        "id_count": superblock.id_count,
+        "version_major": superblock.version_major,
+        "version_minor": superblock.version_minor,
+        "root_inode": superblock.root_inode,
+        "bytes_used": superblock.bytes_used,
+        "id_table": superblock.id_table,
+        "xattr_table": table(superblock.xattr_table),
+        "inode_table": superblock.inode_table,
+        "dir_table": superblock.dir_table,
+        "frag_table": table(superblock.frag_table),
+        "export_table": table(superblock.export_table),
+    });
+
+    let compression_options = match compression_options {
+        None => Value::Null,
+        Some(CompressionOptions::Gzip(gzip)) => json!({
+            "compression_level": gzip.compression_level,
+            "window_size": gzip.window_size,
+            "strategies": gzip.strategies,
+        }),
+        Some(CompressionOptions::Lzo(lzo)) => json!({
+            "algorithm": lzo.algorithm,
+            "compression_level": lzo.compression_level,
+        }),
+        Some(CompressionOptions::Xz(xz)) => json!({
+            "dictionary_size": xz.dictionary_size,
+            "bit_opts": xz.bit_opts,
+            "fb": xz.fb,
+        }),
+        Some(CompressionOptions::Lz4(lz4)) => {
+            json!({ "version": lz4.version, "flags": lz4.flags })
+        }
+        Some(CompressionOptions::Zstd(zstd)) => {
+            json!({ "compression_level": zstd.compression_level })
+        }
+        Some(CompressionOptions::Lzma) => json!({}),
+    };
+
+    json!({
+        "offset": args.offset,
+        "kind": args.kind,
+        "superblock": superblock,
+        "compression_options": compression_options,
+        "flags": flags.collect::<Vec<_>>(),
+    })
 }
 
-fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
+fn extract_all(
     args: &Args,
-    filesystem: &'a FilesystemReader,
-    root_process: bool,
-    nodes: S,
-    n_nodes: Option<usize>,
+    filesystem: &FilesystemReader,
+    options: ExtractOptions,
+    dest: &Path,
+    n_nodes: usize,
     start: Instant,
 ) {
-    let pb = ProgressBar::new(n_nodes.unwrap_or(0) as u64);
+    let pb = ProgressBar::new(n_nodes as u64);
     if !args.quiet {
         pb.set_style(ProgressStyle::default_spinner());
         pb.set_style(
@@ -417,208 +1204,58 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
     }
 
     let processing = Mutex::new(HashSet::new());
-
-    nodes.for_each(|node| {
-        let path = &node.fullpath;
-        let fullpath = path.strip_prefix(Component::RootDir).unwrap_or(path);
+    let event_dest = dest.to_path_buf();
+    let (info, quiet) = (args.info, args.quiet);
+    let event_pb = pb.clone();
+    let options = options.on_event(move |event| {
+        let pb = &event_pb;
         let mut p = processing.lock().unwrap();
-        p.insert(fullpath);
-        if !args.quiet {
-            pb.set_message(
-                p.iter()
-                    .map(|a| a.to_path_buf().into_os_string().into_string().unwrap())
-                    .collect::<Vec<String>>()
-                    .join(", "),
-            );
-            pb.inc(1);
-        }
-        drop(p);
-
-        let filepath = Path::new(&args.dest).join(fullpath);
-        // create required dirs, we will fix permissions later
-        let _ = fs::create_dir_all(filepath.parent().unwrap());
-
-        match &node.inner {
-            InnerNode::File(file) => {
-                // alloc required space for file data readers
-                let (mut buf_read, mut buf_decompress) = filesystem.alloc_read_buffers();
-
-                // check if file exists
-                if !args.force && filepath.exists() {
-                    if !args.quiet {
-                        exists(&pb, filepath.to_str().unwrap());
-                    }
-                    let mut p = processing.lock().unwrap();
-                    p.remove(fullpath);
-                    drop(p);
-                    return;
-                }
-
-                // write to file
-                let mut fd = File::create(&filepath).unwrap();
-                let file = filesystem.file(&file.basic);
-                let mut reader = file.reader(&mut buf_read, &mut buf_decompress);
-
-                match io::copy(&mut reader, &mut fd) {
-                    Ok(_) => {
-                        if args.info && !args.quiet {
-                            extracted(&pb, filepath.to_str().unwrap());
-                        }
-                        set_attributes(&pb, args, &filepath, &node.header, root_process, true);
-                    }
-                    Err(e) => {
-                        if !args.quiet {
-                            let line = format!("{} : {e}", filepath.to_str().unwrap());
-                            failed(&pb, &line);
-                        }
-                        let mut p = processing.lock().unwrap();
-                        p.remove(fullpath);
-                        drop(p);
-                        return;
-                    }
-                }
-            }
-            InnerNode::Symlink(SquashfsSymlink { link }) => {
-                // create symlink
-                let link_display = link.display();
-                // check if file exists
-                if !args.force && filepath.exists() {
-                    exists(&pb, filepath.to_str().unwrap());
-                    let mut p = processing.lock().unwrap();
-                    p.remove(fullpath);
-                    drop(p);
-                    return;
-                }
-
-                match std::os::unix::fs::symlink(link, &filepath) {
-                    Ok(_) => {
-                        if args.info && !args.quiet {
-                            let line = format!("{}->{link_display}", filepath.to_str().unwrap());
-                            created(&pb, &line);
-                        }
-                    }
-                    Err(e) => {
-                        if !args.quiet {
-                            let line =
-                                format!("{}->{link_display} : {e}", filepath.to_str().unwrap());
-                            failed(&pb, &line);
-                        }
-                        let mut p = processing.lock().unwrap();
-                        p.remove(fullpath);
-                        drop(p);
-                        return;
-                    }
-                }
-
-                // set attributes, but special to not follow the symlink
-
-                if root_process {
-                    // TODO: Use (unix_chown) when not nightly: https://github.com/rust-lang/rust/issues/88989
-                    let path_bytes =
-                        PathBuf::from(&filepath).as_os_str().as_bytes().as_ptr().cast::<i8>();
-                    unsafe {
-                        lchown(path_bytes as *const _, node.header.uid, node.header.gid);
-                    }
+        let (node, path) = match event {
+            ExtractEvent::Start { path, .. } => {
+                p.insert(path.to_path_buf());
+                if !quiet {
+                    pb.set_message(
+                        p.iter()
+                            .map(|a| a.display().to_string())
+                            .collect::<Vec<String>>()
+                            .join(", "),
+                    );
+                    pb.inc(1);
                 }
-
-                // TODO Use (file_set_times) when not nightly: https://github.com/rust-lang/rust/issues/98245
-                // Make sure this doesn't follow symlinks when changed to std library!
-                let timespec = TimeSpec::new(node.header.mtime as _, 0);
-                utimensat(None, &filepath, &timespec, &timespec, UtimensatFlags::NoFollowSymlink)
-                    .unwrap();
+                return;
             }
-            InnerNode::Dir(SquashfsDir { .. }) => {
-                // These permissions are corrected later (user default permissions for now)
-                //
-                // don't display error if this was already created, we might have already
-                // created it in another thread to put down a file
-                if std::fs::create_dir(&filepath).is_ok() && args.info && !args.quiet {
-                    created(&pb, filepath.to_str().unwrap())
+            ExtractEvent::Done { node, path } => (node, path),
+            ExtractEvent::Exists { path, .. } => {
+                p.remove(path);
+                if !quiet {
+                    exists(pb, &event_dest.join(path).display().to_string());
                 }
+                return;
             }
-            InnerNode::CharacterDevice(SquashfsCharacterDevice { device_number }) => {
-                if root_process {
-                    match mknod(
-                        &filepath,
-                        SFlag::S_IFCHR,
-                        Mode::from_bits(mode_t::from(node.header.permissions)).unwrap(),
-                        dev_t::try_from(*device_number).unwrap(),
-                    ) {
-                        Ok(_) => {
-                            if args.info && !args.quiet {
-                                created(&pb, filepath.to_str().unwrap());
-                            }
-
-                            set_attributes(&pb, args, &filepath, &node.header, root_process, true);
-                        }
-                        Err(_) => {
-                            if !args.quiet {
-                                let line = format!(
-                                    "char device {}, are you superuser?",
-                                    filepath.to_str().unwrap()
-                                );
-                                failed(&pb, &line);
-                            }
-                            let mut p = processing.lock().unwrap();
-                            p.remove(fullpath);
-                            drop(p);
-                            return;
-                        }
-                    }
-                } else {
-                    if !args.quiet {
-                        let line = format!(
-                            "char device {}, are you superuser?",
-                            filepath.to_str().unwrap()
-                        );
-                        failed(&pb, &line);
-                    }
-                    let mut p = processing.lock().unwrap();
-                    p.remove(fullpath);
-                    drop(p);
-                    return;
+            ExtractEvent::Failed { path, error, .. } => {
+                p.remove(path);
+                if !quiet {
+                    let line = format!("{} : {error}", event_dest.join(path).display());
+                    failed(pb, &line);
                 }
+                return;
             }
-            InnerNode::BlockDevice(SquashfsBlockDevice { device_number }) => {
-                match mknod(
-                    &filepath,
-                    SFlag::S_IFBLK,
-                    Mode::from_bits(mode_t::from(node.header.permissions)).unwrap(),
-                    dev_t::try_from(*device_number).unwrap(),
-                ) {
-                    Ok(_) => {
-                        if args.info && !args.quiet {
-                            created(&pb, filepath.to_str().unwrap());
-                        }
-
-                        set_attributes(&pb, args, &filepath, &node.header, root_process, true);
-                    }
-                    Err(_) => {
-                        if args.info && !args.quiet {
-                            created(&pb, filepath.to_str().unwrap());
-                        }
-                        let mut p = processing.lock().unwrap();
-                        p.remove(fullpath);
-                        drop(p);
-                        return;
-                    }
+        };
+        p.remove(path);
+        if info && !quiet {
+            let filepath = event_dest.join(path);
+            match &node.inner {
+                InnerNode::File(_) => extracted(pb, &filepath.display().to_string()),
+                InnerNode::Symlink(SquashfsSymlink { link }) => {
+                    let line = format!("{}->{}", filepath.display(), link.display());
+                    created(pb, &line);
                 }
+                _ => created(pb, &filepath.display().to_string()),
             }
         }
-        let mut p = processing.lock().unwrap();
-        p.remove(fullpath);
-        drop(p);
     });
 
-    // fixup dir permissions
-    for node in filesystem.files().filter(|a| a.fullpath.starts_with(&args.path_filter)) {
-        if let InnerNode::Dir(SquashfsDir { .. }) = &node.inner {
-            let path = &node.fullpath;
-            let path = path.strip_prefix(Component::RootDir).unwrap_or(path);
-            let path = Path::new(&args.dest).join(path);
-            set_attributes(&pb, args, &path, &node.header, root_process, false);
-        }
-    }
+    filesystem.extract(dest, &options).unwrap();
 
     pb.finish_and_clear();
 
@@ -628,7 +1265,7 @@ fn extract_all<'a, S: ParallelIterator<Item = &'a Node<SquashfsFileReader>>>(
         println!(
             "{:>16} extraction of {} nodes in {}",
             green_bold.apply_to("Finished"),
-            n_nodes.unwrap(),
+            n_nodes,
             HumanDuration(start.elapsed())
         );
     }
@@ -0,0 +1,207 @@
+//! Size bounded cache of decompressed blocks
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use rustc_hash::FxHashMap;
+
+/// Default max amount of decompressed bytes held by the cache of a [`crate::FilesystemReader`]
+///
+/// 8MiB
+pub const DEFAULT_CACHE_SIZE: usize = 0x80_0000;
+
+/// Key of a cached block, all keyed by the start position of the block in the image
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum CacheKey {
+    Fragment(u64),
+    Data(u64),
+    Metadata(u64),
+}
+
+/// Statistics of the block cache of a [`crate::FilesystemReader`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups that found a cached block
+    pub hits: u64,
+    /// Lookups that didn't find a cached block
+    pub misses: u64,
+    /// Blocks removed to stay within `capacity`
+    pub evictions: u64,
+    /// Amount of cached blocks
+    pub entries: usize,
+    /// Decompressed bytes currently held
+    pub bytes: usize,
+    /// Max amount of decompressed bytes held
+    pub capacity: usize,
+}
+
+/// Least recently used cache of decompressed blocks, bounded by the sum of their sizes
+#[derive(Debug)]
+pub(crate) struct Cache {
+    /// Cached blocks and their last use
+    entries: FxHashMap<CacheKey, (u64, Arc<Vec<u8>>)>,
+    /// Last use to key, first entry is the least recently used
+    lru: BTreeMap<u64, CacheKey>,
+    /// Incremented on every use
+    tick: u64,
+    /// Whether [`CacheKey::Data`] blocks are cached
+    data_blocks: bool,
+    stats: CacheStats,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_SIZE)
+    }
+}
+
+impl Cache {
+    /// Create cache holding at most `capacity` decompressed bytes
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: FxHashMap::default(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            data_blocks: false,
+            stats: CacheStats { capacity, ..CacheStats::default() },
+        }
+    }
+
+    pub fn get(&mut self, key: CacheKey) -> Option<Arc<Vec<u8>>> {
+        let tick = self.next_tick();
+        match self.entries.get_mut(&key) {
+            Some((last_use, bytes)) => {
+                self.lru.remove(last_use);
+                self.lru.insert(tick, key);
+                *last_use = tick;
+                self.stats.hits += 1;
+                Some(bytes.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Insert `bytes`, evicting the least recently used blocks if over capacity.
+    ///
+    /// Blocks larger than the capacity, and data blocks unless enabled with
+    /// [`Self::set_data_blocks`], are not cached.
+    pub fn insert(&mut self, key: CacheKey, bytes: Arc<Vec<u8>>) {
+        if bytes.len() > self.stats.capacity {
+            return;
+        }
+        if let CacheKey::Data(_) = key {
+            if !self.data_blocks {
+                return;
+            }
+        }
+        self.remove(key);
+        let tick = self.next_tick();
+        self.stats.bytes += bytes.len();
+        self.entries.insert(key, (tick, bytes));
+        self.lru.insert(tick, key);
+        self.evict();
+    }
+
+    /// Change the capacity, evicting the least recently used blocks if over the new capacity
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.stats.capacity = capacity;
+        self.evict();
+    }
+
+    /// Enable caching of data blocks, removing the cached data blocks if disabled
+    pub fn set_data_blocks(&mut self, enabled: bool) {
+        self.data_blocks = enabled;
+        if !enabled {
+            let data: Vec<CacheKey> = self
+                .entries
+                .keys()
+                .filter(|key| matches!(key, CacheKey::Data(_)))
+                .copied()
+                .collect();
+            for key in data {
+                self.remove(key);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { entries: self.entries.len(), ..self.stats }
+    }
+
+    fn remove(&mut self, key: CacheKey) {
+        if let Some((last_use, bytes)) = self.entries.remove(&key) {
+            self.lru.remove(&last_use);
+            self.stats.bytes -= bytes.len();
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.stats.bytes > self.stats.capacity {
+            let key = match self.lru.values().next() {
+                Some(key) => *key,
+                None => break,
+            };
+            self.remove(key);
+            self.stats.evictions += 1;
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = Cache::new(8);
+        cache.set_data_blocks(true);
+        cache.insert(CacheKey::Fragment(0), Arc::new(vec![0; 4]));
+        cache.insert(CacheKey::Data(0), Arc::new(vec![1; 4]));
+
+        // use fragment, so data is the least recently used
+        assert!(cache.get(CacheKey::Fragment(0)).is_some());
+        cache.insert(CacheKey::Metadata(0), Arc::new(vec![2; 4]));
+
+        assert!(cache.get(CacheKey::Data(0)).is_none());
+        assert_eq!(cache.get(CacheKey::Fragment(0)).unwrap().as_slice(), &[0; 4]);
+        assert_eq!(cache.get(CacheKey::Metadata(0)).unwrap().as_slice(), &[2; 4]);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.bytes, 8);
+
+        // too big to be cached
+        cache.insert(CacheKey::Data(1), Arc::new(vec![3; 9]));
+        assert!(cache.get(CacheKey::Data(1)).is_none());
+
+        cache.set_capacity(0);
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    #[test]
+    fn data_blocks_disabled() {
+        let mut cache = Cache::new(8);
+        cache.insert(CacheKey::Data(0), Arc::new(vec![0; 4]));
+        assert!(cache.get(CacheKey::Data(0)).is_none());
+
+        cache.set_data_blocks(true);
+        cache.insert(CacheKey::Data(0), Arc::new(vec![0; 4]));
+        cache.insert(CacheKey::Fragment(0), Arc::new(vec![1; 4]));
+        cache.set_data_blocks(false);
+        assert!(cache.get(CacheKey::Data(0)).is_none());
+        assert!(cache.get(CacheKey::Fragment(0)).is_some());
+        assert_eq!(cache.stats().bytes, 4);
+    }
+}
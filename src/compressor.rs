@@ -1,17 +1,12 @@
 //! Types of supported compression algorithms
 
-use std::io::{Cursor, Read};
+use std::cell::RefCell;
+use std::io;
 
 use deku::prelude::*;
 #[cfg(feature = "gzip")]
-use flate2::read::ZlibEncoder;
-#[cfg(feature = "gzip")]
-use flate2::Compression;
-use tracing::instrument;
-#[cfg(feature = "xz")]
-use xz2::read::{XzDecoder, XzEncoder};
-#[cfg(feature = "xz")]
-use xz2::stream::{Check, Filters, LzmaOptions, MtStreamBuilder};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use tracing::{error, instrument};
 
 use crate::error::BackhandError;
 use crate::filesystem::writer::{CompressionExtra, FilesystemCompressor};
@@ -149,11 +144,14 @@ pub trait CompressionAction {
     /// # Arguments
     ///
     /// * `bytes` - Input compressed bytes
-    /// * `out` - Output uncompressed bytes
+    /// * `out` - Output uncompressed bytes. This is pre-allocated by the caller with enough
+    /// capacity for the largest expected output (`block_size` for data, [`METADATA_MAXSIZE`] for
+    /// metadata).
     /// * `compressor` - Compressor id from [SuperBlock]. This can be ignored if your custom
     /// compressor doesn't follow the normal values of the Compressor Id.
     ///
     /// [SuperBlock]: [`crate::SuperBlock`]
+    /// [`METADATA_MAXSIZE`]: crate::metadata::METADATA_MAXSIZE
     fn decompress(
         &self,
         bytes: &[u8],
@@ -177,7 +175,105 @@ pub trait CompressionAction {
     ) -> Result<Vec<u8>, BackhandError>;
 }
 
+/// Compression and decompression contexts kept alive for the whole life of a thread, so that they
+/// are not allocated and initialized for every block
+#[derive(Default)]
+struct Contexts {
+    #[cfg(feature = "gzip")]
+    inflate: Option<Decompress>,
+    #[cfg(feature = "gzip")]
+    deflate: Option<(u32, Compress)>,
+    #[cfg(feature = "lzo")]
+    lzo: Option<rust_lzo::LZOContext>,
+    #[cfg(feature = "zstd")]
+    zstd_decompressor: Option<zstd::bulk::Decompressor<'static>>,
+    #[cfg(feature = "zstd")]
+    zstd_compressor: Option<(i32, zstd::bulk::Compressor<'static>)>,
+    #[cfg(feature = "xz")]
+    xz_decoder: Option<XzStream>,
+}
+
+/// liblzma decoder stream, starting an existing stream again reuses its allocations
+///
+/// xz2 has no way of resetting a `Stream`, so liblzma is used through lzma-sys.
+#[cfg(feature = "xz")]
+struct XzStream {
+    raw: lzma_sys::lzma_stream,
+}
+
+#[cfg(feature = "xz")]
+impl XzStream {
+    fn new() -> Self {
+        // same as LZMA_STREAM_INIT
+        Self { raw: unsafe { std::mem::zeroed() } }
+    }
+
+    /// Start a .xz decoder
+    fn start_decoder(&mut self) -> Result<(), BackhandError> {
+        let ret = unsafe { lzma_sys::lzma_stream_decoder(&mut self.raw, u64::MAX, 0) };
+        xz_result(ret).map(|_| ())
+    }
+
+    /// Process `input` into the spare capacity of `out`, returns `true` at the end of the stream
+    fn finish(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<bool, BackhandError> {
+        let len = out.len();
+        let spare = out.capacity() - len;
+        self.raw.next_in = input.as_ptr();
+        self.raw.avail_in = input.len();
+        self.raw.next_out = unsafe { out.as_mut_ptr().add(len) };
+        self.raw.avail_out = spare;
+        let ret = unsafe { lzma_sys::lzma_code(&mut self.raw, lzma_sys::LZMA_FINISH) };
+        // liblzma only writes bytes that it reports as written
+        unsafe { out.set_len(len + spare - self.raw.avail_out) };
+        xz_result(ret)
+    }
+
+    fn total_in(&self) -> u64 {
+        self.raw.total_in
+    }
+}
+
+#[cfg(feature = "xz")]
+impl Drop for XzStream {
+    fn drop(&mut self) {
+        unsafe { lzma_sys::lzma_end(&mut self.raw) };
+    }
+}
+
+/// Returns `true` for the end of the stream, `false` if more input or output space is needed
+#[cfg(feature = "xz")]
+fn xz_result(ret: lzma_sys::lzma_ret) -> Result<bool, BackhandError> {
+    let kind = match ret {
+        lzma_sys::LZMA_STREAM_END => return Ok(true),
+        lzma_sys::LZMA_OK | lzma_sys::LZMA_BUF_ERROR => return Ok(false),
+        lzma_sys::LZMA_DATA_ERROR | lzma_sys::LZMA_FORMAT_ERROR => io::ErrorKind::InvalidData,
+        lzma_sys::LZMA_OPTIONS_ERROR => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+    Err(io::Error::new(kind, format!("xz error {ret}")).into())
+}
+
+thread_local! {
+    static CONTEXTS: RefCell<Contexts> = RefCell::new(Contexts::default());
+}
+
+/// Called when a decompressor stops making progress before the end of the stream
+fn decompress_stalled(out: &[u8], capacity: usize) -> BackhandError {
+    if out.len() == capacity {
+        error!("decompressed bytes are larger than the output buffer({capacity:#02x?})");
+        return BackhandError::LimitExceeded {
+            limit: "decompressed block size",
+            max: capacity as u64,
+        };
+    }
+    error!("compressed bytes ended before the end of the stream");
+    BackhandError::CorruptedOrInvalidSquashfs
+}
+
 /// Default compressor that handles the compression features that are enabled
+///
+/// Decompression never grows the `out` buffer passed to [`CompressionAction::decompress`], output
+/// that doesn't fit into its capacity is reported as [`BackhandError::LimitExceeded`].
 #[derive(Copy, Clone)]
 pub struct DefaultCompressor;
 
@@ -192,30 +288,75 @@ impl CompressionAction for DefaultCompressor {
     ) -> Result<(), BackhandError> {
         match compressor {
             #[cfg(feature = "gzip")]
-            Compressor::Gzip => {
-                let mut decoder = flate2::read::ZlibDecoder::new(bytes);
-                decoder.read_to_end(out)?;
-            }
+            Compressor::Gzip => CONTEXTS.with(|contexts| -> Result<(), BackhandError> {
+                let mut contexts = contexts.borrow_mut();
+                let inflate = match &mut contexts.inflate {
+                    Some(inflate) => {
+                        inflate.reset(true);
+                        inflate
+                    }
+                    inflate => inflate.insert(Decompress::new(true)),
+                };
+                let mut consumed = 0;
+                loop {
+                    let (before_in, before_out) = (inflate.total_in(), out.len());
+                    let status = inflate
+                        .decompress_vec(&bytes[consumed..], out, FlushDecompress::Finish)
+                        .map_err(io::Error::from)?;
+                    consumed += (inflate.total_in() - before_in) as usize;
+                    if status == flate2::Status::StreamEnd {
+                        return Ok(());
+                    }
+                    if inflate.total_in() == before_in && out.len() == before_out {
+                        return Err(decompress_stalled(out, out.capacity()));
+                    }
+                }
+            })?,
             #[cfg(feature = "xz")]
-            Compressor::Xz => {
-                let mut decoder = XzDecoder::new(bytes);
-                decoder.read_to_end(out)?;
-            }
+            Compressor::Xz => CONTEXTS.with(|contexts| -> Result<(), BackhandError> {
+                let mut contexts = contexts.borrow_mut();
+                let stream = contexts.xz_decoder.get_or_insert_with(XzStream::new);
+                stream.start_decoder()?;
+                let mut consumed = 0;
+                loop {
+                    let (before_in, before_out) = (stream.total_in(), out.len());
+                    let end = stream.finish(&bytes[consumed..], out)?;
+                    consumed += (stream.total_in() - before_in) as usize;
+                    if end {
+                        return Ok(());
+                    }
+                    if stream.total_in() == before_in && out.len() == before_out {
+                        return Err(decompress_stalled(out, out.capacity()));
+                    }
+                }
+            })?,
             #[cfg(feature = "lzo")]
             Compressor::Lzo => {
                 out.resize(out.capacity(), 0);
                 let (out_size, error) = rust_lzo::LZOContext::decompress_to_slice(bytes, out);
                 let out_size = out_size.len();
                 out.truncate(out_size);
+                if error == rust_lzo::LZOError::OUTPUT_OVERRUN {
+                    return Err(BackhandError::LimitExceeded {
+                        limit: "decompressed block size",
+                        max: out.capacity() as u64,
+                    });
+                }
                 if error != rust_lzo::LZOError::OK {
                     return Err(BackhandError::CorruptedOrInvalidSquashfs);
                 }
             }
             #[cfg(feature = "zstd")]
-            Compressor::Zstd => {
-                let mut decoder = zstd::bulk::Decompressor::new().unwrap();
+            Compressor::Zstd => CONTEXTS.with(|contexts| -> Result<(), BackhandError> {
+                let mut contexts = contexts.borrow_mut();
+                let decoder = match &mut contexts.zstd_decompressor {
+                    Some(decoder) => decoder,
+                    decoder => decoder.insert(zstd::bulk::Decompressor::new()?),
+                };
+                // writes into the capacity of `out`, failing if the output doesn't fit
                 decoder.decompress_to_buffer(bytes, out)?;
-            }
+                Ok(())
+            })?,
             _ => return Err(BackhandError::UnsupportedCompression(compressor)),
         }
         Ok(())
@@ -247,44 +388,62 @@ impl CompressionAction for DefaultCompressor {
                         }
                     }
                 };
-                let check = Check::Crc32;
-                let mut opts = LzmaOptions::new_preset(level).unwrap();
-                opts.dict_size(dict_size);
+                let mut opts: lzma_sys::lzma_options_lzma = unsafe { std::mem::zeroed() };
+                if unsafe { lzma_sys::lzma_lzma_preset(&mut opts, level) } != 0 {
+                    return Err(BackhandError::InvalidCompressionOption);
+                }
+                opts.dict_size = dict_size;
 
-                let mut filters = Filters::new();
+                // BCJ filters first, then LZMA2, terminated by LZMA_VLI_UNKNOWN
+                let bcj = |id| lzma_sys::lzma_filter { id, options: std::ptr::null_mut() };
+                let mut filters = vec![];
                 if let Some(CompressionOptions::Xz(xz)) = option {
-                    if xz.filters.x86() {
-                        filters.x86();
+                    let bcj_filters = [
+                        (xz.filters.x86(), lzma_sys::LZMA_FILTER_X86),
+                        (xz.filters.powerpc(), lzma_sys::LZMA_FILTER_POWERPC),
+                        (xz.filters.ia64(), lzma_sys::LZMA_FILTER_IA64),
+                        (xz.filters.arm(), lzma_sys::LZMA_FILTER_ARM),
+                        (xz.filters.armthumb(), lzma_sys::LZMA_FILTER_ARMTHUMB),
+                        (xz.filters.sparc(), lzma_sys::LZMA_FILTER_SPARC),
+                    ];
+                    for (_, id) in bcj_filters.into_iter().filter(|(enabled, _)| *enabled) {
+                        filters.push(bcj(id));
                     }
-                    if xz.filters.powerpc() {
-                        filters.powerpc();
-                    }
-                    if xz.filters.ia64() {
-                        filters.ia64();
-                    }
-                    if xz.filters.arm() {
-                        filters.arm();
-                    }
-                    if xz.filters.armthumb() {
-                        filters.arm_thumb();
-                    }
-                    if xz.filters.sparc() {
-                        filters.sparc();
+                }
+                filters.push(lzma_sys::lzma_filter {
+                    id: lzma_sys::LZMA_FILTER_LZMA2,
+                    options: &mut opts as *mut _ as *mut _,
+                });
+                filters.push(bcj(lzma_sys::LZMA_VLI_UNKNOWN));
+
+                // The single-threaded buffer encoder stores the compressed and uncompressed sizes
+                // in the block header, the same as squashfs-tools. Blocks are already compressed
+                // in parallel by the writer, and the encoder has no state worth keeping.
+                let mut buf = vec![0; bytes.len() + bytes.len() / 4 + 1024];
+                loop {
+                    let mut len = 0;
+                    let ret = unsafe {
+                        lzma_sys::lzma_stream_buffer_encode(
+                            filters.as_mut_ptr(),
+                            lzma_sys::LZMA_CHECK_CRC32,
+                            std::ptr::null(),
+                            bytes.as_ptr(),
+                            bytes.len(),
+                            buf.as_mut_ptr(),
+                            &mut len,
+                            buf.len(),
+                        )
+                    };
+                    match ret {
+                        // output didn't fit, this only happens when the bytes aren't compressible
+                        lzma_sys::LZMA_BUF_ERROR => buf.resize(buf.len() * 2, 0),
+                        ret => {
+                            xz_result(ret)?;
+                            buf.truncate(len);
+                            return Ok(buf);
+                        }
                     }
                 }
-                filters.lzma2(&opts);
-
-                let stream = MtStreamBuilder::new()
-                    .threads(2)
-                    .filters(filters)
-                    .check(check)
-                    .encoder()
-                    .unwrap();
-
-                let mut encoder = XzEncoder::new_stream(Cursor::new(bytes), stream);
-                let mut buf = vec![];
-                encoder.read_to_end(&mut buf)?;
-                Ok(buf)
             }
             #[cfg(feature = "gzip")]
             (Compressor::Gzip, option @ (Some(CompressionOptions::Gzip(_)) | None), _) => {
@@ -298,20 +457,47 @@ impl CompressionAction for DefaultCompressor {
 
                 // TODO(#8): Use window_size and strategies (current window size defaults to 15)
 
-                let mut encoder = ZlibEncoder::new(Cursor::new(bytes), compression_level);
-                let mut buf = vec![];
-                encoder.read_to_end(&mut buf)?;
-                Ok(buf)
+                CONTEXTS.with(|contexts| -> Result<Vec<u8>, BackhandError> {
+                    let mut contexts = contexts.borrow_mut();
+                    let level = compression_level.level();
+                    let deflate = match &mut contexts.deflate {
+                        Some((deflate_level, deflate)) if *deflate_level == level => {
+                            deflate.reset();
+                            deflate
+                        }
+                        deflate => {
+                            &mut deflate.insert((level, Compress::new(compression_level, true))).1
+                        }
+                    };
+
+                    let mut buf = Vec::with_capacity(bytes.len());
+                    let mut consumed = 0;
+                    loop {
+                        let before_in = deflate.total_in();
+                        let status = deflate
+                            .compress_vec(&bytes[consumed..], &mut buf, FlushCompress::Finish)
+                            .map_err(io::Error::from)?;
+                        consumed += (deflate.total_in() - before_in) as usize;
+                        if status == flate2::Status::StreamEnd {
+                            return Ok(buf);
+                        }
+                        // output didn't fit, this only happens when the bytes aren't compressible
+                        buf.reserve(buf.capacity().max(64));
+                    }
+                })
             }
             #[cfg(feature = "lzo")]
             (Compressor::Lzo, _, _) => {
-                let mut lzo = rust_lzo::LZOContext::new();
-                let mut buf = vec![0; rust_lzo::worst_compress(bytes.len())];
-                let error = lzo.compress(bytes, &mut buf);
-                if error != rust_lzo::LZOError::OK {
-                    return Err(BackhandError::CorruptedOrInvalidSquashfs);
-                }
-                Ok(buf)
+                CONTEXTS.with(|contexts| -> Result<Vec<u8>, BackhandError> {
+                    let mut contexts = contexts.borrow_mut();
+                    let lzo = contexts.lzo.get_or_insert_with(rust_lzo::LZOContext::new);
+                    let mut buf = vec![0; rust_lzo::worst_compress(bytes.len())];
+                    let error = lzo.compress(bytes, &mut buf);
+                    if error != rust_lzo::LZOError::OK {
+                        return Err(BackhandError::CorruptedOrInvalidSquashfs);
+                    }
+                    Ok(buf)
+                })
             }
             #[cfg(feature = "zstd")]
             (Compressor::Zstd, option @ (Some(CompressionOptions::Zstd(_)) | None), _) => {
@@ -320,10 +506,19 @@ impl CompressionAction for DefaultCompressor {
                     Some(CompressionOptions::Zstd(option)) => option.compression_level,
                     Some(_) => unreachable!(),
                 };
-                let mut encoder = zstd::bulk::Compressor::new(compression_level as i32)?;
-                let mut buf = vec![];
-                encoder.compress_to_buffer(bytes, &mut buf)?;
-                Ok(buf)
+                CONTEXTS.with(|contexts| -> Result<Vec<u8>, BackhandError> {
+                    let mut contexts = contexts.borrow_mut();
+                    let level = compression_level as i32;
+                    let encoder = match &mut contexts.zstd_compressor {
+                        Some((encoder_level, encoder)) if *encoder_level == level => encoder,
+                        encoder => {
+                            &mut encoder.insert((level, zstd::bulk::Compressor::new(level)?)).1
+                        }
+                    };
+                    let mut buf = Vec::with_capacity(zstd::zstd_safe::compress_bound(bytes.len()));
+                    encoder.compress_to_buffer(bytes, &mut buf)?;
+                    Ok(buf)
+                })
             }
             _ => Err(BackhandError::UnsupportedCompression(fc.id)),
         }
@@ -3,6 +3,8 @@
 use std::io::{Read, Seek, Write};
 
 use deku::prelude::*;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use tracing::instrument;
 
 use crate::compressor::CompressionAction;
@@ -15,6 +17,10 @@ use crate::reader::WriteSeek;
 // bitflag for data size field in inode for signifying that the data is uncompressed
 const DATA_STORED_UNCOMPRESSED: u32 = 1 << 24;
 
+/// Amount of full fragment blocks compressed and written together. Not depending on the amount
+/// of threads, so the image is the same no matter the amount of threads.
+const FRAGMENT_BATCH_LEN: usize = 16;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
 #[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
 pub struct DataSize(u32);
@@ -90,31 +96,113 @@ impl<R: std::io::Read> DataWriterChunkReader<R> {
     }
 }
 
+/// Threads compressing the data blocks of a [`DataWriter`]
+enum Compressors {
+    /// Calling thread only
+    Sequential,
+    /// Global rayon pool
+    Global,
+    /// Pool built with the amount of threads given to [`DataWriter::new`]
+    Pool(ThreadPool),
+}
+
 pub(crate) struct DataWriter<'a> {
-    kind: &'a dyn CompressionAction,
+    kind: &'a (dyn CompressionAction + Send + Sync),
     block_size: u32,
     fs_compressor: FilesystemCompressor,
+    /// Threads used for compressing data blocks
+    compressors: Compressors,
+    /// Amount of blocks read ahead before being compressed together
+    batch_len: usize,
     /// Un-written fragment_bytes
     pub(crate) fragment_bytes: Vec<u8>,
+    /// Full fragment blocks waiting to be compressed, see [`FRAGMENT_BATCH_LEN`]
+    pending_fragments: Vec<Vec<u8>>,
     pub(crate) fragment_table: Vec<Fragment>,
 }
 
 impl<'a> DataWriter<'a> {
+    /// Create a new `DataWriter`, compressing data blocks with `threads` threads.
+    ///
+    /// `threads` of `0` uses the global rayon pool, `1` compresses on the calling thread.
     #[instrument(skip_all)]
     pub fn new(
-        kind: &'a dyn CompressionAction,
+        kind: &'a (dyn CompressionAction + Send + Sync),
         fs_compressor: FilesystemCompressor,
         block_size: u32,
-    ) -> Self {
-        Self {
+        threads: usize,
+    ) -> Result<Self, BackhandError> {
+        // nothing to gain from a pool with a single thread
+        let (compressors, num_threads) = match threads {
+            1 => (Compressors::Sequential, 1),
+            0 if rayon::current_num_threads() == 1 => (Compressors::Sequential, 1),
+            0 => (Compressors::Global, rayon::current_num_threads()),
+            threads => {
+                let pool = ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .thread_name(|i| format!("backhand-compress-{i}"))
+                    .build()
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                (Compressors::Pool(pool), threads)
+            }
+        };
+        let batch_len = if num_threads == 1 { 1 } else { num_threads * 2 };
+
+        Ok(Self {
             kind,
             block_size,
             fs_compressor,
+            compressors,
+            batch_len,
             fragment_bytes: Vec::with_capacity(block_size as usize),
+            pending_fragments: vec![],
             fragment_table: vec![],
+        })
+    }
+
+    /// Compress all `blocks`, in parallel unless compressing on the calling thread.
+    ///
+    /// The returned blocks are in the same order as `blocks`.
+    fn compress_blocks(&self, blocks: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, BackhandError> {
+        let kind = self.kind;
+        let fs_compressor = self.fs_compressor;
+        let block_size = self.block_size;
+        let compress = || -> Result<Vec<Vec<u8>>, BackhandError> {
+            blocks.par_iter().map(|block| kind.compress(block, fs_compressor, block_size)).collect()
+        };
+        match &self.compressors {
+            Compressors::Global if blocks.len() > 1 => compress(),
+            Compressors::Pool(pool) if blocks.len() > 1 => pool.install(compress),
+            _ => {
+                blocks.iter().map(|block| kind.compress(block, fs_compressor, block_size)).collect()
+            }
         }
     }
 
+    /// Compress and write all `blocks` in order, draining `blocks` and adding the resulting
+    /// sizes to `block_sizes`
+    fn write_blocks<W: WriteSeek>(
+        &self,
+        blocks: &mut Vec<Vec<u8>>,
+        block_sizes: &mut Vec<DataSize>,
+        writer: &mut W,
+    ) -> Result<(), BackhandError> {
+        let compressed = self.compress_blocks(blocks)?;
+        for (block, cb) in blocks.drain(..).zip(compressed) {
+            // compression didn't reduce size
+            if cb.len() > block.len() {
+                // store uncompressed
+                block_sizes.push(DataSize::new_uncompressed(block.len() as u32));
+                writer.write_all(&block)?;
+            } else {
+                // store compressed
+                block_sizes.push(DataSize::new_compressed(cb.len() as u32));
+                writer.write_all(&cb)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Add to data writer, either a pre-compressed Data or Fragment
     // TODO: support tail-end fragments (off by default in squashfs-tools/mksquashfs)
     pub(crate) fn just_copy_it<W: WriteSeek>(
@@ -140,10 +228,10 @@ impl<'a> DataWriter<'a> {
             // if this doesn't fit in the current fragment bytes
             // compress the current fragment bytes and add to data_bytes
             if (decompress_buf.len() + self.fragment_bytes.len()) > self.block_size as usize {
-                self.finalize(writer)?;
+                self.next_fragment(writer)?;
             }
             // add to fragment bytes
-            let frag_index = self.fragment_table.len() as u32;
+            let frag_index = self.frag_index();
             let block_offset = self.fragment_bytes.len() as u32;
             self.fragment_bytes.write_all(&decompress_buf)?;
 
@@ -197,57 +285,77 @@ impl<'a> DataWriter<'a> {
             // if this doesn't fit in the current fragment bytes
             // compress the current fragment bytes and add to data_bytes
             if (chunk.len() + self.fragment_bytes.len()) > self.block_size as usize {
-                self.finalize(writer)?;
+                self.next_fragment(writer)?;
             }
 
             // add to fragment bytes
-            let frag_index = self.fragment_table.len() as u32;
+            let frag_index = self.frag_index();
             let block_offset = self.fragment_bytes.len() as u32;
             self.fragment_bytes.write_all(chunk)?;
 
             Ok((chunk_reader.file_len, Added::Fragment { frag_index, block_offset }))
         } else {
-            // Add to data bytes
+            // Add to data bytes, reading ahead `batch_len` blocks and compressing them together
             let blocks_start = writer.stream_position()? as u32;
             let mut block_sizes = vec![];
+            let mut blocks = Vec::with_capacity(self.batch_len);
             while !chunk.is_empty() {
-                let cb = self.kind.compress(chunk, self.fs_compressor, self.block_size)?;
-
-                // compression didn't reduce size
-                if cb.len() > chunk.len() {
-                    // store uncompressed
-                    block_sizes.push(DataSize::new_uncompressed(chunk.len() as u32));
-                    writer.write_all(chunk)?;
-                } else {
-                    // store compressed
-                    block_sizes.push(DataSize::new_compressed(cb.len() as u32));
-                    writer.write_all(&cb)?;
+                blocks.push(chunk.to_vec());
+                if blocks.len() == self.batch_len {
+                    self.write_blocks(&mut blocks, &mut block_sizes, writer)?;
                 }
                 chunk = chunk_reader.read_chunk()?;
             }
+            self.write_blocks(&mut blocks, &mut block_sizes, writer)?;
 
             Ok((chunk_reader.file_len, Added::Data { blocks_start, block_sizes }))
         }
     }
 
-    /// Compress the fragments that were under length, write to data, add to fragment table, clear
-    /// current fragment_bytes
-    pub fn finalize<W: Write + Seek>(&mut self, writer: &mut W) -> Result<(), BackhandError> {
-        let start = writer.stream_position()?;
-        let cb = self.kind.compress(&self.fragment_bytes, self.fs_compressor, self.block_size)?;
-
-        // compression didn't reduce size
-        let size = if cb.len() > self.fragment_bytes.len() {
-            // store uncompressed
-            writer.write_all(&self.fragment_bytes)?;
-            DataSize::new_uncompressed(self.fragment_bytes.len() as u32)
-        } else {
-            // store compressed
-            writer.write_all(&cb)?;
-            DataSize::new_compressed(cb.len() as u32)
-        };
-        self.fragment_table.push(Fragment::new(start, size, 0));
-        self.fragment_bytes.clear();
+    /// Index in the fragment table of the current fragment block
+    fn frag_index(&self) -> u32 {
+        (self.fragment_table.len() + self.pending_fragments.len()) as u32
+    }
+
+    /// Start a new fragment block, writing the full fragment blocks once a batch is complete
+    fn next_fragment<W: Write + Seek>(&mut self, writer: &mut W) -> Result<(), BackhandError> {
+        let full = std::mem::replace(
+            &mut self.fragment_bytes,
+            Vec::with_capacity(self.block_size as usize),
+        );
+        self.pending_fragments.push(full);
+        if self.pending_fragments.len() == FRAGMENT_BATCH_LEN {
+            self.write_fragments(writer)?;
+        }
         Ok(())
     }
+
+    /// Compress the pending fragment blocks in parallel, write them to data and add them to the
+    /// fragment table
+    fn write_fragments<W: Write + Seek>(&mut self, writer: &mut W) -> Result<(), BackhandError> {
+        let compressed = self.compress_blocks(&self.pending_fragments)?;
+        for (fragment, cb) in self.pending_fragments.drain(..).zip(compressed) {
+            let start = writer.stream_position()?;
+            // compression didn't reduce size
+            let size = if cb.len() > fragment.len() {
+                // store uncompressed
+                writer.write_all(&fragment)?;
+                DataSize::new_uncompressed(fragment.len() as u32)
+            } else {
+                // store compressed
+                writer.write_all(&cb)?;
+                DataSize::new_compressed(cb.len() as u32)
+            };
+            self.fragment_table.push(Fragment::new(start, size, 0));
+        }
+        Ok(())
+    }
+
+    /// Compress the fragments that were under length, and the pending full fragment blocks,
+    /// write to data, add to fragment table, clear current fragment_bytes
+    pub fn finalize<W: Write + Seek>(&mut self, writer: &mut W) -> Result<(), BackhandError> {
+        let last = std::mem::take(&mut self.fragment_bytes);
+        self.pending_fragments.push(last);
+        self.write_fragments(writer)
+    }
 }
@@ -6,7 +6,7 @@
 use core::fmt;
 use std::ffi::OsStr;
 use std::os::unix::prelude::OsStrExt;
-use std::path::{Component, Path};
+use std::path::Path;
 
 use deku::prelude::*;
 
@@ -79,10 +79,13 @@ impl fmt::Debug for DirEntry {
 }
 
 impl DirEntry {
+    /// Name of the entry, only a single normal path component is valid.
+    ///
+    /// Empty names, `.`, `..`, `/`, and names containing `/` or NUL are rejected, so a path
+    /// joined from names can't escape its directory.
     pub fn name(&self) -> Result<&Path, BackhandError> {
-        // allow root and nothing else
-        if self.name == Component::RootDir.as_os_str().as_bytes() {
-            return Ok(Path::new(Component::RootDir.as_os_str()));
+        if self.name.contains(&0) {
+            return Err(BackhandError::InvalidFilePath);
         }
         let path = Path::new(OsStr::from_bytes(&self.name));
         // if not a simple filename, return an error
@@ -121,7 +124,7 @@ impl fmt::Debug for DirectoryIndex {
 
 impl DirectoryIndex {
     pub fn name(&self) -> String {
-        std::str::from_utf8(&self.name).unwrap().to_string()
+        String::from_utf8_lossy(&self.name).into_owned()
     }
 }
 
@@ -131,24 +134,19 @@ mod tests {
 
     #[test]
     fn no_invalid_dir_entry() {
-        // just root
-        let dir = DirEntry {
+        let entry = |name: &[u8]| DirEntry {
             offset: 0x300,
             inode_offset: 0x0,
             t: InodeId::BasicDirectory,
-            name_size: 0x1,
-            name: b"/".to_vec(),
+            name_size: name.len().saturating_sub(1) as u16,
+            name: name.to_vec(),
         };
-        assert_eq!(Path::new("/"), dir.name().unwrap());
+        assert_eq!(Path::new("nice"), entry(b"nice").name().unwrap());
+        assert_eq!(Path::new("..nice"), entry(b"..nice").name().unwrap());
 
         // InvalidFilePath
-        let dir = DirEntry {
-            offset: 0x300,
-            inode_offset: 0x0,
-            t: InodeId::BasicDirectory,
-            name_size: 0x1,
-            name: b"/nice/".to_vec(),
-        };
-        assert!(dir.name().is_err());
+        for name in [&b"/"[..], b"/nice/", b"nice/", b"a/b", b".", b"..", b"", b"ni\0ce"] {
+            assert!(entry(name).name().is_err(), "{name:?}");
+        }
     }
 }
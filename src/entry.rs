@@ -2,10 +2,11 @@ use std::ffi::OsStr;
 use std::fmt;
 use std::os::unix::prelude::OsStrExt;
 
-use tracing::instrument;
+use tracing::{error, instrument};
 
 use crate::data::Added;
 use crate::dir::{Dir, DirEntry};
+use crate::error::BackhandError;
 use crate::inode::{
     BasicDeviceSpecialFile, BasicDirectory, BasicFile, BasicSymlink, ExtendedDirectory, Inode,
     InodeHeader, InodeId, InodeInner,
@@ -26,8 +27,31 @@ pub(crate) struct Entry<'a> {
 }
 
 impl<'a> Entry<'a> {
+    /// Header of the inode with number `inode`, with the uid and gid of `header` as index into
+    /// `id_table`
+    fn inode_header(
+        header: NodeHeader,
+        inode: u32,
+        id_table: &[Id],
+    ) -> Result<InodeHeader, BackhandError> {
+        let index = |id: u32| -> Result<u16, BackhandError> {
+            let index = id_table.iter().position(|a| a.num == id).ok_or_else(|| {
+                error!("id {id} is not in the id table");
+                BackhandError::Unreachable
+            })?;
+            Ok(index.try_into()?)
+        };
+        Ok(InodeHeader {
+            inode_number: inode,
+            uid: index(header.uid)?,
+            gid: index(header.gid)?,
+            permissions: header.permissions,
+            mtime: header.mtime,
+        })
+    }
+
     pub fn name(&self) -> String {
-        std::str::from_utf8(self.name).unwrap().to_string()
+        String::from_utf8_lossy(self.name).into_owned()
     }
 
     /// Write data and metadata for path node (Basic Directory or ExtendedDirectory)
@@ -45,24 +69,16 @@ impl<'a> Entry<'a> {
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
-    ) -> Self {
-        let uid = id_table.iter().position(|a| a.num == header.uid).unwrap() as u16;
-        let gid = id_table.iter().position(|a| a.num == header.gid).unwrap() as u16;
-        let header = InodeHeader {
-            inode_number: inode,
-            uid,
-            gid,
-            permissions: header.permissions,
-            mtime: header.mtime,
-        };
+    ) -> Result<Self, BackhandError> {
+        let header = Self::inode_header(header, inode, id_table)?;
         // if entry won't fit in file_size of regular dir entry, create extended directory
         let dir_inode = if file_size > u16::MAX as usize {
             Inode::new(
                 InodeId::ExtendedDirectory,
                 header,
                 InodeInner::ExtendedDirectory(ExtendedDirectory {
-                    link_count: 2 + u32::try_from(children_num).unwrap(),
-                    file_size: file_size.try_into().unwrap(), // u32
+                    link_count: 2 + u32::try_from(children_num)?,
+                    file_size: file_size.try_into()?, // u32
                     block_index,
                     parent_inode,
                     // TODO: Support Directory Index
@@ -80,8 +96,8 @@ impl<'a> Entry<'a> {
                 header,
                 InodeInner::BasicDirectory(BasicDirectory {
                     block_index,
-                    link_count: 2 + u32::try_from(children_num).unwrap(),
-                    file_size: file_size.try_into().unwrap(), // u16
+                    link_count: 2 + u32::try_from(children_num)?,
+                    file_size: file_size.try_into()?, // u16
                     block_offset,
                     parent_inode,
                 }),
@@ -103,23 +119,15 @@ impl<'a> Entry<'a> {
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
-    ) -> Self {
-        let uid = id_table.iter().position(|a| a.num == header.uid).unwrap() as u16;
-        let gid = id_table.iter().position(|a| a.num == header.gid).unwrap() as u16;
-        let header = InodeHeader {
-            inode_number: inode,
-            uid,
-            gid,
-            permissions: header.permissions,
-            mtime: header.mtime,
-        };
+    ) -> Result<Self, BackhandError> {
+        let header = Self::inode_header(header, inode, id_table)?;
         let basic_file = match added {
             Added::Data { blocks_start, block_sizes } => {
                 BasicFile {
                     blocks_start: *blocks_start,
                     frag_index: 0xffffffff, // <- no fragment
                     block_offset: 0x0,      // <- no fragment
-                    file_size: file_size.try_into().unwrap(),
+                    file_size: file_size.try_into()?,
                     block_sizes: block_sizes.to_vec(),
                 }
             }
@@ -127,7 +135,7 @@ impl<'a> Entry<'a> {
                 blocks_start: 0,
                 frag_index: *frag_index,
                 block_offset: *block_offset,
-                file_size: file_size.try_into().unwrap(),
+                file_size: file_size.try_into()?,
                 block_sizes: vec![],
             },
         };
@@ -148,23 +156,15 @@ impl<'a> Entry<'a> {
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
-    ) -> Self {
-        let uid = id_table.iter().position(|a| a.num == header.uid).unwrap() as u16;
-        let gid = id_table.iter().position(|a| a.num == header.gid).unwrap() as u16;
-        let header = InodeHeader {
-            inode_number: inode,
-            uid,
-            gid,
-            permissions: header.permissions,
-            mtime: header.mtime,
-        };
+    ) -> Result<Self, BackhandError> {
+        let header = Self::inode_header(header, inode, id_table)?;
         let link = symlink.link.as_os_str().as_bytes();
         let sym_inode = Inode::new(
             InodeId::BasicSymlink,
             header,
             InodeInner::BasicSymlink(BasicSymlink {
                 link_count: 0x1,
-                target_size: link.len().try_into().unwrap(),
+                target_size: link.len().try_into()?,
                 target_path: link.to_vec(),
             }),
         );
@@ -183,16 +183,8 @@ impl<'a> Entry<'a> {
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
-    ) -> Self {
-        let uid = id_table.iter().position(|a| a.num == header.uid).unwrap() as u16;
-        let gid = id_table.iter().position(|a| a.num == header.gid).unwrap() as u16;
-        let header = InodeHeader {
-            inode_number: inode,
-            uid,
-            gid,
-            permissions: header.permissions,
-            mtime: header.mtime,
-        };
+    ) -> Result<Self, BackhandError> {
+        let header = Self::inode_header(header, inode, id_table)?;
         let char_inode = Inode::new(
             InodeId::BasicCharacterDevice,
             header,
@@ -216,16 +208,8 @@ impl<'a> Entry<'a> {
         superblock: &SuperBlock,
         kind: &Kind,
         id_table: &[Id],
-    ) -> Self {
-        let uid = id_table.iter().position(|a| a.num == header.uid).unwrap() as u16;
-        let gid = id_table.iter().position(|a| a.num == header.gid).unwrap() as u16;
-        let header = InodeHeader {
-            inode_number: inode,
-            uid,
-            gid,
-            permissions: header.permissions,
-            mtime: header.mtime,
-        };
+    ) -> Result<Self, BackhandError> {
+        let header = Self::inode_header(header, inode, id_table)?;
         let block_inode = Inode::new(
             InodeId::BasicBlockDevice,
             header,
@@ -253,12 +237,17 @@ impl<'a> fmt::Debug for Entry<'a> {
 }
 
 impl<'a> Entry<'a> {
-    fn create_dir(creating_dir: &Vec<&Self>, start: u32, lowest_inode: u32) -> Dir {
+    fn create_dir(
+        creating_dir: &Vec<&Self>,
+        start: u32,
+        lowest_inode: u32,
+    ) -> Result<Dir, BackhandError> {
         let mut dir = Dir::new(lowest_inode);
 
-        dir.count = creating_dir.len().try_into().unwrap();
+        dir.count = creating_dir.len().try_into()?;
         if dir.count >= 256 {
-            panic!("dir.count({}) >= 256:", dir.count);
+            error!("dir.count({}) >= 256", dir.count);
+            return Err(BackhandError::Unreachable);
         }
 
         dir.start = start;
@@ -266,7 +255,7 @@ impl<'a> Entry<'a> {
             let inode = e.inode;
             let new_entry = DirEntry {
                 offset: e.offset,
-                inode_offset: (inode - lowest_inode).try_into().unwrap(),
+                inode_offset: (inode - lowest_inode).try_into()?,
                 t: e.t.into_base_type(),
                 name_size: e.name_size,
                 name: e.name.to_vec(),
@@ -274,12 +263,12 @@ impl<'a> Entry<'a> {
             dir.push(new_entry);
         }
 
-        dir
+        Ok(dir)
     }
 
     /// Create entries, input need to be alphabetically sorted
     #[instrument(skip_all)]
-    pub(crate) fn into_dir(entries: Vec<Self>) -> Vec<Dir> {
+    pub(crate) fn into_dir(entries: Vec<Self>) -> Result<Vec<Dir>, BackhandError> {
         let mut dirs = vec![];
         let mut creating_dir = vec![];
         let mut lowest_inode = u32::MAX;
@@ -287,7 +276,7 @@ impl<'a> Entry<'a> {
         let mut creating_start = if let Some(entry) = iter.peek() {
             entry.start
         } else {
-            return vec![];
+            return Ok(vec![]);
         };
 
         while let Some(e) = iter.next() {
@@ -302,7 +291,7 @@ impl<'a> Entry<'a> {
                 let max_inode = (next.inode as u64).abs_diff(lowest_inode as u64) > i16::MAX as u64;
                 // make sure entires have the correct start and amount of directories
                 if next.start != creating_start || creating_dir.len() >= 255 || max_inode {
-                    let dir = Self::create_dir(&creating_dir, creating_start, lowest_inode);
+                    let dir = Self::create_dir(&creating_dir, creating_start, lowest_inode)?;
                     dirs.push(dir);
                     creating_dir = vec![];
                     creating_start = next.start;
@@ -311,12 +300,12 @@ impl<'a> Entry<'a> {
             }
             // last entry
             if iter.peek().is_none() {
-                let dir = Self::create_dir(&creating_dir, creating_start, lowest_inode);
+                let dir = Self::create_dir(&creating_dir, creating_start, lowest_inode)?;
                 dirs.push(dir);
             }
         }
 
-        dirs
+        Ok(dirs)
     }
 }
 
@@ -353,7 +342,7 @@ mod tests {
             },
         ];
 
-        let dir = Entry::into_dir(entries);
+        let dir = Entry::into_dir(entries).unwrap();
         assert_eq!(
             vec![
                 Dir {
@@ -1,5 +1,8 @@
 //! Errors
 
+use std::fmt;
+use std::num::TryFromIntError;
+use std::path::PathBuf;
 use std::{io, string};
 
 use thiserror::Error;
@@ -22,6 +25,9 @@ pub enum BackhandError {
     #[error("string error: {0:?}")]
     StrUtf8(#[from] std::str::Utf8Error),
 
+    #[error("integer conversion error: {0}")]
+    TryFromInt(#[from] TryFromIntError),
+
     #[error("unsupported compression: {0:?}")]
     UnsupportedCompression(Compressor),
 
@@ -51,6 +57,94 @@ pub enum BackhandError {
 
     #[error("file duplicated in squashfs image")]
     DuplicatedFileName,
+
+    #[error("not a directory")]
+    NotADirectory,
+
+    #[error("not a symlink")]
+    NotASymlink,
+
+    #[error("not a regular file")]
+    NotAFile,
+
+    #[error("inode {inode} of {path:?} not found")]
+    InodeNotFound { path: PathBuf, inode: u32 },
+
+    #[error("directory {path:?} with inode {inode} was already read, directories form a cycle")]
+    DirectoryCycle { path: PathBuf, inode: u32 },
+
+    #[error("directory {path:?} has parent inode {found}, expected {expected}")]
+    InvalidParentInode { path: PathBuf, expected: u32, found: u32 },
+
+    #[error("{limit} is over the limit of {max:#x?}")]
+    LimitExceeded { limit: &'static str, max: u64 },
+
+    #[error("too many levels of symbolic links")]
+    TooManySymlinks,
+
+    #[error("id index {0} is not in the id table")]
+    InvalidIdIndex(u16),
+
+    #[error("invalid {field} {value:#x?}")]
+    InvalidField { field: &'static str, value: u64 },
+
+    #[error("invalid pattern: {0}")]
+    InvalidPattern(String),
+
+    #[error("{context}: {source}")]
+    Context { context: ErrorContext, source: Box<BackhandError> },
+}
+
+impl BackhandError {
+    /// Error without the [`BackhandError::Context`] wrapping it
+    pub fn root_cause(&self) -> &BackhandError {
+        match self {
+            BackhandError::Context { source, .. } => source.root_cause(),
+            e => e,
+        }
+    }
+
+    /// Wrap `self` in [`BackhandError::Context`]
+    pub(crate) fn context(self, context: ErrorContext) -> Self {
+        BackhandError::Context { context, source: Box::new(self) }
+    }
+}
+
+/// Structure of the image an error happened in, see [`BackhandError::Context`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorContext {
+    /// Table starting at byte `offset` of the image
+    Table { name: &'static str, offset: u64 },
+    /// Inode with inode number
+    Inode(u32),
+    /// Node at path
+    Path(PathBuf),
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorContext::Table { name, offset } => write!(f, "{name} at {offset:#x?}"),
+            ErrorContext::Inode(inode) => write!(f, "inode {inode}"),
+            ErrorContext::Path(path) => write!(f, "{path:?}"),
+        }
+    }
+}
+
+/// [`ErrorContext::Table`] of table `name` at `offset`
+pub(crate) fn table(name: &'static str, offset: u64) -> ErrorContext {
+    ErrorContext::Table { name, offset }
+}
+
+/// Add an [`ErrorContext`] to the error of a `Result`
+pub(crate) trait ResultExt<T> {
+    fn context<F: FnOnce() -> ErrorContext>(self, context: F) -> Result<T, BackhandError>;
+}
+
+impl<T, E: Into<BackhandError>> ResultExt<T> for Result<T, E> {
+    fn context<F: FnOnce() -> ErrorContext>(self, context: F) -> Result<T, BackhandError> {
+        self.map_err(|e| e.into().context(context()))
+    }
 }
 
 impl From<BackhandError> for io::Error {
@@ -61,8 +155,17 @@ impl From<BackhandError> for io::Error {
             Deku(e) => e.into(),
             StringUtf8(e) => Self::new(io::ErrorKind::InvalidData, e),
             StrUtf8(e) => Self::new(io::ErrorKind::InvalidData, e),
+            TryFromInt(e) => Self::new(io::ErrorKind::InvalidData, e),
+            Context { context, source } => {
+                let source = io::Error::from(*source);
+                Self::new(source.kind(), format!("{context}: {source}"))
+            }
             e @ UnsupportedCompression(_) => Self::new(io::ErrorKind::Unsupported, e),
             e @ FileNotFound => Self::new(io::ErrorKind::NotFound, e),
+            e @ (NotADirectory | NotASymlink | NotAFile | InvalidPattern(_)) => {
+                Self::new(io::ErrorKind::InvalidInput, e)
+            }
+            e @ TooManySymlinks => Self::new(io::ErrorKind::Other, e),
             e @ (Unreachable
             | UnexpectedInode(_)
             | UnsupportedInode(_)
@@ -70,7 +173,13 @@ impl From<BackhandError> for io::Error {
             | InvalidCompressionOption
             | InvalidFilePath
             | UndefineFileName
-            | DuplicatedFileName) => Self::new(io::ErrorKind::InvalidData, e),
+            | DuplicatedFileName
+            | InodeNotFound { .. }
+            | DirectoryCycle { .. }
+            | InvalidParentInode { .. }
+            | LimitExceeded { .. }
+            | InvalidIdIndex(_)
+            | InvalidField { .. }) => Self::new(io::ErrorKind::InvalidData, e),
         }
     }
 }
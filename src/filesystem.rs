@@ -1,6 +1,13 @@
 //! In-memory representation of SquashFS filesystem tree used for writing to image
+#[cfg(feature = "tokio")]
+pub mod async_reader;
+pub mod extract;
+pub mod lazy;
 pub mod node;
+pub mod owned;
+pub mod pattern;
 pub mod reader;
+pub mod seek;
 pub mod writer;
 
 use std::path::{Component, Path, PathBuf};
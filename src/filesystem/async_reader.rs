@@ -0,0 +1,293 @@
+//! Async reading of images with tokio, feature `tokio`
+
+use std::future::Future;
+use std::io::{self, Cursor, SeekFrom};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
+use tokio::sync::Mutex;
+
+use super::node::InnerNode;
+use super::owned::OwnedFilesystemReader;
+use super::reader::FilesystemReader;
+use super::seek::{decode_block, seek_position, BlockLocation, FileBlocks};
+use crate::error::BackhandError;
+use crate::inode::BasicFile;
+use crate::kinds::{Kind, LE_V4_0};
+use crate::reader::{BufReadSeek, ReadAt, ReaderBackend};
+use crate::squashfs::Squashfs;
+
+/// Max bytes of the superblock and compression options metadata block
+const HEAD_LEN: u64 = 96 + 2 + 0x2000;
+
+/// Combined [`AsyncRead`] and [`AsyncSeek`] source of an [`AsyncFilesystemReader`]
+pub trait AsyncReadSeek: AsyncRead + AsyncSeek + Send + Unpin {}
+impl<T: AsyncRead + AsyncSeek + Send + Unpin> AsyncReadSeek for T {}
+
+/// Image with only the superblock and the tables after the data blocks in memory, the data
+/// blocks in between read as zeros
+///
+/// Only used to parse the filesystem tree on a blocking thread
+struct TablesImage {
+    head: Vec<u8>,
+    tables_start: u64,
+    tables: Vec<u8>,
+}
+
+impl ReadAt for TablesImage {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        buf.fill(0);
+        for (start, bytes) in [(0, &self.head), (self.tables_start, &self.tables)] {
+            let end = start + bytes.len() as u64;
+            let from = offset.max(start);
+            let to = (offset + buf.len() as u64).min(end);
+            if from < to {
+                buf[(from - offset) as usize..(to - offset) as usize]
+                    .copy_from_slice(&bytes[(from - start) as usize..(to - start) as usize]);
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.tables_start + self.tables.len() as u64)
+    }
+}
+
+/// Reader of the [`FilesystemReader`] of an [`AsyncFilesystemReader`], file data is only read
+/// by [`AsyncFile`]
+struct AsyncOnly;
+
+impl ReadAt for AsyncOnly {
+    fn read_exact_at(&self, _buf: &mut [u8], _offset: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "file data of an AsyncFilesystemReader must be read with AsyncFile",
+        ))
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(0)
+    }
+}
+
+/// Async version of [`FilesystemReader`], reading from a tokio [`AsyncRead`] + [`AsyncSeek`]
+///
+/// Construction reads the superblock and the inode, directory, fragment, export and id tables
+/// into memory, then parses them on a blocking thread. File data is read asynchronously from the
+/// source by [`AsyncFile`], and decompressed on blocking threads instead of the runtime threads.
+///
+/// [`Clone`] only increments a reference count, all clones share the source and the block cache.
+///
+/// ```rust,no_run
+/// # async fn run() {
+/// # use tokio::io::AsyncReadExt;
+/// # use backhand::AsyncFilesystemReader;
+/// let image = tokio::fs::File::open("image.squashfs").await.unwrap();
+/// let filesystem = AsyncFilesystemReader::from_reader(image).await.unwrap();
+///
+/// let mut passwd = String::new();
+/// let mut file = filesystem.open("/etc/passwd").unwrap();
+/// file.read_to_string(&mut passwd).await.unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct AsyncFilesystemReader {
+    system: OwnedFilesystemReader,
+    source: Arc<Mutex<Box<dyn AsyncReadSeek>>>,
+    /// Offset of the image in `source`
+    offset: u64,
+}
+
+impl AsyncFilesystemReader {
+    /// Create from `reader`, with default kind: [`crate::kind::LE_V4_0`] and offset `0`
+    pub async fn from_reader<R: AsyncReadSeek + 'static>(reader: R) -> Result<Self, BackhandError> {
+        Self::from_reader_with_offset(reader, 0).await
+    }
+
+    /// Same as [`Self::from_reader`], but with the image starting at `offset` in `reader`
+    pub async fn from_reader_with_offset<R: AsyncReadSeek + 'static>(
+        reader: R,
+        offset: u64,
+    ) -> Result<Self, BackhandError> {
+        let kind = Kind { inner: Arc::new(LE_V4_0) };
+        Self::from_reader_with_offset_and_kind(reader, offset, kind).await
+    }
+
+    /// Same as [`Self::from_reader_with_offset`], but setting custom `kind`
+    pub async fn from_reader_with_offset_and_kind<R: AsyncReadSeek + 'static>(
+        mut reader: R,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        let total_length = reader.seek(SeekFrom::End(0)).await?;
+        let image_length =
+            total_length.checked_sub(offset).ok_or(BackhandError::CorruptedOrInvalidSquashfs)?;
+
+        let mut head = vec![0; HEAD_LEN.min(image_length) as usize];
+        reader.seek(SeekFrom::Start(offset)).await?;
+        reader.read_exact(&mut head).await?;
+
+        let mut head_reader: Box<dyn BufReadSeek + '_> = Box::new(Cursor::new(head.as_slice()));
+        let (superblock, _) =
+            Squashfs::superblock_and_compression_options(&mut head_reader, &kind)?;
+        drop(head_reader);
+
+        // everything after the data blocks, the tables needed for the filesystem tree
+        let tables_start = superblock.inode_table;
+        if tables_start > superblock.bytes_used || superblock.bytes_used > image_length {
+            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        }
+        let mut tables = vec![0; (superblock.bytes_used - tables_start) as usize];
+        reader.seek(SeekFrom::Start(offset + tables_start)).await?;
+        reader.read_exact(&mut tables).await?;
+
+        let image = TablesImage { head, tables_start, tables };
+        let mut system = tokio::task::spawn_blocking(move || {
+            FilesystemReader::from_read_at_with_offset_and_kind(image, 0, kind)
+        })
+        .await
+        .map_err(io::Error::from)??;
+        // drop the tables, and make sure file data isn't read as the zeros of the TablesImage
+        system.reader = ReaderBackend::Positional { reader: Arc::new(AsyncOnly), offset: 0 };
+
+        Ok(Self { system: system.into(), source: Arc::new(Mutex::new(Box::new(reader))), offset })
+    }
+
+    /// Filesystem tree and path lookup of the image
+    ///
+    /// File data can't be read through the returned [`FilesystemReader`], use [`Self::open`]
+    /// or [`Self::file`].
+    pub fn filesystem(&self) -> &FilesystemReader<'static> {
+        &self.system
+    }
+
+    /// Open the file at `path`, following symlinks
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<AsyncFile, BackhandError> {
+        match &self.system.metadata(path)?.inner {
+            InnerNode::File(file) => Ok(self.file(&file.basic)),
+            _ => Err(BackhandError::NotAFile),
+        }
+    }
+
+    /// Return an async file handler for this file, holding a copy of `basic_file`
+    pub fn file(&self, basic_file: &BasicFile) -> AsyncFile {
+        AsyncFile {
+            system: self.clone(),
+            blocks: FileBlocks::new(basic_file),
+            basic: Arc::new(basic_file.clone()),
+            pos: 0,
+            pending: None,
+        }
+    }
+
+    /// Decompressed block at `location`, from the cache or read from the source
+    async fn fetch_block(self, location: BlockLocation) -> Result<Arc<Vec<u8>>, BackhandError> {
+        let key = match location.key {
+            Some(key) => key,
+            None => return Ok(Arc::new(vec![0; location.range.end])),
+        };
+        let cached = self.system.cache.lock().unwrap().get(key);
+        if let Some(bytes) = cached {
+            return Ok(bytes);
+        }
+
+        let mut raw = vec![0; location.size.size() as usize];
+        {
+            let mut source = self.source.lock().await;
+            source.seek(SeekFrom::Start(self.offset + location.start)).await?;
+            source.read_exact(&mut raw).await?;
+        }
+
+        if location.size.uncompressed() {
+            decode_block(&self.system, &location, raw)
+        } else {
+            let system = self.system.clone();
+            tokio::task::spawn_blocking(move || decode_block(&system, &location, raw))
+                .await
+                .map_err(io::Error::from)?
+        }
+    }
+}
+
+type PendingBlock = Pin<Box<dyn Future<Output = Result<Arc<Vec<u8>>, BackhandError>> + Send>>;
+
+/// File of an [`AsyncFilesystemReader`], impls [`AsyncRead`] and [`AsyncSeek`]
+///
+/// Same as [`crate::OwnedFile`], but reading the blocks asynchronously and decompressing them on
+/// blocking threads.
+pub struct AsyncFile {
+    system: AsyncFilesystemReader,
+    basic: Arc<BasicFile>,
+    blocks: FileBlocks,
+    pos: u64,
+    /// Block being read and decompressed
+    pending: Option<(BlockLocation, PendingBlock)>,
+}
+
+impl AsyncFile {
+    /// Inode data of the file
+    pub fn basic(&self) -> &BasicFile {
+        &self.basic
+    }
+
+    /// Size of the file in bytes
+    pub fn len(&self) -> u64 {
+        self.basic.file_size.into()
+    }
+
+    /// Returns `true` if the file has no data
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl AsyncRead for AsyncFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if let Some((location, pending)) = &mut this.pending {
+                let bytes = ready!(pending.as_mut().poll(cx));
+                let location = location.clone();
+                this.pending = None;
+                this.blocks.set_current(&location, &bytes?)?;
+            }
+
+            let system = &this.system.system;
+            let location = match this.blocks.locate(system, &this.basic, this.pos)? {
+                Some(location) => location,
+                None => return Poll::Ready(Ok(())),
+            };
+            if this.blocks.is_current(location.index) {
+                let n = this.blocks.copy_current(system, this.pos, buf.initialize_unfilled());
+                buf.advance(n);
+                this.pos += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+            let pending = Box::pin(this.system.clone().fetch_block(location.clone()));
+            this.pending = Some((location, pending));
+        }
+    }
+}
+
+impl AsyncSeek for AsyncFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        this.pos = seek_position(position, this.pos, this.len())?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
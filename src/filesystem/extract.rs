@@ -0,0 +1,1065 @@
+//! Extraction of the nodes of a [`FilesystemReader`] into an [`ExtractSink`]
+
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::prelude::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use nix::errno::Errno;
+use nix::fcntl::{openat, AtFlags, OFlag};
+use nix::sys::stat::{
+    dev_t, fchmod, fchmodat, fstatat, futimens, mkdirat, mknodat, mode_t, utimensat, FchmodatFlags,
+    Mode, SFlag, UtimensatFlags,
+};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{symlinkat, unlinkat, UnlinkatFlags};
+use rayon::prelude::*;
+use rustc_hash::FxHashSet;
+use tracing::info;
+
+use super::node::{InnerNode, Node, NodeHeader, SquashfsFileReader, SquashfsSymlink};
+use super::pattern::Pattern;
+use super::reader::FilesystemReader;
+use super::seek::SquashfsSeekFile;
+use crate::error::BackhandError;
+use crate::reader::ReadAt;
+use crate::scan;
+
+/// Files with at least this amount of data blocks are decompressed with read-ahead
+const READ_AHEAD_MIN_BLOCKS: usize = 16;
+
+/// Size of the headers and data blocks of a tar archive
+const TAR_BLOCK: usize = 512;
+
+/// Capacity reserved up front when reading a file into a [`MemorySink`], the file size of the
+/// inode may be corrupted
+const MEMORY_MAX_CAPACITY: u64 = 0x100_0000;
+
+/// Destination of the nodes extracted by [`FilesystemReader::extract_with_sink`]
+///
+/// All paths are relative to the root of the extraction, the root directory of the image being
+/// the empty path. Directories are created in order before all other nodes, so the parent of a
+/// node exists unless it was excluded by [`ExtractOptions::filter`]. The other nodes can be
+/// created from multiple threads at once.
+///
+/// Except for directories, `create_*` is only called if [`Self::exists`] returned `false` or
+/// [`ExtractOptions::force`] is set, in which case an existing node must be replaced.
+pub trait ExtractSink: Sync {
+    /// Create directory at `path`, an existing directory isn't an error
+    fn create_dir(&self, path: &Path, header: &NodeHeader) -> io::Result<()>;
+
+    /// Create file at `path` with the `size` bytes of `reader`, returning the amount of bytes
+    /// written
+    fn create_file(
+        &self,
+        path: &Path,
+        header: &NodeHeader,
+        size: u64,
+        reader: &mut dyn Read,
+    ) -> io::Result<u64>;
+
+    /// Create symlink at `path` pointing to `target`
+    fn create_symlink(&self, path: &Path, target: &Path, header: &NodeHeader) -> io::Result<()>;
+
+    /// Create character device at `path`
+    fn create_char_device(
+        &self,
+        path: &Path,
+        device_number: u32,
+        header: &NodeHeader,
+    ) -> io::Result<()>;
+
+    /// Create block device at `path`
+    fn create_block_device(
+        &self,
+        path: &Path,
+        device_number: u32,
+        header: &NodeHeader,
+    ) -> io::Result<()>;
+
+    /// Called for every directory once all nodes are extracted, deepest directories first.
+    /// Used to set attributes that would prevent creating the children of the directory.
+    fn finish_dir(&self, _path: &Path, _header: &NodeHeader) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns `true` if a node already exists at `path`
+    fn exists(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// Returns `false` if [`Self::create_file`] holds a lock while reading, so big files must not
+    /// be decompressed with read-ahead on the rayon pool, where the reading thread can pick up
+    /// the extraction of another file blocking on that lock
+    fn read_ahead(&self) -> bool {
+        true
+    }
+}
+
+/// Progress of [`FilesystemReader::extract_with_sink`], given to [`ExtractOptions::on_event`]
+///
+/// Every node gets [`Self::Start`], followed by one of [`Self::Done`], [`Self::Exists`] or
+/// [`Self::Failed`]. Directories can also get [`Self::Failed`] after all nodes are extracted,
+/// if [`ExtractSink::finish_dir`] fails.
+#[derive(Debug)]
+pub enum ExtractEvent<'a> {
+    /// Started extracting the node at the relative `path`
+    Start { node: &'a Node<SquashfsFileReader>, path: &'a Path },
+    /// Node was extracted
+    Done { node: &'a Node<SquashfsFileReader>, path: &'a Path },
+    /// Node was skipped, it already exists and [`ExtractOptions::force`] isn't set
+    Exists { node: &'a Node<SquashfsFileReader>, path: &'a Path },
+    /// Node couldn't be extracted
+    Failed { node: &'a Node<SquashfsFileReader>, path: &'a Path, error: &'a BackhandError },
+}
+
+type Filter = Box<dyn Fn(&Node<SquashfsFileReader>) -> bool + Send + Sync>;
+type OnEvent = Box<dyn Fn(ExtractEvent<'_>) + Send + Sync>;
+
+/// Options of [`FilesystemReader::extract`]
+///
+/// ```rust,no_run
+/// # use std::fs::File;
+/// # use backhand::{ExtractEvent, ExtractOptions, FilesystemReader, Pattern};
+/// # let filesystem = FilesystemReader::from_read_at(File::open("image.squashfs").unwrap()).unwrap();
+/// let options = ExtractOptions::new()
+///     .path_filter("/etc")
+///     .path_filter("/www")
+///     .exclude(Pattern::glob("*.bak").unwrap())
+///     .force(true)
+///     .on_event(|event| {
+///         if let ExtractEvent::Failed { path, error, .. } = event {
+///             eprintln!("{}: {error}", path.display());
+///         }
+///     });
+/// let report = filesystem.extract("squashfs-root", &options).unwrap();
+/// ```
+pub struct ExtractOptions {
+    parallel: bool,
+    force: bool,
+    recursive: usize,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    filter: Option<Filter>,
+    on_event: Option<OnEvent>,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            parallel: true,
+            force: false,
+            recursive: 0,
+            include: vec![],
+            exclude: vec![],
+            filter: None,
+            on_event: None,
+        }
+    }
+}
+
+impl ExtractOptions {
+    /// Parallel extraction of all nodes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Extract files with multiple threads from the current rayon thread pool
+    ///
+    /// Default: `true`
+    pub fn parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+    /// Replace existing nodes
+    ///
+    /// Default: `false`, existing nodes are skipped
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Extract the images found in extracted files, up to `depth` levels of nested images
+    ///
+    /// After a file is extracted, its data is checked for an image of any kind at offset 0,
+    /// validated like [`crate::Squashfs::scan`]. An image is extracted next to the file into the
+    /// directory `<name>.extracted`, reading it from the data of the file in this image.
+    /// [`Self::filter`] and [`Self::on_event`] also apply to the nodes of nested images, with
+    /// paths relative to the root of the extraction. [`Self::include`] and [`Self::exclude`] only
+    /// apply to this image.
+    ///
+    /// Default: `0`, files aren't checked for images
+    pub fn recursive(mut self, depth: usize) -> Self {
+        self.recursive = depth;
+        self
+    }
+
+    /// Only extract `path` and the nodes under it, along with the directories leading to it.
+    ///
+    /// For example, "/www/webpages/data" will extract all files under that dir, such as
+    /// "/www/webpages/data/region.json". When given an exact file, only that file will be
+    /// extracted. Extracting fails with [`BackhandError::FileNotFound`] if `path` doesn't exist.
+    ///
+    /// Same as [`Self::include`] with [`Pattern::path`], can be given multiple times.
+    pub fn path_filter<P: AsRef<Path>>(self, path: P) -> Self {
+        self.include(Pattern::path(path))
+    }
+
+    /// Only extract the nodes matching one of the included patterns, or under a matching
+    /// directory. The directories leading to them are also extracted, with their permissions.
+    ///
+    /// Default: all nodes are included
+    pub fn include(mut self, pattern: Pattern) -> Self {
+        self.include.push(pattern);
+        self
+    }
+
+    /// Don't extract the nodes matching `pattern`, or under a matching directory
+    pub fn exclude(mut self, pattern: Pattern) -> Self {
+        self.exclude.push(pattern);
+        self
+    }
+
+    /// Only extract nodes for which `filter` returns `true`, applied after [`Self::include`] and
+    /// [`Self::exclude`]
+    pub fn filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Node<SquashfsFileReader>) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Call `on_event` for the progress of every node, from the threads extracting them
+    pub fn on_event<F>(mut self, on_event: F) -> Self
+    where
+        F: Fn(ExtractEvent<'_>) + Send + Sync + 'static,
+    {
+        self.on_event = Some(Box::new(on_event));
+        self
+    }
+
+    fn event(&self, event: ExtractEvent<'_>) {
+        if let Some(on_event) = &self.on_event {
+            on_event(event);
+        }
+    }
+}
+
+/// Result of [`FilesystemReader::extract`]
+#[derive(Debug, Default)]
+pub struct ExtractReport {
+    /// Amount of nodes extracted
+    pub extracted: usize,
+    /// Amount of nodes skipped, as they already existed
+    pub skipped: usize,
+    /// Nodes that couldn't be extracted, by path in the image. The nodes of nested images, see
+    /// [`ExtractOptions::recursive`], are under the `<name>.extracted` directory of their image.
+    pub errors: Vec<(PathBuf, BackhandError)>,
+}
+
+impl ExtractReport {
+    /// Returns `true` if no node failed to be extracted
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+enum Outcome {
+    Done,
+    Exists,
+}
+
+impl<'b> FilesystemReader<'b> {
+    /// Extract all nodes to the directory `dest` of the host filesystem, see [`HostSink`]
+    ///
+    /// Errors of single nodes don't stop the extraction, and are returned in the
+    /// [`ExtractReport`].
+    pub fn extract<P: AsRef<Path>>(
+        &self,
+        dest: P,
+        options: &ExtractOptions,
+    ) -> Result<ExtractReport, BackhandError> {
+        self.extract_with_sink(&HostSink::new(dest), options)
+    }
+
+    /// Extract all nodes into `sink`
+    pub fn extract_with_sink<S: ExtractSink>(
+        &self,
+        sink: &S,
+        options: &ExtractOptions,
+    ) -> Result<ExtractReport, BackhandError> {
+        self.extract_into(sink, options, true, Path::new(""), options.recursive)
+    }
+
+    /// Nodes extracted by [`Self::extract`] with `options`, in order, not including the nodes of
+    /// nested images
+    pub fn selected_nodes(
+        &self,
+        options: &ExtractOptions,
+    ) -> Result<Vec<&Node<SquashfsFileReader>>, BackhandError> {
+        self.extract_nodes(options, true)
+    }
+
+    /// Extract the selected nodes into `sink`, at the relative path `prefix`, and the images in
+    /// files up to `depth` levels of nesting. Included and excluded patterns are only used if
+    /// `patterns`.
+    fn extract_into<S: ExtractSink>(
+        &self,
+        sink: &S,
+        options: &ExtractOptions,
+        patterns: bool,
+        prefix: &Path,
+        depth: usize,
+    ) -> Result<ExtractReport, BackhandError> {
+        let nodes = self.extract_nodes(options, patterns)?;
+        let report = Mutex::new(ExtractReport::default());
+
+        let extract = |node: &Node<SquashfsFileReader>| {
+            let path = &prefix.join(relative_path(&node.fullpath));
+            options.event(ExtractEvent::Start { node, path });
+            let outcome = self.extract_node(sink, options, node, path);
+            let nested = match (&outcome, &node.inner) {
+                (Ok(Outcome::Done), InnerNode::File(file)) if depth > 0 => {
+                    self.extract_image(sink, options, file, path, depth - 1)
+                }
+                _ => None,
+            };
+            let mut report = report.lock().unwrap();
+            match outcome {
+                Ok(Outcome::Done) => {
+                    report.extracted += 1;
+                    options.event(ExtractEvent::Done { node, path });
+                }
+                Ok(Outcome::Exists) => {
+                    report.skipped += 1;
+                    options.event(ExtractEvent::Exists { node, path });
+                }
+                Err(error) => {
+                    options.event(ExtractEvent::Failed { node, path, error: &error });
+                    report.errors.push((Path::new("/").join(path), error));
+                }
+            }
+            match nested {
+                Some((_, Ok(nested))) => {
+                    report.extracted += nested.extracted;
+                    report.skipped += nested.skipped;
+                    report.errors.extend(nested.errors);
+                }
+                Some((dest, Err(error))) => {
+                    options.event(ExtractEvent::Failed { node, path: &dest, error: &error });
+                    report.errors.push((Path::new("/").join(dest), error));
+                }
+                None => (),
+            }
+        };
+
+        // directories first, so the parents of all other nodes exist
+        let (dirs, others): (Vec<_>, Vec<_>) =
+            nodes.into_iter().partition(|node| matches!(node.inner, InnerNode::Dir(_)));
+        dirs.iter().for_each(|node| extract(*node));
+        if options.parallel {
+            others.par_iter().for_each(|node| extract(*node));
+        } else {
+            others.iter().for_each(|node| extract(*node));
+        }
+
+        // fixup dir attributes, children first
+        for node in dirs.iter().rev() {
+            let path = &prefix.join(relative_path(&node.fullpath));
+            if let Err(e) = sink.finish_dir(path, &node.header) {
+                let error = BackhandError::from(e);
+                options.event(ExtractEvent::Failed { node, path, error: &error });
+                report.lock().unwrap().errors.push((Path::new("/").join(path), error));
+            }
+        }
+
+        Ok(report.into_inner().unwrap())
+    }
+
+    /// If the data of `file`, extracted at `path`, is an image, extract it into
+    /// `<path>.extracted`. Returns that path with the result, `None` if `file` isn't an image.
+    fn extract_image<S: ExtractSink>(
+        &self,
+        sink: &S,
+        options: &ExtractOptions,
+        file: &SquashfsFileReader,
+        path: &Path,
+        depth: usize,
+    ) -> Option<(PathBuf, Result<ExtractReport, BackhandError>)> {
+        let image: Arc<dyn ReadAt + '_> =
+            Arc::new(NestedImage(Mutex::new(SquashfsSeekFile::new(self, &file.basic))));
+        let found = scan::detect(&image)?;
+
+        let mut dest = OsString::from(path);
+        dest.push(".extracted");
+        let dest = PathBuf::from(dest);
+        info!("extracting {} image {} into {}", found.name, path.display(), dest.display());
+        let result = FilesystemReader::from_read_at_with_limits(image, 0, found.kind, self.limits)
+            .and_then(|nested| nested.extract_into(sink, options, false, &dest, depth));
+        Some((dest, result))
+    }
+
+    /// Nodes selected by the patterns of `options` if `patterns`, with their parent directories,
+    /// then by [`ExtractOptions::filter`], in order
+    fn extract_nodes(
+        &self,
+        options: &ExtractOptions,
+        patterns: bool,
+    ) -> Result<Vec<&Node<SquashfsFileReader>>, BackhandError> {
+        let (include, exclude) = if patterns {
+            (options.include.as_slice(), options.exclude.as_slice())
+        } else {
+            (&[][..], &[][..])
+        };
+        for path in include.iter().filter_map(Pattern::as_path) {
+            self.root.find(path).ok_or(BackhandError::FileNotFound)?;
+        }
+
+        let candidates: Vec<(&Node<SquashfsFileReader>, bool)> = self
+            .files()
+            .map(|node| {
+                let path = node.fullpath.as_path();
+                let included =
+                    include.is_empty() || include.iter().any(|p| p.matches_ancestor(path));
+                (node, included && !exclude.iter().any(|p| p.matches_ancestor(path)))
+            })
+            .collect();
+
+        // the exact parent dirs must be extracted above the selected nodes
+        let mut parents = FxHashSet::default();
+        for (node, _) in candidates.iter().filter(|(_, selected)| *selected) {
+            parents.extend(node.fullpath.ancestors().skip(1));
+        }
+
+        let mut nodes: Vec<_> = candidates
+            .iter()
+            .filter(|(node, selected)| *selected || parents.contains(node.fullpath.as_path()))
+            .map(|(node, _)| *node)
+            .collect();
+        if let Some(filter) = &options.filter {
+            nodes.retain(|node| filter(node));
+        }
+        Ok(nodes)
+    }
+
+    fn extract_node<S: ExtractSink>(
+        &self,
+        sink: &S,
+        options: &ExtractOptions,
+        node: &Node<SquashfsFileReader>,
+        path: &Path,
+    ) -> Result<Outcome, BackhandError> {
+        if let InnerNode::Dir(_) = node.inner {
+            sink.create_dir(path, &node.header)?;
+            return Ok(Outcome::Done);
+        }
+
+        if !options.force && sink.exists(path) {
+            return Ok(Outcome::Exists);
+        }
+
+        match &node.inner {
+            InnerNode::File(file) => {
+                let (mut buf_read, mut buf_decompress) = self.alloc_read_buffers();
+                let file_reader = self.file(&file.basic).with_path(&node.fullpath);
+                let mut reader = file_reader.reader(&mut buf_read, &mut buf_decompress);
+                // decompress big files using all threads, instead of one block at a time
+                if options.parallel
+                    && sink.read_ahead()
+                    && file.basic.block_sizes.len() >= READ_AHEAD_MIN_BLOCKS
+                {
+                    reader = reader.read_ahead(rayon::current_num_threads());
+                }
+                let size = u64::from(file.basic.file_size);
+                sink.create_file(path, &node.header, size, &mut reader)?;
+            }
+            InnerNode::Symlink(SquashfsSymlink { link }) => {
+                sink.create_symlink(path, link, &node.header)?
+            }
+            InnerNode::CharacterDevice(device) => {
+                sink.create_char_device(path, device.device_number, &node.header)?
+            }
+            InnerNode::BlockDevice(device) => {
+                sink.create_block_device(path, device.device_number, &node.header)?
+            }
+            InnerNode::Dir(_) => unreachable!(),
+        }
+        Ok(Outcome::Done)
+    }
+}
+
+/// `fullpath` of a node relative to the root of the image
+fn relative_path(fullpath: &Path) -> &Path {
+    fullpath.strip_prefix(Component::RootDir).unwrap_or(fullpath)
+}
+
+/// Data of a file of an image, read as a nested image by [`ExtractOptions::recursive`]
+struct NestedImage<'a, 'b>(Mutex<SquashfsSeekFile<'a, 'b>>);
+
+impl<'a, 'b> ReadAt for NestedImage<'a, 'b> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.0.lock().unwrap().read_exact_at(offset, buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.lock().unwrap().len())
+    }
+}
+
+/// [`ExtractSink`] creating the nodes under a directory of the host filesystem
+///
+/// All nodes are created relative to a file descriptor of their parent directory, opened from
+/// `dest` one component at a time without following symlinks (`openat` with `O_NOFOLLOW`).
+/// Symlinks, from the image or already existing in `dest`, can never redirect writes outside of
+/// `dest`, and paths with components other than normal names are rejected.
+///
+/// Like squashfs-tools/unsquashfs, owners are only set when running as root. Otherwise the
+/// write permission of group and others is removed from files.
+#[derive(Debug, Clone)]
+pub struct HostSink {
+    dest: PathBuf,
+    root_process: bool,
+}
+
+impl HostSink {
+    /// Extract into `dest`, created if it doesn't exist
+    pub fn new<P: AsRef<Path>>(dest: P) -> Self {
+        let root_process = unsafe { libc::geteuid() == 0 };
+        Self { dest: dest.as_ref().to_path_buf(), root_process }
+    }
+
+    /// Open the parent directory of `path`, returning it with the name of `path` in it.
+    ///
+    /// The empty path, the root of the image, is `.` in `dest`.
+    fn open_parent<'p>(&self, path: &'p Path) -> io::Result<(File, &'p OsStr)> {
+        let mut dir = File::open(&self.dest)?;
+        let mut components = path.components().peekable();
+        let mut name = OsStr::new(".");
+        while let Some(component) = components.next() {
+            let component = match component {
+                Component::Normal(component) => component,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid path component in {}", path.display()),
+                    ))
+                }
+            };
+            if components.peek().is_none() {
+                name = component;
+            } else {
+                // parents not extracted because of ExtractOptions::filter
+                match mkdirat(dir.as_raw_fd(), component, Mode::from_bits_truncate(0o755)) {
+                    Ok(()) | Err(Errno::EEXIST) => (),
+                    Err(e) => return Err(e.into()),
+                }
+                dir = open_dir_at(&dir, component)?;
+            }
+        }
+        Ok((dir, name))
+    }
+
+    /// Remove the non-directory node `name` in `dir`, if any
+    fn replace(dir: &File, name: &OsStr) -> io::Result<()> {
+        match fstatat(dir.as_raw_fd(), name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+            Ok(stat) if stat.st_mode & libc::S_IFMT != libc::S_IFDIR => {
+                unlinkat(Some(dir.as_raw_fd()), name, UnlinkatFlags::NoRemoveDir)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Set the attributes of the opened file `fd`
+    fn set_attributes(&self, fd: &File, header: &NodeHeader) -> io::Result<()> {
+        let timespec = TimeSpec::new(header.mtime as _, 0);
+        futimens(fd.as_raw_fd(), &timespec, &timespec)?;
+
+        let mut mode = mode_t::from(header.permissions);
+
+        // Only chown when root
+        if self.root_process {
+            if unsafe { libc::fchown(fd.as_raw_fd(), header.uid, header.gid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        } else {
+            // bitwise-not if not rooted (disable write permissions for user/group). Following
+            // squashfs-tools/unsquashfs behavior
+            mode &= !0o022;
+        }
+
+        // set permissions
+        //
+        // NOTE: In squashfs-tools/unsquashfs they remove the write bits for user and group?
+        // I don't know if there is a reason for that but I keep the permissions the same if possible
+        match fchmod(fd.as_raw_fd(), Mode::from_bits_truncate(mode)) {
+            // try without sticky bit
+            Err(Errno::EPERM | Errno::EACCES) => {
+                fchmod(fd.as_raw_fd(), Mode::from_bits_truncate(mode & !0o1000))?
+            }
+            result => result?,
+        }
+        Ok(())
+    }
+
+    /// Set the attributes of the node `name` in `dir`, without following it if it's a symlink
+    fn set_attributes_at(
+        &self,
+        dir: &File,
+        name: &OsStr,
+        header: &NodeHeader,
+        mode: bool,
+    ) -> io::Result<()> {
+        if self.root_process {
+            let name = CString::new(name.as_bytes())?;
+            let ret = unsafe {
+                libc::fchownat(
+                    dir.as_raw_fd(),
+                    name.as_ptr(),
+                    header.uid,
+                    header.gid,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if mode {
+            let mode = mode_t::from(header.permissions);
+            let chmod = |mode| {
+                let mode = Mode::from_bits_truncate(mode);
+                fchmodat(Some(dir.as_raw_fd()), name, mode, FchmodatFlags::FollowSymlink)
+            };
+            match chmod(mode) {
+                // try without sticky bit
+                Err(Errno::EPERM | Errno::EACCES) => chmod(mode & !0o1000)?,
+                result => result?,
+            }
+        }
+        let timespec = TimeSpec::new(header.mtime as _, 0);
+        utimensat(
+            Some(dir.as_raw_fd()),
+            name,
+            &timespec,
+            &timespec,
+            UtimensatFlags::NoFollowSymlink,
+        )?;
+        Ok(())
+    }
+
+    fn mknod(
+        &self,
+        path: &Path,
+        kind: SFlag,
+        device_number: u32,
+        header: &NodeHeader,
+    ) -> io::Result<()> {
+        let (dir, name) = self.open_parent(path)?;
+        Self::replace(&dir, name)?;
+        let mode = Mode::from_bits_truncate(mode_t::from(header.permissions));
+        mknodat(dir.as_raw_fd(), name, kind, mode, dev_t::from(device_number))?;
+        self.set_attributes_at(&dir, name, header, true)
+    }
+}
+
+impl ExtractSink for HostSink {
+    fn create_dir(&self, path: &Path, _header: &NodeHeader) -> io::Result<()> {
+        if path.as_os_str().is_empty() {
+            return fs::create_dir_all(&self.dest);
+        }
+        // These permissions are corrected in finish_dir (user default permissions for now)
+        let (dir, name) = self.open_parent(path)?;
+        match mkdirat(dir.as_raw_fd(), name, Mode::from_bits_truncate(0o755)) {
+            Ok(()) | Err(Errno::EEXIST) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn create_file(
+        &self,
+        path: &Path,
+        header: &NodeHeader,
+        _size: u64,
+        reader: &mut dyn Read,
+    ) -> io::Result<u64> {
+        let (dir, name) = self.open_parent(path)?;
+        Self::replace(&dir, name)?;
+        let flags = OFlag::O_WRONLY
+            | OFlag::O_CREAT
+            | OFlag::O_TRUNC
+            | OFlag::O_NOFOLLOW
+            | OFlag::O_CLOEXEC;
+        let fd = openat(dir.as_raw_fd(), name, flags, Mode::from_bits_truncate(0o600))?;
+        let mut fd = unsafe { File::from_raw_fd(fd) };
+        let written = io::copy(reader, &mut fd)?;
+        self.set_attributes(&fd, header)?;
+        Ok(written)
+    }
+
+    fn create_symlink(&self, path: &Path, target: &Path, header: &NodeHeader) -> io::Result<()> {
+        let (dir, name) = self.open_parent(path)?;
+        Self::replace(&dir, name)?;
+        symlinkat(target, Some(dir.as_raw_fd()), name)?;
+
+        // set attributes, but special to not follow the symlink
+        self.set_attributes_at(&dir, name, header, false)
+    }
+
+    fn create_char_device(
+        &self,
+        path: &Path,
+        device_number: u32,
+        header: &NodeHeader,
+    ) -> io::Result<()> {
+        self.mknod(path, SFlag::S_IFCHR, device_number, header)
+    }
+
+    fn create_block_device(
+        &self,
+        path: &Path,
+        device_number: u32,
+        header: &NodeHeader,
+    ) -> io::Result<()> {
+        self.mknod(path, SFlag::S_IFBLK, device_number, header)
+    }
+
+    fn finish_dir(&self, path: &Path, header: &NodeHeader) -> io::Result<()> {
+        // through the parent, as the directory can't be opened without read permission
+        let (dir, name) = self.open_parent(path)?;
+        let stat = fstatat(dir.as_raw_fd(), name, AtFlags::AT_SYMLINK_NOFOLLOW)?;
+        if stat.st_mode & SFlag::S_IFMT.bits() != SFlag::S_IFDIR.bits() {
+            return Err(Errno::ENOTDIR.into());
+        }
+        self.set_attributes_at(&dir, name, header, true)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        match self.open_parent(path) {
+            Ok((dir, name)) => fstatat(dir.as_raw_fd(), name, AtFlags::AT_SYMLINK_NOFOLLOW).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Open the directory `name` in `dir`, failing if it's a symlink
+fn open_dir_at(dir: &File, name: &OsStr) -> io::Result<File> {
+    let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let fd = openat(dir.as_raw_fd(), name, flags, Mode::empty())?;
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Node extracted into a [`MemorySink`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryNode {
+    Dir,
+    File(Vec<u8>),
+    Symlink(PathBuf),
+    CharacterDevice(u32),
+    BlockDevice(u32),
+}
+
+/// [`ExtractSink`] keeping the extracted nodes in memory, by relative path
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    nodes: Mutex<BTreeMap<PathBuf, (NodeHeader, MemoryNode)>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Extracted nodes, ordered by path
+    pub fn into_nodes(self) -> BTreeMap<PathBuf, (NodeHeader, MemoryNode)> {
+        self.nodes.into_inner().unwrap()
+    }
+
+    fn insert(&self, path: &Path, header: &NodeHeader, node: MemoryNode) {
+        self.nodes.lock().unwrap().insert(path.to_path_buf(), (*header, node));
+    }
+}
+
+impl ExtractSink for MemorySink {
+    fn create_dir(&self, path: &Path, header: &NodeHeader) -> io::Result<()> {
+        self.insert(path, header, MemoryNode::Dir);
+        Ok(())
+    }
+
+    fn create_file(
+        &self,
+        path: &Path,
+        header: &NodeHeader,
+        size: u64,
+        reader: &mut dyn Read,
+    ) -> io::Result<u64> {
+        let mut bytes = Vec::with_capacity(size.min(MEMORY_MAX_CAPACITY) as usize);
+        reader.read_to_end(&mut bytes)?;
+        let len = bytes.len() as u64;
+        self.insert(path, header, MemoryNode::File(bytes));
+        Ok(len)
+    }
+
+    fn create_symlink(&self, path: &Path, target: &Path, header: &NodeHeader) -> io::Result<()> {
+        self.insert(path, header, MemoryNode::Symlink(target.to_path_buf()));
+        Ok(())
+    }
+
+    fn create_char_device(
+        &self,
+        path: &Path,
+        device_number: u32,
+        header: &NodeHeader,
+    ) -> io::Result<()> {
+        self.insert(path, header, MemoryNode::CharacterDevice(device_number));
+        Ok(())
+    }
+
+    fn create_block_device(
+        &self,
+        path: &Path,
+        device_number: u32,
+        header: &NodeHeader,
+    ) -> io::Result<()> {
+        self.insert(path, header, MemoryNode::BlockDevice(device_number));
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.nodes.lock().unwrap().contains_key(path)
+    }
+}
+
+/// [`ExtractSink`] writing the extracted nodes to `writer` as a ustar archive
+///
+/// Entries are written in the order the nodes are extracted, disable [`ExtractOptions::parallel`]
+/// to get the same archive every time. The root directory of the extraction isn't written, and
+/// paths or ids that don't fit in the ustar header are written in a pax extended header. Call
+/// [`Self::finish`] once extracted to end the archive.
+///
+/// Files are streamed to the writer while it is locked, so they are never read with read-ahead.
+///
+/// ```rust,no_run
+/// # use std::fs::File;
+/// # use backhand::{ExtractOptions, FilesystemReader, TarSink};
+/// # let filesystem = FilesystemReader::from_read_at(File::open("image.squashfs").unwrap()).unwrap();
+/// let sink = TarSink::new(File::create("etc.tar").unwrap());
+/// let options = ExtractOptions::new().path_filter("/etc").parallel(false);
+/// filesystem.extract_with_sink(&sink, &options).unwrap();
+/// sink.finish().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct TarSink<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> TarSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer: Mutex::new(writer) }
+    }
+
+    /// Write the end of the archive, returning the writer
+    pub fn finish(self) -> io::Result<W> {
+        let mut writer = self.writer.into_inner().unwrap();
+        writer.write_all(&[0; 2 * TAR_BLOCK])?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    fn append(&self, path: &Path, header: &NodeHeader, entry: TarEntry<'_>) -> io::Result<()> {
+        if path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        self.writer.lock().unwrap().write_all(&tar_header(path, header, &entry))
+    }
+}
+
+impl<W: Write + Send> ExtractSink for TarSink<W> {
+    fn create_dir(&self, path: &Path, header: &NodeHeader) -> io::Result<()> {
+        self.append(path, header, TarEntry::Dir)
+    }
+
+    fn create_file(
+        &self,
+        path: &Path,
+        header: &NodeHeader,
+        size: u64,
+        reader: &mut dyn Read,
+    ) -> io::Result<u64> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&tar_header(path, header, &TarEntry::File(size)))?;
+
+        let mut buf = [0; 8 * 1024];
+        let mut written = 0;
+        let result = loop {
+            let len = (size - written).min(buf.len() as u64) as usize;
+            match reader.read(&mut buf[..len]) {
+                Ok(0) if written == size => break Ok(written),
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    writer.write_all(&buf[..n])?;
+                    written += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => break Err(e),
+            }
+        };
+        // the header has the size of the entry, keep the archive readable if reading failed
+        io::copy(&mut io::repeat(0).take(size - written), &mut *writer)?;
+        let padding = (TAR_BLOCK - (size as usize % TAR_BLOCK)) % TAR_BLOCK;
+        writer.write_all(&[0; TAR_BLOCK][..padding])?;
+        result
+    }
+
+    fn create_symlink(&self, path: &Path, target: &Path, header: &NodeHeader) -> io::Result<()> {
+        self.append(path, header, TarEntry::Symlink(target))
+    }
+
+    fn read_ahead(&self) -> bool {
+        false
+    }
+
+    fn create_char_device(
+        &self,
+        path: &Path,
+        device_number: u32,
+        header: &NodeHeader,
+    ) -> io::Result<()> {
+        self.append(path, header, TarEntry::CharacterDevice(device_number))
+    }
+
+    fn create_block_device(
+        &self,
+        path: &Path,
+        device_number: u32,
+        header: &NodeHeader,
+    ) -> io::Result<()> {
+        self.append(path, header, TarEntry::BlockDevice(device_number))
+    }
+}
+
+/// Type of a tar entry, with the data stored in its header
+enum TarEntry<'a> {
+    Dir,
+    File(u64),
+    Symlink(&'a Path),
+    CharacterDevice(u32),
+    BlockDevice(u32),
+}
+
+/// Header of the tar entry at `path`, preceded by a pax extended header if needed
+fn tar_header(path: &Path, header: &NodeHeader, entry: &TarEntry<'_>) -> Vec<u8> {
+    let mut name = path.as_os_str().as_bytes().to_vec();
+    let (kind, size, link, device_number) = match entry {
+        TarEntry::Dir => {
+            name.push(b'/');
+            (b'5', 0, &[][..], 0)
+        }
+        TarEntry::File(size) => (b'0', *size, &[][..], 0),
+        TarEntry::Symlink(target) => (b'2', 0, target.as_os_str().as_bytes(), 0),
+        TarEntry::CharacterDevice(device_number) => (b'3', 0, &[][..], *device_number),
+        TarEntry::BlockDevice(device_number) => (b'4', 0, &[][..], *device_number),
+    };
+
+    let mut block = [0; TAR_BLOCK];
+    let mut records = vec![];
+    match split_ustar_name(&name) {
+        Some((prefix, name)) => {
+            block[..name.len()].copy_from_slice(name);
+            block[345..345 + prefix.len()].copy_from_slice(prefix);
+        }
+        None => {
+            pax_record(&mut records, "path", &name);
+            block[..100].copy_from_slice(&name[..100]);
+        }
+    }
+    if link.len() <= 100 {
+        block[157..157 + link.len()].copy_from_slice(link);
+    } else {
+        pax_record(&mut records, "linkpath", link);
+        block[157..257].copy_from_slice(&link[..100]);
+    }
+    tar_octal(&mut block[100..108], header.permissions.into());
+    if !tar_octal(&mut block[108..116], header.uid.into()) {
+        pax_record(&mut records, "uid", header.uid.to_string().as_bytes());
+    }
+    if !tar_octal(&mut block[116..124], header.gid.into()) {
+        pax_record(&mut records, "gid", header.gid.to_string().as_bytes());
+    }
+    tar_octal(&mut block[124..136], size);
+    tar_octal(&mut block[136..148], header.mtime.into());
+    block[156] = kind;
+    let major = (device_number >> 8) & 0xfff;
+    let minor = (device_number & 0xff) | ((device_number >> 12) & 0xfff00);
+    tar_octal(&mut block[329..337], major.into());
+    tar_octal(&mut block[337..345], minor.into());
+    tar_finish_header(&mut block);
+
+    let mut blocks = vec![];
+    if !records.is_empty() {
+        let mut pax = [0; TAR_BLOCK];
+        pax[..14].copy_from_slice(b"././@PaxHeader");
+        tar_octal(&mut pax[100..108], 0o644);
+        tar_octal(&mut pax[124..136], records.len() as u64);
+        tar_octal(&mut pax[136..148], header.mtime.into());
+        pax[156] = b'x';
+        tar_finish_header(&mut pax);
+        blocks.extend_from_slice(&pax);
+        blocks.extend_from_slice(&records);
+        blocks.resize(blocks.len() + (TAR_BLOCK - records.len() % TAR_BLOCK) % TAR_BLOCK, 0);
+    }
+    blocks.extend_from_slice(&block);
+    blocks
+}
+
+/// Split `name` into the prefix and name fields of a ustar header, `None` if it doesn't fit
+fn split_ustar_name(name: &[u8]) -> Option<(&[u8], &[u8])> {
+    if name.len() <= 100 {
+        return Some((&name[..0], name));
+    }
+    // the name can't be empty, a directory doesn't split at its trailing `/`
+    let split = (0..name.len() - 1)
+        .filter(|&i| name[i] == b'/')
+        .find(|&i| i <= 155 && name.len() - i - 1 <= 100)?;
+    Some((&name[..split], &name[split + 1..]))
+}
+
+/// Append the pax extended header record `key=value`
+fn pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // the length of the record includes its own digits
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    records.extend_from_slice(format!("{len} {key}=").as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+/// Write `value` as a NUL terminated octal number filling `field`, returns `false` if it doesn't
+/// fit
+fn tar_octal(field: &mut [u8], value: u64) -> bool {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    if digits.len() >= field.len() {
+        return false;
+    }
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    true
+}
+
+/// Write the magic and the checksum of the header `block`, the checksum being the sum of its
+/// bytes with the checksum field as spaces
+fn tar_finish_header(block: &mut [u8; TAR_BLOCK]) {
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    block[148..156].fill(b' ');
+    let checksum: u32 = block.iter().map(|b| u32::from(*b)).sum();
+    block[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+}
@@ -0,0 +1,532 @@
+//! Lazy, on-demand reading of inodes and directories
+
+use std::ffi::OsString;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::os::unix::prelude::OsStringExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use deku::bitvec::{BitView, Msb0};
+use deku::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+use tracing::{error, instrument, trace};
+
+use super::node::{InnerNode, Nodes};
+use super::normalize_squashfs_path;
+use super::reader::{FilesystemReader, FilesystemReaderFile};
+use crate::cache::{Cache, CacheKey};
+use crate::compressor::CompressionOptions;
+use crate::dir::Dir;
+use crate::error::{table, BackhandError, ErrorContext, ResultExt};
+use crate::inode::{BasicFile, Inode, InodeInner};
+use crate::kinds::{Kind, LE_V4_0};
+use crate::limits::{Allocation, ReadLimits};
+use crate::metadata::{self, METADATA_MAXSIZE};
+use crate::reader::{
+    BufReadSeek, ReadAt, ReadAtCursor, ReaderBackend, SquashFsReader, SquashfsReaderWithOffset,
+};
+use crate::squashfs::SuperBlock;
+use crate::{
+    Node, NodeHeader, Squashfs, SquashfsBlockDevice, SquashfsCharacterDevice, SquashfsDir,
+    SquashfsFileReader, SquashfsSymlink,
+};
+
+/// Max amount of known positions of following metadata blocks, cleared when full
+const NEXT_BLOCK_MAX_LEN: usize = 0x1_0000;
+
+/// Representation of SquashFS filesystem, reading inodes and directories only when requested
+///
+/// Unlike [`FilesystemReader`], the inode and directory tables are not read when created. Only the
+/// superblock, fragment and id tables are read, everything else is decoded on demand through the
+/// block cache of decompressed metadata blocks. This keeps the memory usage predictable for images
+/// with millions of inodes.
+///
+/// ```rust,no_run
+/// # use std::fs::File;
+/// # use std::io::BufReader;
+/// # use backhand::{InnerNode, LazyFilesystemReader};
+/// let file = BufReader::new(File::open("image.squashfs").unwrap());
+/// let filesystem = LazyFilesystemReader::from_reader(file).unwrap();
+///
+/// // find one file, without reading the rest of the image
+/// let node = filesystem.node("/usr/bin/bash").unwrap();
+/// if let InnerNode::File(file) = &node.inner {
+///     let (mut buf_read, mut buf_decompress) = filesystem.alloc_read_buffers();
+///     let reader = filesystem.file(&file.basic).reader(&mut buf_read, &mut buf_decompress);
+/// }
+///
+/// // iterate through all nodes, one directory at a time
+/// for node in filesystem.files() {
+///     let node = node.unwrap();
+///     println!("{}", node.fullpath.display());
+/// }
+/// ```
+pub struct LazyFilesystemReader<'b> {
+    superblock: SuperBlock,
+    compression_options: Option<CompressionOptions>,
+    /// Reader, block cache and tables shared with the [`FilesystemReaderFile`]s. The nodes only
+    /// contain the root.
+    system: FilesystemReader<'b>,
+    /// Position of metadata block to position of following metadata block, holding at most
+    /// [`NEXT_BLOCK_MAX_LEN`] entries
+    next_block: Mutex<FxHashMap<u64, u64>>,
+}
+
+/// Entry of a directory from [`LazyFilesystemReader::read_dir_inode`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LazyDirEntry {
+    /// File name of entry
+    pub name: PathBuf,
+    /// Reference of inode, used with [`LazyFilesystemReader::inode`]
+    pub inode_ref: u64,
+    /// Inode number
+    pub inode_number: u32,
+}
+
+impl<'b> LazyFilesystemReader<'b> {
+    /// Same as [`Self::from_reader_with_offset_and_kind`], with default kind:
+    /// [`crate::kind::LE_V4_0`] and offset `0`.
+    pub fn from_reader<R: BufReadSeek + 'b>(reader: R) -> Result<Self, BackhandError> {
+        Self::from_reader_with_offset(reader, 0)
+    }
+
+    /// Same as [`Self::from_reader_with_offset_and_kind`], with default kind:
+    /// [`crate::kind::LE_V4_0`]
+    pub fn from_reader_with_offset<R: BufReadSeek + 'b>(
+        reader: R,
+        offset: u64,
+    ) -> Result<Self, BackhandError> {
+        Self::from_reader_with_offset_and_kind(reader, offset, Kind { inner: Arc::new(LE_V4_0) })
+    }
+
+    /// Read superblock, fragment and id tables from `reader`, with image starting at `offset`
+    pub fn from_reader_with_offset_and_kind<R: BufReadSeek + 'b>(
+        reader: R,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        Self::from_reader_with_limits(reader, offset, kind, ReadLimits::default())
+    }
+
+    /// Same as [`Self::from_reader_with_offset_and_kind`], but with custom `limits` instead of
+    /// the [`ReadLimits::default`] ones
+    ///
+    /// `max_inodes` is not checked, as inodes are only decoded when requested.
+    pub fn from_reader_with_limits<R: BufReadSeek + 'b>(
+        reader: R,
+        offset: u64,
+        kind: Kind,
+        limits: ReadLimits,
+    ) -> Result<Self, BackhandError> {
+        let reader: Box<dyn BufReadSeek + 'b> = if offset == 0 {
+            Box::new(reader)
+        } else {
+            Box::new(SquashfsReaderWithOffset::new(reader, offset)?)
+        };
+        Self::inner_new(reader, None, kind, limits)
+    }
+
+    /// Same as [`Self::from_reader_with_offset_and_kind`], but reading with positional reads from
+    /// `reader`
+    pub fn from_read_at_with_offset_and_kind<R: ReadAt + 'b>(
+        reader: R,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        Self::from_read_at_with_limits(reader, offset, kind, ReadLimits::default())
+    }
+
+    /// Same as [`Self::from_read_at_with_offset_and_kind`], but with custom `limits`
+    pub fn from_read_at_with_limits<R: ReadAt + 'b>(
+        reader: R,
+        offset: u64,
+        kind: Kind,
+        limits: ReadLimits,
+    ) -> Result<Self, BackhandError> {
+        let reader: Arc<dyn ReadAt + 'b> = Arc::new(reader);
+        let stream = BufReader::new(ReadAtCursor::new(reader.clone())?);
+        let stream = Box::new(SquashfsReaderWithOffset::new(stream, offset)?);
+        Self::inner_new(stream, Some((reader, offset)), kind, limits)
+    }
+
+    fn inner_new(
+        mut reader: Box<dyn BufReadSeek + 'b>,
+        read_at: Option<(Arc<dyn ReadAt + 'b>, u64)>,
+        kind: Kind,
+        limits: ReadLimits,
+    ) -> Result<Self, BackhandError> {
+        let (superblock, compression_options) =
+            Squashfs::superblock_and_compression_options(&mut reader, &kind)?;
+        ReadLimits::check(
+            "block size",
+            superblock.block_size.into(),
+            limits.max_block_size.into(),
+        )?;
+
+        // Check if legal image
+        let total_length = reader.seek(SeekFrom::End(0))?;
+        reader.rewind()?;
+        if superblock.bytes_used > total_length
+            || superblock.inode_table > total_length
+            || superblock.dir_table > total_length
+            || superblock.id_table > total_length
+        {
+            error!("corrupted or invalid table position");
+            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        }
+
+        let alloc = Allocation::new(limits);
+        let fragments = reader
+            .fragments(&superblock, &kind, &alloc)
+            .context(|| table("fragment table", superblock.frag_table))?
+            .map(|(_, table)| table);
+        let (_, id_table) = reader
+            .id(&superblock, &kind, &alloc)
+            .context(|| table("id table", superblock.id_table))?;
+
+        let reader = match read_at {
+            Some((reader, offset)) => ReaderBackend::Positional { reader, offset },
+            None => ReaderBackend::Stream(Mutex::new(reader)),
+        };
+        let system = FilesystemReader {
+            kind,
+            block_size: superblock.block_size,
+            block_log: superblock.block_log,
+            compressor: superblock.compressor,
+            compression_options,
+            mod_time: superblock.mod_time,
+            id_table,
+            fragments,
+            root: Nodes::new_root(NodeHeader::default()),
+            reader,
+            cache: Mutex::new(Cache::default()),
+            damage: None,
+            limits,
+        };
+        let mut filesystem = Self {
+            superblock,
+            compression_options,
+            system,
+            next_block: Mutex::new(FxHashMap::default()),
+        };
+
+        let root_inode = filesystem.root_inode()?;
+        let header = NodeHeader::from_inode(root_inode.header, &filesystem.system.id_table)
+            .context(|| ErrorContext::Path(PathBuf::from("/")))?;
+        filesystem.system.root = Nodes::new_root(header);
+        Ok(filesystem)
+    }
+
+    /// Superblock of image
+    pub fn superblock(&self) -> &SuperBlock {
+        &self.superblock
+    }
+
+    /// Compression options of image
+    pub fn compression_options(&self) -> Option<CompressionOptions> {
+        self.compression_options
+    }
+
+    /// Set the max amount of decompressed bytes held in the block cache, see
+    /// [`FilesystemReader::set_cache_size`]
+    pub fn set_cache_size(&self, size: usize) {
+        self.system.set_cache_size(size);
+    }
+
+    /// Statistics of the block cache, see [`FilesystemReader::cache_stats`]
+    pub fn cache_stats(&self) -> crate::CacheStats {
+        self.system.cache_stats()
+    }
+
+    /// Decompressed metadata block at `pos`, and the position of the following metadata block
+    fn metadata_block(&self, pos: u64) -> Result<(Arc<Vec<u8>>, u64), BackhandError> {
+        let key = CacheKey::Metadata(pos);
+        let cached = self.system.cache.lock().unwrap().get(key);
+        let next = self.next_block.lock().unwrap().get(&pos).copied();
+        if let (Some(bytes), Some(next)) = (cached.clone(), next) {
+            return Ok((bytes, next));
+        }
+
+        trace!("reading metadata block: {pos:02x?}");
+        let mut buf = [0u8; 2];
+        self.system.reader.read_exact_at(&mut buf, pos)?;
+        let (_, metadata_len) =
+            u16::read(buf.view_bits::<Msb0>(), self.system.kind.inner.data_endian)?;
+        let byte_len = metadata::len(metadata_len);
+        let next = pos + 2 + u64::from(byte_len);
+
+        // only the position of the following block was forgotten
+        if let Some(bytes) = cached {
+            self.set_next_block(pos, next);
+            return Ok((bytes, next));
+        }
+
+        let mut buf = vec![0u8; byte_len as usize];
+        self.system.reader.read_exact_at(&mut buf, pos + 2)?;
+        let bytes = if metadata::is_compressed(metadata_len) {
+            let mut out = Vec::with_capacity(METADATA_MAXSIZE);
+            self.system.kind.inner.compressor.decompress(&buf, &mut out, self.system.compressor)?;
+            out
+        } else {
+            buf
+        };
+
+        let bytes = Arc::new(bytes);
+        self.system.cache.lock().unwrap().insert(key, bytes.clone());
+        self.set_next_block(pos, next);
+        Ok((bytes, next))
+    }
+
+    /// Remember `next` as the position of the metadata block following `pos`
+    fn set_next_block(&self, pos: u64, next: u64) {
+        let mut next_block = self.next_block.lock().unwrap();
+        if next_block.len() >= NEXT_BLOCK_MAX_LEN {
+            next_block.clear();
+        }
+        next_block.insert(pos, next);
+    }
+
+    /// Read `len` bytes starting at `offset` of metadata block `pos`, continuing into the
+    /// following metadata blocks
+    fn metadata_bytes(
+        &self,
+        pos: u64,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, BackhandError> {
+        let (block, mut pos) = self.metadata_block(pos)?;
+        let mut bytes = match block.get(offset..) {
+            Some(bytes) => bytes.to_vec(),
+            None => {
+                error!("metadata offset {offset:02x?} outside of block");
+                return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            }
+        };
+        while bytes.len() < len {
+            if pos >= self.superblock.bytes_used {
+                error!("metadata read past the end of the image");
+                return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            }
+            let (block, next) = self.metadata_block(pos)?;
+            bytes.extend_from_slice(&block);
+            pos = next;
+        }
+        bytes.truncate(len);
+        Ok(bytes)
+    }
+
+    /// Root inode, as stored in the superblock
+    pub fn root_inode(&self) -> Result<Inode, BackhandError> {
+        self.inode(self.superblock.root_inode)
+    }
+
+    /// Decode the inode at `inode_ref`
+    ///
+    /// The upper bits of `inode_ref` are the position of the metadata block relative to the
+    /// inode table, the lower 16 bits are the offset into the decompressed metadata block.
+    #[instrument(skip_all)]
+    pub fn inode(&self, inode_ref: u64) -> Result<Inode, BackhandError> {
+        let pos = self.superblock.inode_table + (inode_ref >> 16);
+        let offset = (inode_ref & 0xffff) as usize;
+        trace!("inode at: {pos:02x?}, offset: {offset:02x?}");
+
+        let (block, mut pos) = self.metadata_block(pos)?;
+        let mut bytes = match block.get(offset..) {
+            Some(bytes) => bytes.to_vec(),
+            None => {
+                error!("inode offset {offset:02x?} outside of block");
+                return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            }
+        };
+        let ctx = (
+            self.superblock.bytes_used,
+            self.superblock.block_size,
+            self.superblock.block_log,
+            self.system.kind.inner.type_endian,
+        );
+        loop {
+            match Inode::read(bytes.view_bits::<Msb0>(), ctx) {
+                Ok((_, inode)) => return Ok(inode),
+                // inodes can span multiple blocks!
+                Err(DekuError::Incomplete(_)) if pos < self.superblock.dir_table => {
+                    let (block, next) = self.metadata_block(pos)?;
+                    bytes.extend_from_slice(&block);
+                    pos = next;
+                }
+                Err(e) => {
+                    error!("{e}");
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    /// Decode the directory listing of `dir`, sorted by name
+    #[instrument(skip_all)]
+    pub fn read_dir_inode(&self, dir: &Inode) -> Result<Vec<LazyDirEntry>, BackhandError> {
+        let (block_index, file_size, block_offset) = match &dir.inner {
+            InodeInner::BasicDirectory(dir) => {
+                (dir.block_index, u32::from(dir.file_size), dir.block_offset)
+            }
+            InodeInner::ExtendedDirectory(dir) => {
+                (dir.block_index, dir.file_size, dir.block_offset)
+            }
+            _ => return Err(BackhandError::UnexpectedInode(dir.inner.clone())),
+        };
+        // empty dir
+        if file_size < 4 {
+            return Ok(vec![]);
+        }
+
+        let inode_number = dir.header.inode_number;
+        let pos = self.superblock.dir_table + u64::from(block_index);
+        let bytes = self
+            .metadata_bytes(pos, block_offset as usize, file_size as usize - 3)
+            .context(|| ErrorContext::Inode(inode_number))?;
+
+        let mut entries = vec![];
+        let mut all_bytes = bytes.view_bits::<Msb0>();
+        while let Ok((rest, dir)) = Dir::read(all_bytes, self.system.kind.inner.type_endian) {
+            for entry in &dir.dir_entries {
+                let name = entry.name().context(|| ErrorContext::Inode(inode_number))?;
+                entries.push(LazyDirEntry {
+                    name: name.to_path_buf(),
+                    inode_ref: (u64::from(dir.start) << 16) | u64::from(entry.offset),
+                    inode_number: (dir.inode_num as i32 + i32::from(entry.inode_offset)) as u32,
+                });
+            }
+            all_bytes = rest;
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        if entries.windows(2).any(|entries| entries[0].name == entries[1].name) {
+            return Err(BackhandError::DuplicatedFileName);
+        }
+        Ok(entries)
+    }
+
+    /// Find the node at `path`, only decoding the directories leading to it
+    pub fn node<P: AsRef<Path>>(&self, path: P) -> Result<Node<SquashfsFileReader>, BackhandError> {
+        let path = normalize_squashfs_path(path.as_ref())?;
+        let mut inode = self.root_inode()?;
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                _ => continue,
+            };
+            let entry = self
+                .read_dir_inode(&inode)?
+                .into_iter()
+                .find(|entry| entry.name.as_os_str() == name)
+                .ok_or(BackhandError::FileNotFound)?;
+            inode = self.inode(entry.inode_ref)?;
+        }
+        self.node_from_inode(path, inode)
+    }
+
+    /// Iterator of all nodes, including the root, decoding one directory at a time
+    ///
+    /// Nodes are returned depth-first, with the entries of a directory following the directory.
+    /// A directory read a second time, from a cycle in the image, is returned as
+    /// [`BackhandError::DirectoryCycle`] without its entries.
+    pub fn files(&self) -> LazyFiles<'_, 'b> {
+        LazyFiles {
+            filesystem: self,
+            stack: vec![(PathBuf::from("/"), self.superblock.root_inode)],
+            visited: FxHashSet::default(),
+        }
+    }
+
+    /// Allocate two properly sized buffers for [`FilesystemReaderFile::reader`]
+    pub fn alloc_read_buffers(&self) -> (Vec<u8>, Vec<u8>) {
+        self.system.alloc_read_buffers()
+    }
+
+    /// Return a file handler for this file
+    pub fn file<'a>(&'a self, basic_file: &'a BasicFile) -> FilesystemReaderFile<'a, 'b> {
+        self.system.file(basic_file)
+    }
+
+    fn node_from_inode(
+        &self,
+        fullpath: PathBuf,
+        inode: Inode,
+    ) -> Result<Node<SquashfsFileReader>, BackhandError> {
+        let header = NodeHeader::from_inode(inode.header, &self.system.id_table)
+            .context(|| ErrorContext::Path(fullpath.clone()))?;
+        let inner = match inode.inner {
+            InodeInner::BasicDirectory(_) | InodeInner::ExtendedDirectory(_) => {
+                InnerNode::Dir(SquashfsDir::default())
+            }
+            InodeInner::BasicFile(basic) => {
+                self.check_file_size(basic.file_size.into())?;
+                InnerNode::File(SquashfsFileReader { basic })
+            }
+            InodeInner::ExtendedFile(ref file) => {
+                self.check_file_size(file.file_size)?;
+                InnerNode::File(SquashfsFileReader { basic: BasicFile::try_from(file)? })
+            }
+            InodeInner::BasicSymlink(symlink) => {
+                let link = PathBuf::from(OsString::from_vec(symlink.target_path));
+                InnerNode::Symlink(SquashfsSymlink { link })
+            }
+            InodeInner::BasicCharacterDevice(device) => {
+                InnerNode::CharacterDevice(SquashfsCharacterDevice {
+                    device_number: device.device_number,
+                })
+            }
+            InodeInner::BasicBlockDevice(device) => {
+                InnerNode::BlockDevice(SquashfsBlockDevice { device_number: device.device_number })
+            }
+        };
+        Ok(Node::new(fullpath, header, inner))
+    }
+
+    fn check_file_size(&self, file_size: u64) -> Result<(), BackhandError> {
+        ReadLimits::check("file size", file_size, self.system.limits.max_file_size)
+    }
+}
+
+/// Iterator of all nodes of a [`LazyFilesystemReader`], see [`LazyFilesystemReader::files`]
+pub struct LazyFiles<'a, 'b> {
+    filesystem: &'a LazyFilesystemReader<'b>,
+    /// Path and inode reference of nodes still to be returned, last is next
+    stack: Vec<(PathBuf, u64)>,
+    /// Inode numbers of the directories already read
+    visited: FxHashSet<u32>,
+}
+
+impl<'a, 'b> LazyFiles<'a, 'b> {
+    fn visit(
+        &mut self,
+        fullpath: PathBuf,
+        inode_ref: u64,
+    ) -> Result<Node<SquashfsFileReader>, BackhandError> {
+        ReadLimits::check(
+            "directory depth",
+            fullpath.components().count() as u64 - 1,
+            self.filesystem.system.limits.max_dir_depth as u64,
+        )?;
+        let inode = self.filesystem.inode(inode_ref)?;
+        if matches!(inode.inner, InodeInner::BasicDirectory(_) | InodeInner::ExtendedDirectory(_)) {
+            let inode_number = inode.header.inode_number;
+            if !self.visited.insert(inode_number) {
+                error!("directory {fullpath:?} with inode {inode_number} was already read");
+                return Err(BackhandError::DirectoryCycle { path: fullpath, inode: inode_number });
+            }
+            let entries = self.filesystem.read_dir_inode(&inode)?;
+            for entry in entries.into_iter().rev() {
+                self.stack.push((fullpath.join(entry.name), entry.inode_ref));
+            }
+        }
+        self.filesystem.node_from_inode(fullpath, inode)
+    }
+}
+
+impl<'a, 'b> Iterator for LazyFiles<'a, 'b> {
+    type Item = Result<Node<SquashfsFileReader>, BackhandError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (fullpath, inode_ref) = self.stack.pop()?;
+        Some(self.visit(fullpath, inode_ref))
+    }
+}
@@ -4,6 +4,8 @@ use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
+use tracing::error;
+
 use super::normalize_squashfs_path;
 use crate::data::Added;
 use crate::inode::{BasicFile, InodeHeader};
@@ -27,13 +29,20 @@ impl NodeHeader {
 }
 
 impl NodeHeader {
-    pub fn from_inode(inode_header: InodeHeader, id_table: &[Id]) -> Self {
-        Self {
+    /// Header of inode, with the uid and gid looked up in `id_table`
+    pub fn from_inode(inode_header: InodeHeader, id_table: &[Id]) -> Result<Self, BackhandError> {
+        let id = |index: u16| {
+            id_table.get(usize::from(index)).map(|id| id.num).ok_or_else(|| {
+                error!("id index {index} is not in the id table");
+                BackhandError::InvalidIdIndex(index)
+            })
+        };
+        Ok(Self {
             permissions: inode_header.permissions,
-            uid: id_table[inode_header.uid as usize].num,
-            gid: id_table[inode_header.gid as usize].num,
+            uid: id(inode_header.uid)?,
+            gid: id(inode_header.gid)?,
             mtime: inode_header.mtime,
-        }
+        })
     }
 }
 
@@ -154,6 +163,18 @@ impl<T> Nodes<T> {
             .map(|found| &mut self.nodes[found])
     }
 
+    /// Find the node at exactly `path`, without following symlinks
+    pub fn find<S: AsRef<Path>>(&self, path: S) -> Option<&Node<T>> {
+        self.find_index(path).map(|found| &self.nodes[found])
+    }
+
+    pub(crate) fn find_index<S: AsRef<Path>>(&self, path: S) -> Option<usize> {
+        //the search path root prefix is optional, so remove it if present to
+        //not affect the search
+        let find_path = normalize_squashfs_path(path.as_ref()).ok()?;
+        self.nodes.binary_search_by(|node| node.fullpath.cmp(&find_path)).ok()
+    }
+
     pub fn insert(&mut self, node: Node<T>) -> Result<(), BackhandError> {
         let path = &node.fullpath;
         let parent = node.fullpath.parent().ok_or(BackhandError::InvalidFilePath)?;
@@ -0,0 +1,188 @@
+//! Owned, cheaply cloneable handles to a [`FilesystemReader`] and its files
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::node::InnerNode;
+use super::reader::FilesystemReader;
+use super::seek::{seek_position, FileBlocks};
+use crate::error::BackhandError;
+use crate::inode::BasicFile;
+use crate::kinds::Kind;
+use crate::reader::{BufReadSeek, ReadAt};
+
+/// [`FilesystemReader`] owning its reader, shared behind an [`Arc`]
+///
+/// Unlike [`FilesystemReader`], this has no lifetime and [`Clone`] only increments a reference
+/// count, so it can be stored in structs, sent to other threads or tasks, and hand out
+/// [`OwnedFile`] handles that outlive the borrow of the filesystem. All clones share the same
+/// block cache.
+///
+/// All methods of [`FilesystemReader`] are available through [`Deref`].
+///
+/// ```rust,no_run
+/// # use std::fs::File;
+/// # use std::io::Read;
+/// # use backhand::OwnedFilesystemReader;
+/// let filesystem = OwnedFilesystemReader::from_read_at(File::open("image.squashfs").unwrap()).unwrap();
+///
+/// let mut file = filesystem.open("/etc/passwd").unwrap();
+/// std::thread::spawn(move || {
+///     let mut passwd = String::new();
+///     file.read_to_string(&mut passwd).unwrap();
+/// });
+/// ```
+#[derive(Clone)]
+pub struct OwnedFilesystemReader {
+    inner: Arc<FilesystemReader<'static>>,
+}
+
+impl OwnedFilesystemReader {
+    /// Same as [`FilesystemReader::from_reader`]
+    pub fn from_reader<R: BufReadSeek + 'static>(reader: R) -> Result<Self, BackhandError> {
+        FilesystemReader::from_reader(reader).map(Self::from)
+    }
+
+    /// Same as [`FilesystemReader::from_reader_with_offset`]
+    pub fn from_reader_with_offset<R: BufReadSeek + 'static>(
+        reader: R,
+        offset: u64,
+    ) -> Result<Self, BackhandError> {
+        FilesystemReader::from_reader_with_offset(reader, offset).map(Self::from)
+    }
+
+    /// Same as [`FilesystemReader::from_reader_with_offset_and_kind`]
+    pub fn from_reader_with_offset_and_kind<R: BufReadSeek + 'static>(
+        reader: R,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        FilesystemReader::from_reader_with_offset_and_kind(reader, offset, kind).map(Self::from)
+    }
+
+    /// Same as [`FilesystemReader::from_read_at`]
+    pub fn from_read_at<R: ReadAt + 'static>(reader: R) -> Result<Self, BackhandError> {
+        FilesystemReader::from_read_at(reader).map(Self::from)
+    }
+
+    /// Same as [`FilesystemReader::from_read_at_with_offset`]
+    pub fn from_read_at_with_offset<R: ReadAt + 'static>(
+        reader: R,
+        offset: u64,
+    ) -> Result<Self, BackhandError> {
+        FilesystemReader::from_read_at_with_offset(reader, offset).map(Self::from)
+    }
+
+    /// Same as [`FilesystemReader::from_read_at_with_offset_and_kind`]
+    pub fn from_read_at_with_offset_and_kind<R: ReadAt + 'static>(
+        reader: R,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        FilesystemReader::from_read_at_with_offset_and_kind(reader, offset, kind).map(Self::from)
+    }
+
+    /// Open the file at `path`, following symlinks. Same as [`FilesystemReader::open`], but the
+    /// returned handle doesn't borrow `self`
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<OwnedFile, BackhandError> {
+        match &self.metadata(path)?.inner {
+            InnerNode::File(file) => Ok(self.file(&file.basic)),
+            _ => Err(BackhandError::NotAFile),
+        }
+    }
+
+    /// Return an owned file handler for this file, holding a copy of `basic_file`
+    pub fn file(&self, basic_file: &BasicFile) -> OwnedFile {
+        OwnedFile {
+            system: self.clone(),
+            blocks: FileBlocks::new(basic_file),
+            basic: Arc::new(basic_file.clone()),
+            pos: 0,
+        }
+    }
+}
+
+impl From<FilesystemReader<'static>> for OwnedFilesystemReader {
+    fn from(reader: FilesystemReader<'static>) -> Self {
+        Self { inner: Arc::new(reader) }
+    }
+}
+
+impl Deref for OwnedFilesystemReader {
+    type Target = FilesystemReader<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+/// File of an [`OwnedFilesystemReader`], impls [`Read`] and [`Seek`]
+///
+/// Same as [`crate::SquashfsSeekFile`], but owning a reference to the filesystem and the
+/// [`BasicFile`] of the file. [`Clone`] returns a handle at the same position, reading
+/// independently of the original.
+#[derive(Clone)]
+pub struct OwnedFile {
+    system: OwnedFilesystemReader,
+    basic: Arc<BasicFile>,
+    blocks: FileBlocks,
+    pos: u64,
+}
+
+impl OwnedFile {
+    /// Filesystem this file belongs to
+    pub fn filesystem(&self) -> &OwnedFilesystemReader {
+        &self.system
+    }
+
+    /// Inode data of the file
+    pub fn basic(&self) -> &BasicFile {
+        &self.basic
+    }
+
+    /// Size of the file in bytes
+    pub fn len(&self) -> u64 {
+        self.basic.file_size.into()
+    }
+
+    /// Returns `true` if the file has no data
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Same as [`crate::SquashfsSeekFile::read_at`]
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, BackhandError> {
+        self.blocks.read_at(&self.system, &self.basic, offset, buf)
+    }
+
+    /// Same as [`crate::SquashfsSeekFile::read_exact_at`]
+    pub fn read_exact_at(&mut self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(offset, buf)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => {
+                    offset += n as u64;
+                    buf = &mut buf[n..];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for OwnedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.read_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for OwnedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(pos, self.pos, self.len())?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,290 @@
+//! Patterns selecting the nodes extracted by [`crate::ExtractOptions`]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "regex")]
+use regex::Regex;
+
+use crate::error::BackhandError;
+
+/// Pattern matching the full path of nodes, such as `/usr/bin/wget`
+///
+/// When including or excluding nodes with [`crate::ExtractOptions`], a pattern matching a
+/// directory also matches all the nodes under it.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    inner: PatternInner,
+}
+
+#[derive(Debug, Clone)]
+enum PatternInner {
+    Path(PathBuf),
+    /// Glob matching the file name if `name`, otherwise the full path
+    Glob {
+        tokens: Vec<GlobToken>,
+        name: bool,
+    },
+    #[cfg(feature = "regex")]
+    Regex(Regex),
+}
+
+/// Part of a parsed glob, matching one or more characters
+#[derive(Debug, Clone)]
+enum GlobToken {
+    Char(char),
+    /// `?`
+    AnyChar,
+    /// `*`
+    AnyName,
+    /// `**` not followed by `/`
+    AnyPath,
+    /// `**/`, matching zero or more directories
+    AnyDirs,
+    /// `[...]`, with inclusive ranges of characters
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Pattern {
+    /// Match the node at exactly `path`, relative to the root of the image
+    pub fn path<P: AsRef<Path>>(path: P) -> Self {
+        Self { inner: PatternInner::Path(Path::new("/").join(path)) }
+    }
+
+    /// Match the paths matching the shell glob `glob`
+    ///
+    /// `?` matches any character and `*` any amount of characters, except `/`. `**` also matches
+    /// `/`, with `**/` matching zero or more directories. `[...]` matches any of the characters
+    /// in it, or any other character when starting with `!`. A glob without `/`, such as `*.so`,
+    /// matches the file name of nodes in any directory. Otherwise the glob matches the full path,
+    /// relative to the root of the image.
+    pub fn glob(glob: &str) -> Result<Self, BackhandError> {
+        let name = !glob.contains('/');
+        let mut tokens = vec![];
+        if !name && !glob.starts_with('/') {
+            tokens.push(GlobToken::Char('/'));
+        }
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    // `/usr/**/wget` also matches `/usr/wget`
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        GlobToken::AnyDirs
+                    } else {
+                        GlobToken::AnyPath
+                    }
+                }
+                '*' => GlobToken::AnyName,
+                '?' => GlobToken::AnyChar,
+                '[' => {
+                    let negated = chars.peek() == Some(&'!');
+                    if negated {
+                        chars.next();
+                    }
+                    let mut class = vec![];
+                    loop {
+                        match chars.next() {
+                            Some(']') => break,
+                            Some(c) => class.push(c),
+                            None => {
+                                let error = format!("unclosed `[` in glob {glob:?}");
+                                return Err(BackhandError::InvalidPattern(error));
+                            }
+                        }
+                    }
+                    if class.is_empty() {
+                        let error = format!("empty `[]` in glob {glob:?}");
+                        return Err(BackhandError::InvalidPattern(error));
+                    }
+                    GlobToken::Class { negated, ranges: class_ranges(&class) }
+                }
+                c => GlobToken::Char(c),
+            };
+            tokens.push(token);
+        }
+        Ok(Self { inner: PatternInner::Glob { tokens, name } })
+    }
+
+    /// Match the paths in which the regular expression `regex` matches, see [`regex::Regex`]
+    ///
+    /// The regex isn't anchored, use `^` and `$` to match the full path.
+    #[cfg(feature = "regex")]
+    pub fn regex(regex: &str) -> Result<Self, BackhandError> {
+        let regex = Regex::new(regex).map_err(|e| BackhandError::InvalidPattern(e.to_string()))?;
+        Ok(Self { inner: PatternInner::Regex(regex) })
+    }
+
+    /// Parse the pattern file at `path`, with one pattern per line
+    ///
+    /// Lines starting with `regex:` are parsed with [`Self::regex`], and all others with
+    /// [`Self::glob`], after removing an optional `glob:` prefix. Lines are trimmed, and empty
+    /// lines and lines starting with `#` are ignored. Without the `regex` feature, `regex:` lines
+    /// are an error.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, BackhandError> {
+        Self::from_lines(&fs::read_to_string(path)?)
+    }
+
+    /// Parse `patterns` like [`Self::from_file`]
+    fn from_lines(patterns: &str) -> Result<Vec<Self>, BackhandError> {
+        patterns
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| match line.strip_prefix("regex:") {
+                #[cfg(feature = "regex")]
+                Some(regex) => Self::regex(regex),
+                #[cfg(not(feature = "regex"))]
+                Some(_) => {
+                    let error = format!("{line:?} needs the regex feature");
+                    Err(BackhandError::InvalidPattern(error))
+                }
+                None => Self::glob(line.strip_prefix("glob:").unwrap_or(line)),
+            })
+            .collect()
+    }
+
+    /// Returns `true` if the node at the full path `path` is matched, not including the nodes
+    /// under the matched directories
+    pub fn matches(&self, path: &Path) -> bool {
+        match &self.inner {
+            PatternInner::Path(pattern) => path == pattern,
+            PatternInner::Glob { tokens, name: true } => {
+                path.file_name().map_or(false, |name| glob_matches(tokens, &name.to_string_lossy()))
+            }
+            PatternInner::Glob { tokens, name: false } => {
+                glob_matches(tokens, &path.to_string_lossy())
+            }
+            #[cfg(feature = "regex")]
+            PatternInner::Regex(regex) => regex.is_match(&path.to_string_lossy()),
+        }
+    }
+
+    /// Returns `true` if `path` or one of its parent directories is matched
+    pub(crate) fn matches_ancestor(&self, path: &Path) -> bool {
+        path.ancestors().any(|ancestor| self.matches(ancestor))
+    }
+
+    /// Exact path of a [`Self::path`] pattern
+    pub(crate) fn as_path(&self) -> Option<&Path> {
+        match &self.inner {
+            PatternInner::Path(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// Ranges of the characters of a glob class, `-` is only a range between two characters
+fn class_ranges(class: &[char]) -> Vec<(char, char)> {
+    let mut ranges = vec![];
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            ranges.push((class[i], class[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((class[i], class[i]));
+            i += 1;
+        }
+    }
+    ranges
+}
+
+/// Returns `true` if all of `text` is matched by `tokens`
+fn glob_matches(tokens: &[GlobToken], text: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    // ends[i] is true if text[..i] is matched by the tokens so far
+    let mut ends = vec![false; text.len() + 1];
+    ends[0] = true;
+    for token in tokens {
+        let mut next = vec![false; text.len() + 1];
+        for start in (0..=text.len()).filter(|start| ends[*start]) {
+            let rest = &text[start..];
+            match (token, rest.first()) {
+                (GlobToken::Char(c), Some(first)) => next[start + 1] |= first == c,
+                (GlobToken::AnyChar, Some(first)) => next[start + 1] |= *first != '/',
+                (GlobToken::Class { negated, ranges }, Some(first)) => {
+                    let in_class = ranges.iter().any(|(low, high)| (*low..=*high).contains(first));
+                    next[start + 1] |= in_class != *negated;
+                }
+                (GlobToken::Char(_) | GlobToken::AnyChar | GlobToken::Class { .. }, None) => (),
+                (GlobToken::AnyName, _) => {
+                    let len = rest.iter().position(|c| *c == '/').unwrap_or(rest.len());
+                    next[start..=start + len].iter_mut().for_each(|end| *end = true);
+                }
+                (GlobToken::AnyPath, _) => next[start..].iter_mut().for_each(|end| *end = true),
+                (GlobToken::AnyDirs, _) => {
+                    next[start] = true;
+                    for (i, c) in rest.iter().enumerate() {
+                        next[start + i + 1] |= *c == '/';
+                    }
+                }
+            }
+        }
+        ends = next;
+    }
+    ends[text.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        let so = Pattern::glob("*.so").unwrap();
+        assert!(so.matches(Path::new("/usr/lib/libc.so")));
+        assert!(!so.matches(Path::new("/usr/lib/libc.so.6")));
+
+        let bin = Pattern::glob("usr/*/w?et").unwrap();
+        assert!(bin.matches(Path::new("/usr/bin/wget")));
+        assert!(!bin.matches(Path::new("/usr/local/bin/wget")));
+        let any_dir = Pattern::glob("/usr/**/wget").unwrap();
+        assert!(any_dir.matches(Path::new("/usr/local/bin/wget")));
+        assert!(any_dir.matches(Path::new("/usr/wget")));
+        assert!(!any_dir.matches(Path::new("/usr/bin/xwget")));
+        assert!(Pattern::glob("**/wget").unwrap().matches(Path::new("/wget")));
+        assert!(Pattern::glob("/usr/**").unwrap().matches(Path::new("/usr/bin/wget")));
+
+        let class = Pattern::glob("/etc/rc[0-9!].d").unwrap();
+        assert!(class.matches(Path::new("/etc/rc5.d")));
+        assert!(class.matches(Path::new("/etc/rc!.d")));
+        assert!(!class.matches(Path::new("/etc/rcS.d")));
+        assert!(Pattern::glob("/etc/rc[!0-9].d").unwrap().matches(Path::new("/etc/rcS.d")));
+        assert!(Pattern::glob("/etc/rc[-a].d").unwrap().matches(Path::new("/etc/rc-.d")));
+        assert!(Pattern::glob("a+(b).txt").unwrap().matches(Path::new("/a+(b).txt")));
+        assert!(Pattern::glob("[").is_err());
+        assert!(Pattern::glob("[]").is_err());
+    }
+
+    #[test]
+    fn test_from_lines() {
+        let patterns = Pattern::from_lines("# comment\r\n*.bak \r\n\r\n  glob:/tmp/*\r\n").unwrap();
+        assert_eq!(patterns.len(), 2);
+        assert!(patterns[0].matches(Path::new("/etc/config.bak")));
+        assert!(patterns[1].matches(Path::new("/tmp/file")));
+        assert_eq!(Pattern::from_lines("regex:^/usr$").is_ok(), cfg!(feature = "regex"));
+    }
+
+    #[test]
+    fn test_path() {
+        let path = Pattern::path("usr/bin");
+        assert!(path.matches(Path::new("/usr/bin")));
+        assert!(!path.matches(Path::new("/usr/bin/wget")));
+        assert!(path.matches_ancestor(Path::new("/usr/bin/wget")));
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn test_regex() {
+        let regex = Pattern::regex(r"\.(json|xml)$").unwrap();
+        assert!(regex.matches(Path::new("/www/data/region.json")));
+        assert!(!regex.matches(Path::new("/www/data/region.js")));
+        assert!(Pattern::regex("(").is_err());
+    }
+}
@@ -1,17 +1,29 @@
-use std::io::{Read, SeekFrom};
-use std::sync::Mutex;
-
-use super::node::Nodes;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io::{BufRead, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rayon::prelude::*;
+use tracing::error;
+
+use super::node::{InnerNode, Nodes};
+use super::seek::SquashfsSeekFile;
+use crate::cache::{Cache, CacheKey, CacheStats};
 use crate::compressor::{CompressionOptions, Compressor};
 use crate::data::DataSize;
 use crate::error::BackhandError;
 use crate::fragment::Fragment;
 use crate::id::Id;
 use crate::inode::BasicFile;
-use crate::kinds::Kind;
-use crate::reader::BufReadSeek;
-use crate::squashfs::Cache;
-use crate::{Node, Squashfs, SquashfsFileReader};
+use crate::kinds::{Kind, LE_V4_0};
+use crate::limits::ReadLimits;
+use crate::reader::{BufReadSeek, ReadAt, ReaderBackend};
+use crate::salvage::{tolerate, Damage};
+use crate::{Node, Squashfs, SquashfsFileReader, SquashfsSymlink};
+
+/// Max amount of symlinks followed when resolving a path, same as linux
+const MAX_SYMLINKS: usize = 40;
 
 /// Representation of SquashFS filesystem after read from image
 /// - Use [`Self::from_reader`] to read into `Self` from a `reader`
@@ -86,9 +98,13 @@ pub struct FilesystemReader<'b> {
     /// All files and directories in filesystem
     pub root: Nodes<SquashfsFileReader>,
     // File reader
-    pub(crate) reader: Mutex<Box<dyn BufReadSeek + 'b>>,
-    // Cache used in the decompression
+    pub(crate) reader: ReaderBackend<'b>,
+    // Cache of decompressed blocks
     pub(crate) cache: Mutex<Cache>,
+    // Damage found in salvage mode, `None` otherwise
+    pub(crate) damage: Option<Mutex<Vec<Damage>>>,
+    // Limits the image was read with, also used for the images nested in it
+    pub(crate) limits: ReadLimits,
 }
 
 impl<'b> FilesystemReader<'b> {
@@ -127,6 +143,123 @@ impl<'b> FilesystemReader<'b> {
         squashfs.into_filesystem_reader()
     }
 
+    /// Call [`Squashfs::from_reader_with_limits`], then [`Squashfs::into_filesystem_reader`]
+    pub fn from_reader_with_limits<R: BufReadSeek + 'b>(
+        reader: R,
+        offset: u64,
+        kind: Kind,
+        limits: ReadLimits,
+    ) -> Result<Self, BackhandError> {
+        let squashfs = Squashfs::from_reader_with_limits(reader, offset, kind, limits)?;
+        squashfs.into_filesystem_reader()
+    }
+
+    /// Call [`Squashfs::from_read_at_with_offset_and_kind`], then
+    /// [`Squashfs::into_filesystem_reader`]
+    ///
+    /// With default kind: [`crate::kind::LE_V4_0`] and offset `0`. File data is read with
+    /// positional reads, so files can be read concurrently without contending on a lock.
+    ///
+    /// ```rust,no_run
+    /// # use std::fs::File;
+    /// # use backhand::FilesystemReader;
+    /// let file = File::open("image.squashfs").unwrap();
+    /// let filesystem = FilesystemReader::from_read_at(file).unwrap();
+    /// ```
+    pub fn from_read_at<R: ReadAt + 'b>(reader: R) -> Result<Self, BackhandError> {
+        Self::from_read_at_with_offset(reader, 0)
+    }
+
+    /// Same as [`Self::from_read_at`], but with the image starting at `offset` in `reader`
+    pub fn from_read_at_with_offset<R: ReadAt + 'b>(
+        reader: R,
+        offset: u64,
+    ) -> Result<Self, BackhandError> {
+        let kind = Kind { inner: Arc::new(LE_V4_0) };
+        Self::from_read_at_with_offset_and_kind(reader, offset, kind)
+    }
+
+    /// Same as [`Self::from_read_at_with_offset`], but setting custom `kind`
+    pub fn from_read_at_with_offset_and_kind<R: ReadAt + 'b>(
+        reader: R,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        let squashfs = Squashfs::from_read_at_with_offset_and_kind(reader, offset, kind)?;
+        squashfs.into_filesystem_reader()
+    }
+
+    /// Call [`Squashfs::from_read_at_with_limits`], then [`Squashfs::into_filesystem_reader`]
+    pub fn from_read_at_with_limits<R: ReadAt + 'b>(
+        reader: R,
+        offset: u64,
+        kind: Kind,
+        limits: ReadLimits,
+    ) -> Result<Self, BackhandError> {
+        let squashfs = Squashfs::from_read_at_with_limits(reader, offset, kind, limits)?;
+        squashfs.into_filesystem_reader()
+    }
+
+    /// Memory-map `file`, then call [`Self::from_read_at`]
+    ///
+    /// Data blocks are read directly from the mapping, uncompressed blocks are returned without
+    /// copying from [`SquashfsReadFile`]'s [`BufRead`] impl.
+    ///
+    /// # Safety
+    /// `file` must not be modified or truncated while `Self` is alive, see [`memmap2::Mmap`]
+    #[cfg(feature = "mmap")]
+    pub unsafe fn from_mmap(file: &std::fs::File) -> Result<Self, BackhandError> {
+        Self::from_mmap_with_offset(file, 0)
+    }
+
+    /// Same as [`Self::from_mmap`], but with the image starting at `offset` in `file`
+    ///
+    /// # Safety
+    /// `file` must not be modified or truncated while `Self` is alive, see [`memmap2::Mmap`]
+    #[cfg(feature = "mmap")]
+    pub unsafe fn from_mmap_with_offset(
+        file: &std::fs::File,
+        offset: u64,
+    ) -> Result<Self, BackhandError> {
+        let mmap = memmap2::Mmap::map(file)?;
+        Self::from_read_at_with_offset(mmap, offset)
+    }
+
+    /// Statistics of the cache of decompressed fragment, data and metadata blocks
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+
+    /// Also cache the data blocks read by [`SquashfsSeekFile`] and `AsyncFile`, for random
+    /// access to the same blocks. Disabling removes the data blocks already cached.
+    ///
+    /// Data blocks read sequentially by [`SquashfsReadFile`] are never cached, as they are
+    /// expected to only be read once.
+    ///
+    /// Default: `false`, only fragment and metadata blocks are cached
+    pub fn set_cache_data_blocks(&self, enabled: bool) {
+        self.cache.lock().unwrap().set_data_blocks(enabled);
+    }
+
+    /// Set the max amount of decompressed bytes held in the block cache, evicting the least
+    /// recently used blocks if needed. A `size` of `0` disables the cache.
+    ///
+    /// Default: [`crate::DEFAULT_CACHE_SIZE`]
+    pub fn set_cache_size(&self, size: usize) {
+        self.cache.lock().unwrap().set_capacity(size);
+    }
+
+    /// Take the [`Damage`] found so far, if read with [`Squashfs::from_reader_salvage`]
+    ///
+    /// Damage of the image tables is found while reading the image, damage of data blocks is
+    /// found when reading the files.
+    pub fn take_damage(&self) -> Vec<Damage> {
+        match &self.damage {
+            Some(damage) => std::mem::take(&mut *damage.lock().unwrap()),
+            None => vec![],
+        }
+    }
+
     /// Return a file handler for this file
     pub fn file<'a>(&'a self, basic_file: &'a BasicFile) -> FilesystemReaderFile<'a, 'b> {
         FilesystemReaderFile::new(self, basic_file)
@@ -166,6 +299,135 @@ impl<'b> FilesystemReader<'b> {
     pub fn files(&self) -> impl Iterator<Item = &Node<SquashfsFileReader>> {
         self.root.nodes.iter()
     }
+
+    /// Find the node at `path`, following symlinks in all but the last component of the path
+    ///
+    /// Like `lstat`, if `path` is a symlink the symlink itself is returned. See [`Self::metadata`]
+    /// for following the last symlink.
+    pub fn node<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<&Node<SquashfsFileReader>, BackhandError> {
+        self.resolve(path.as_ref(), false)
+    }
+
+    /// Find the node at `path`, following all symlinks
+    ///
+    /// Like `stat`, if `path` is a symlink the node it points to is returned.
+    pub fn metadata<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<&Node<SquashfsFileReader>, BackhandError> {
+        self.resolve(path.as_ref(), true)
+    }
+
+    /// Iterator of the nodes directly inside the directory at `path`, following symlinks
+    pub fn read_dir<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<impl Iterator<Item = &Node<SquashfsFileReader>>, BackhandError> {
+        let dir = self.metadata(path)?;
+        if !matches!(dir.inner, InnerNode::Dir(_)) {
+            return Err(BackhandError::NotADirectory);
+        }
+        let index = self.root.find_index(&dir.fullpath).ok_or(BackhandError::FileNotFound)?;
+        let dir = dir.fullpath.as_path();
+        Ok(self.root.nodes[index + 1..]
+            .iter()
+            .take_while(move |node| node.fullpath.starts_with(dir))
+            .filter(move |node| node.fullpath.parent() == Some(dir)))
+    }
+
+    /// Target of the symlink at `path`
+    pub fn read_link<P: AsRef<Path>>(&self, path: P) -> Result<&Path, BackhandError> {
+        match &self.node(path)?.inner {
+            InnerNode::Symlink(SquashfsSymlink { link }) => Ok(link),
+            _ => Err(BackhandError::NotASymlink),
+        }
+    }
+
+    /// Open the file at `path` for random access, following symlinks
+    ///
+    /// See [`SquashfsSeekFile`] for reading at an offset without decompressing the whole file.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<SquashfsSeekFile<'_, 'b>, BackhandError> {
+        match &self.metadata(path)?.inner {
+            InnerNode::File(file) => Ok(SquashfsSeekFile::new(self, &file.basic)),
+            _ => Err(BackhandError::NotAFile),
+        }
+    }
+
+    /// Absolute path of `path` in the image, with all symlinks, `.` and `..` resolved
+    ///
+    /// Same as POSIX path resolution, `..` following a symlink to a directory is the parent of
+    /// the symlink target, not of the symlink.
+    pub fn canonicalize<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf, BackhandError> {
+        Ok(self.metadata(path)?.fullpath.clone())
+    }
+
+    /// Resolve `path` component by component, following symlinks. The last component is only
+    /// followed if `follow_last`.
+    fn resolve(
+        &self,
+        path: &Path,
+        follow_last: bool,
+    ) -> Result<&Node<SquashfsFileReader>, BackhandError> {
+        // components still to resolve, `None` for `..`
+        let mut pending = VecDeque::new();
+        push_components(&mut pending, path)?;
+
+        let mut resolved = PathBuf::from("/");
+        let mut symlinks = 0;
+        while let Some(component) = pending.pop_front() {
+            let name = match component {
+                Some(name) => name,
+                None => {
+                    resolved.pop();
+                    continue;
+                }
+            };
+            let candidate = resolved.join(name);
+            let node = self.root.find(&candidate).ok_or(BackhandError::FileNotFound)?;
+            let last = pending.is_empty();
+            match &node.inner {
+                InnerNode::Symlink(SquashfsSymlink { link }) if !last || follow_last => {
+                    symlinks += 1;
+                    if symlinks > MAX_SYMLINKS {
+                        return Err(BackhandError::TooManySymlinks);
+                    }
+                    // absolute links start again from the root, relative links from the directory
+                    // containing the symlink
+                    if link.has_root() {
+                        resolved = PathBuf::from("/");
+                    }
+                    let mut link_components = VecDeque::new();
+                    push_components(&mut link_components, link)?;
+                    link_components.append(&mut pending);
+                    pending = link_components;
+                }
+                InnerNode::Dir(_) => resolved = candidate,
+                _ if last => resolved = candidate,
+                _ => return Err(BackhandError::NotADirectory),
+            }
+        }
+
+        self.root.find(&resolved).ok_or(BackhandError::FileNotFound)
+    }
+}
+
+/// Push the components of `path` to `pending`, `None` for `..`
+fn push_components(
+    pending: &mut VecDeque<Option<OsString>>,
+    path: &Path,
+) -> Result<(), BackhandError> {
+    for component in path.components() {
+        match component {
+            Component::Prefix(..) => return Err(BackhandError::InvalidFilePath),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => pending.push_back(None),
+            Component::Normal(name) => pending.push_back(Some(name.to_os_string())),
+        }
+    }
+    Ok(())
 }
 
 /// Filesystem handle for file
@@ -173,11 +435,20 @@ impl<'b> FilesystemReader<'b> {
 pub struct FilesystemReaderFile<'a, 'b> {
     pub(crate) system: &'a FilesystemReader<'b>,
     pub(crate) basic: &'a BasicFile,
+    /// Path of the file in the image, added to the damage found while reading it
+    pub(crate) path: Option<&'a Path>,
 }
 
 impl<'a, 'b> FilesystemReaderFile<'a, 'b> {
     pub fn new(system: &'a FilesystemReader<'b>, basic: &'a BasicFile) -> Self {
-        Self { system, basic }
+        Self { system, basic, path: None }
+    }
+
+    /// Set the `path` of the file in the image, added to the [`Damage`] found while reading
+    /// the file of an image read with [`Squashfs::from_reader_salvage`]
+    pub fn with_path(mut self, path: &'a Path) -> Self {
+        self.path = Some(path);
+        self
     }
 
     /// Create [`SquashfsReadFile`] that impls [`std::io::Read`] from [`FilesystemReaderFile`].
@@ -202,6 +473,12 @@ impl<'a, 'b> FilesystemReaderFile<'a, 'b> {
         self.raw_data_reader().into_reader(buf_read, buf_decompress)
     }
 
+    /// Create [`SquashfsSeekFile`] that impls [`std::io::Read`] and [`std::io::Seek`], only
+    /// decompressing the blocks of the file that are read
+    pub fn seekable(&self) -> SquashfsSeekFile<'a, 'b> {
+        SquashfsSeekFile::new(self.system, self.basic)
+    }
+
     pub fn fragment(&self) -> Option<&'a Fragment> {
         if self.basic.frag_index == 0xffffffff {
             None
@@ -209,12 +486,12 @@ impl<'a, 'b> FilesystemReaderFile<'a, 'b> {
             self.system
                 .fragments
                 .as_ref()
-                .map(|fragments| &fragments[self.basic.frag_index as usize])
+                .and_then(|fragments| fragments.get(self.basic.frag_index as usize))
         }
     }
 
     pub(crate) fn raw_data_reader(&self) -> SquashfsRawData<'a, 'b> {
-        SquashfsRawData::new(Self { system: self.system, basic: self.basic })
+        SquashfsRawData::new(*self)
     }
 }
 
@@ -257,6 +534,14 @@ pub(crate) struct RawDataBlock {
     pub(crate) uncompressed: bool,
 }
 
+/// Position and uncompressed length of a block of a file
+#[derive(Clone, Copy)]
+pub(crate) struct BlockLocation {
+    pos: u64,
+    len: usize,
+    fragment: bool,
+}
+
 pub(crate) struct SquashfsRawData<'a, 'b> {
     pub(crate) file: FilesystemReaderFile<'a, 'b>,
     current_block: BlockIterator<'a>,
@@ -276,33 +561,39 @@ impl<'a, 'b> SquashfsRawData<'a, 'b> {
         block: &BlockFragment<'a>,
     ) -> Result<RawDataBlock, BackhandError> {
         match block {
+            // sparse block, not stored in the image
+            BlockFragment::Block(block) if block.size() == 0 => {
+                let block_len = self.file.system.block_size as usize;
+                let index = self.file.basic.block_sizes.len() - self.current_block.blocks.len() - 1;
+                let file_size = self.file.basic.file_size as usize;
+                let len = file_size.saturating_sub(index * block_len).min(block_len);
+                data.clear();
+                data.resize(len, 0);
+                Ok(RawDataBlock { fragment: false, uncompressed: true })
+            }
             BlockFragment::Block(block) => {
                 let block_size = block.size() as usize;
                 data.resize(block_size, 0);
-                //NOTE: storing/restoring the file-pos is not required at the
-                //moment of writing, but in the future, it may.
-                let mut reader = self.file.system.reader.lock().unwrap();
-                reader.seek(SeekFrom::Start(self.pos))?;
-                reader.read_exact(data)?;
-                self.pos = reader.stream_position()?;
+                // the position of the next block is known, even if this one can't be read
+                let pos = self.pos;
+                self.pos += block_size as u64;
+                self.file.system.reader.read_exact_at(data, pos)?;
                 Ok(RawDataBlock { fragment: false, uncompressed: block.uncompressed() })
             }
             BlockFragment::Fragment(fragment) => {
-                let cache = self.file.system.cache.lock().unwrap();
-                if let Some(cache_bytes) = cache.fragment_cache.get(&fragment.start) {
+                let cached =
+                    self.file.system.cache.lock().unwrap().get(CacheKey::Fragment(fragment.start));
+                if let Some(cache_bytes) = cached {
                     //if in cache, just return the cache, don't read it
-                    let cache_size = cache_bytes.len();
-                    data.resize(cache_size, 0);
-                    data[..cache_size].copy_from_slice(cache_bytes);
+                    data.clear();
+                    data.extend_from_slice(&cache_bytes);
                     //cache is store uncompressed
                     Ok(RawDataBlock { fragment: true, uncompressed: true })
                 } else {
                     //otherwise read and return it
                     let frag_size = fragment.size.size() as usize;
                     data.resize(frag_size, 0);
-                    let mut reader = self.file.system.reader.lock().unwrap();
-                    reader.seek(SeekFrom::Start(fragment.start))?;
-                    reader.read_exact(data)?;
+                    self.file.system.reader.read_exact_at(data, fragment.start)?;
                     Ok(RawDataBlock { fragment: true, uncompressed: fragment.size.uncompressed() })
                 }
             }
@@ -313,16 +604,96 @@ impl<'a, 'b> SquashfsRawData<'a, 'b> {
         self.current_block.next().map(|next| self.read_raw_data(buf, &next))
     }
 
+    /// Same as [`Self::next_block`], but borrowing data blocks directly from the image if it is
+    /// in memory, without copying into `buf`.
+    ///
+    /// Fragments, and blocks of images not in memory, are read into `buf` and `None` is returned
+    /// as the borrowed bytes.
+    pub(crate) fn next_block_borrowed(
+        &mut self,
+        buf: &mut Vec<u8>,
+    ) -> Option<Result<(RawDataBlock, Option<&'a [u8]>), BackhandError>> {
+        let next = self.current_block.next()?;
+        if let BlockFragment::Block(block) = next {
+            let system: &'a FilesystemReader<'b> = self.file.system;
+            let block_size = block.size() as usize;
+            // sparse blocks are not stored in the image
+            let bytes =
+                if block_size == 0 { None } else { system.reader.slice(self.pos, block_size) };
+            if let Some(bytes) = bytes {
+                self.pos += block_size as u64;
+                let bytes = match bytes {
+                    Ok(bytes) => bytes,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                let block = RawDataBlock { fragment: false, uncompressed: block.uncompressed() };
+                return Some(Ok((block, Some(bytes))));
+            }
+        }
+        Some(self.read_raw_data(buf, &next).map(|block| (block, None)))
+    }
+
     fn fragment_range(&self) -> std::ops::Range<usize> {
         let block_len = self.file.system.block_size as usize;
         let block_num = self.file.basic.block_sizes.len();
         let file_size = self.file.basic.file_size as usize;
-        let frag_len = file_size - (block_num * block_len);
+        let frag_len = file_size.saturating_sub(block_num * block_len);
         let frag_start = self.file.basic.block_offset as usize;
         let frag_end = frag_start + frag_len;
         frag_start..frag_end
     }
 
+    /// Location of the next block, used to replace it if it can't be read
+    fn next_location(&self) -> BlockLocation {
+        if self.current_block.blocks.is_empty() {
+            let pos = self.file.fragment().map_or(self.pos, |fragment| fragment.start);
+            return BlockLocation { pos, len: self.fragment_range().len(), fragment: true };
+        }
+        let block_len = self.file.system.block_size as usize;
+        let index = self.file.basic.block_sizes.len() - self.current_block.blocks.len();
+        let file_size = self.file.basic.file_size as usize;
+        let len = file_size.saturating_sub(index * block_len).min(block_len);
+        BlockLocation { pos: self.pos, len, fragment: false }
+    }
+
+    /// In salvage mode, replace the block at `location` that failed with `error` by zeros in
+    /// `output_buf`, and add it to the damage of the file. Otherwise, `error` is returned.
+    fn zeroed(
+        &self,
+        error: BackhandError,
+        location: BlockLocation,
+        output_buf: &mut Vec<u8>,
+    ) -> Result<(), BackhandError> {
+        let system = self.file.system;
+        let structure = if location.fragment { "fragment" } else { "data block" };
+        let path = self.file.path;
+        tolerate::<()>(system.damage.as_ref(), Err(error), location.pos, structure, path)?;
+        output_buf.clear();
+        output_buf.resize(location.len, 0);
+        Ok(())
+    }
+
+    /// Store a copy of the decompressed `bytes` in the cache
+    fn cache(&self, key: CacheKey, bytes: &[u8]) {
+        self.file.system.cache.lock().unwrap().insert(key, Arc::new(bytes.to_vec()));
+    }
+
+    /// Decompress a compressed data block borrowed from the image into `output_buf`
+    pub(crate) fn decompress_block(
+        &self,
+        bytes: &[u8],
+        output_buf: &mut Vec<u8>,
+    ) -> Result<(), BackhandError> {
+        //append to the output_buf is not allowed, it need to be empty
+        assert!(output_buf.is_empty());
+        output_buf.reserve(self.file.system.block_size as usize);
+        self.file.system.kind.inner.compressor.decompress(
+            bytes,
+            output_buf,
+            self.file.system.compressor,
+        )
+    }
+
     pub fn decompress(
         &self,
         data: RawDataBlock,
@@ -344,18 +715,17 @@ impl<'a, 'b> SquashfsRawData<'a, 'b> {
             )?;
             // store the cache, so decompression is not duplicated
             if data.fragment {
-                self.file
-                    .system
-                    .cache
-                    .lock()
-                    .unwrap()
-                    .fragment_cache
-                    .insert(self.file.fragment().unwrap().start, output_buf.clone());
+                let key = CacheKey::Fragment(self.file.fragment().unwrap().start);
+                self.cache(key, output_buf);
             }
         }
         //apply the fragment offset
         if data.fragment {
             let range = self.fragment_range();
+            if range.end > output_buf.len() {
+                error!("file is outside of its fragment");
+                return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            }
             output_buf.drain(range.end..);
             output_buf.drain(..range.start);
         }
@@ -368,7 +738,16 @@ impl<'a, 'b> SquashfsRawData<'a, 'b> {
         buf_decompress: &'a mut Vec<u8>,
     ) -> SquashfsReadFile<'a, 'b> {
         let bytes_available = self.file.basic.file_size as usize;
-        SquashfsReadFile { raw_data: self, buf_read, buf_decompress, last_read: 0, bytes_available }
+        SquashfsReadFile {
+            raw_data: self,
+            buf_read,
+            buf_decompress,
+            borrowed: &[],
+            last_read: 0,
+            bytes_available,
+            read_ahead: 0,
+            ready: VecDeque::new(),
+        }
     }
 }
 
@@ -376,14 +755,34 @@ pub struct SquashfsReadFile<'a, 'b> {
     raw_data: SquashfsRawData<'a, 'b>,
     buf_read: &'a mut Vec<u8>,
     buf_decompress: &'a mut Vec<u8>,
-    //offset of buf_decompress to start reading
+    // uncompressed block borrowed from the image, used instead of buf_decompress if not empty
+    borrowed: &'a [u8],
+    //offset of buf_decompress (or borrowed) to start reading
     last_read: usize,
     bytes_available: usize,
+    // amount of blocks decompressed in parallel, 0 or 1 for one block at a time
+    read_ahead: usize,
+    // blocks already decompressed by read-ahead, not yet moved into buf_decompress
+    ready: VecDeque<Vec<u8>>,
 }
 
 impl<'a, 'b> SquashfsReadFile<'a, 'b> {
+    /// Read ahead and decompress the next `blocks` blocks in parallel using the rayon thread pool.
+    ///
+    /// At most `blocks` decompressed blocks are held in memory at a time, in addition to the
+    /// buffers given to [`FilesystemReaderFile::reader`]. A value of `0` or `1` disables
+    /// read-ahead, decompressing one block at a time on the calling thread (the default).
+    pub fn read_ahead(mut self, blocks: usize) -> Self {
+        self.read_ahead = blocks;
+        self
+    }
+
     fn available(&self) -> &[u8] {
-        &self.buf_decompress[self.last_read..]
+        if self.borrowed.is_empty() {
+            &self.buf_decompress[self.last_read..]
+        } else {
+            &self.borrowed[self.last_read..]
+        }
     }
 
     fn read_available(&mut self, buf: &mut [u8]) -> usize {
@@ -396,13 +795,99 @@ impl<'a, 'b> SquashfsReadFile<'a, 'b> {
     }
 
     fn read_next_block(&mut self) -> Result<(), BackhandError> {
-        let block = match self.raw_data.next_block(self.buf_read) {
-            Some(block) => block?,
+        self.borrowed = &[];
+        if self.read_ahead > 1 {
+            if self.ready.is_empty() {
+                self.read_ahead_blocks()?;
+            }
+            if let Some(block) = self.ready.pop_front() {
+                *self.buf_decompress = block;
+                self.last_read = 0;
+            }
+        } else {
+            self.read_block()?;
+        }
+
+        // in salvage mode, the blocks missing at the end of the file are zeros, such as the
+        // fragment of a damaged fragment table
+        let raw_data = &self.raw_data;
+        let exhausted =
+            raw_data.current_block.blocks.is_empty() && raw_data.current_block.fragment.is_none();
+        if exhausted
+            && self.available().is_empty()
+            && self.bytes_available > 0
+            && raw_data.file.system.damage.is_some()
+        {
+            let location = raw_data.next_location();
+            let error = BackhandError::CorruptedOrInvalidSquashfs;
+            raw_data.zeroed(error, location, self.buf_decompress)?;
+            self.borrowed = &[];
+            self.last_read = 0;
+        }
+        Ok(())
+    }
+
+    /// Read and decompress the next block into `buf_decompress` or `borrowed`
+    fn read_block(&mut self) -> Result<(), BackhandError> {
+        let location = self.raw_data.next_location();
+        let (block, borrowed) = match self.raw_data.next_block_borrowed(self.buf_read) {
+            Some(Ok(block)) => block,
+            Some(Err(error)) => {
+                self.last_read = 0;
+                return self.raw_data.zeroed(error, location, self.buf_decompress);
+            }
             None => return Ok(()),
         };
         self.buf_decompress.clear();
-        self.raw_data.decompress(block, self.buf_read, self.buf_decompress)?;
+        let decompressed = match borrowed {
+            // uncompressed block in memory, read directly from the image
+            Some(bytes) if block.uncompressed => {
+                self.borrowed = bytes;
+                Ok(())
+            }
+            Some(bytes) => self.raw_data.decompress_block(bytes, self.buf_decompress),
+            None => self.raw_data.decompress(block, self.buf_read, self.buf_decompress),
+        };
         self.last_read = 0;
+        if let Err(error) = decompressed {
+            return self.raw_data.zeroed(error, location, self.buf_decompress);
+        }
+        Ok(())
+    }
+
+    /// Read the next `read_ahead` raw blocks, and decompress them in parallel into `ready`
+    fn read_ahead_blocks(&mut self) -> Result<(), BackhandError> {
+        // reading is done serially, as the reader is shared
+        let mut raw_blocks = Vec::with_capacity(self.read_ahead);
+        while raw_blocks.len() < self.read_ahead {
+            let location = self.raw_data.next_location();
+            let mut buf_read = vec![];
+            match self.raw_data.next_block_borrowed(&mut buf_read) {
+                Some(block) => raw_blocks.push((location, block, buf_read)),
+                None => break,
+            }
+        }
+
+        let raw_data = &self.raw_data;
+        let blocks: Vec<Vec<u8>> = raw_blocks
+            .into_par_iter()
+            .map(|(location, block, mut buf_read)| {
+                let mut buf_decompress = vec![];
+                let decompressed = block.and_then(|(block, borrowed)| match borrowed {
+                    Some(bytes) if block.uncompressed => {
+                        buf_decompress.extend_from_slice(bytes);
+                        Ok(())
+                    }
+                    Some(bytes) => raw_data.decompress_block(bytes, &mut buf_decompress),
+                    None => raw_data.decompress(block, &mut buf_read, &mut buf_decompress),
+                });
+                if let Err(error) = decompressed {
+                    raw_data.zeroed(error, location, &mut buf_decompress)?;
+                }
+                Ok(buf_decompress)
+            })
+            .collect::<Result<_, BackhandError>>()?;
+        self.ready.extend(blocks);
         Ok(())
     }
 }
@@ -413,6 +898,8 @@ impl<'a, 'b> Read for SquashfsReadFile<'a, 'b> {
         if self.bytes_available == 0 {
             self.buf_read.clear();
             self.buf_decompress.clear();
+            self.borrowed = &[];
+            self.ready.clear();
             return Ok(0);
         }
         //no data available, read the next block
@@ -424,3 +911,26 @@ impl<'a, 'b> Read for SquashfsReadFile<'a, 'b> {
         Ok(self.read_available(buf))
     }
 }
+
+impl<'a, 'b> BufRead for SquashfsReadFile<'a, 'b> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        // file was fully consumed
+        if self.bytes_available == 0 {
+            return Ok(&[]);
+        }
+        //no data available, read the next block
+        if self.available().is_empty() {
+            self.read_next_block()?;
+        }
+
+        // uncompressed blocks of in memory images are returned without copying
+        let read_len = self.available().len().min(self.bytes_available);
+        Ok(&self.available()[..read_len])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.available().len()).min(self.bytes_available);
+        self.bytes_available -= amt;
+        self.last_read += amt;
+    }
+}
@@ -0,0 +1,306 @@
+//! Random access to the data of a file, decompressing only the blocks that are read
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::reader::FilesystemReader;
+use crate::cache::CacheKey;
+use crate::data::DataSize;
+use crate::error::BackhandError;
+use crate::inode::BasicFile;
+use crate::reader::add_signed;
+
+/// Location of a block of a file, from [`FileBlocks::locate`]
+#[derive(Debug, Clone)]
+pub(crate) struct BlockLocation {
+    /// Index of the block in the file, the block after the last of `block_sizes` being the
+    /// fragment
+    pub index: usize,
+    /// Key of the decompressed block in the cache, `None` for sparse blocks not stored in the image
+    pub key: Option<CacheKey>,
+    /// Start of the block in the image
+    pub start: u64,
+    pub size: DataSize,
+    /// Range of the file data in the decompressed block
+    pub range: Range<usize>,
+}
+
+/// Position in the image of every data block of a file, and the last decompressed block
+///
+/// Doesn't borrow the file or the [`FilesystemReader`], so it can be stored next to them by
+/// handles that own them.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct FileBlocks {
+    /// Start position in the image of each data block of `block_sizes`
+    starts: Vec<u64>,
+    /// Index of the block currently in `buf`
+    current: Option<usize>,
+    /// Decompressed data of the `current` block
+    buf: Vec<u8>,
+}
+
+impl FileBlocks {
+    pub fn new(basic: &BasicFile) -> Self {
+        let mut pos = u64::from(basic.blocks_start);
+        let starts = basic
+            .block_sizes
+            .iter()
+            .map(|block| {
+                let start = pos;
+                pos += u64::from(block.size());
+                start
+            })
+            .collect();
+        Self { starts, ..Self::default() }
+    }
+
+    /// Read the data of the file at `offset` into `buf`, returning the amount of bytes read.
+    ///
+    /// Only the block containing `offset` is decompressed, so less than `buf.len()` bytes are
+    /// read if `buf` crosses the end of that block. `0` is returned at or after the end of the file.
+    pub fn read_at(
+        &mut self,
+        system: &FilesystemReader<'_>,
+        basic: &BasicFile,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, BackhandError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let location = match self.locate(system, basic, offset)? {
+            Some(location) => location,
+            None => return Ok(0),
+        };
+        if self.current != Some(location.index) {
+            let bytes = fetch_block(system, &location)?;
+            self.set_current(&location, &bytes)?;
+        }
+        Ok(self.copy_current(system, offset, buf))
+    }
+
+    /// Location of the block containing `offset`, `None` at or after the end of the file
+    pub fn locate(
+        &self,
+        system: &FilesystemReader<'_>,
+        basic: &BasicFile,
+        offset: u64,
+    ) -> Result<Option<BlockLocation>, BackhandError> {
+        if offset >= u64::from(basic.file_size) {
+            return Ok(None);
+        }
+        let block_size = u64::from(system.block_size);
+        let index = (offset / block_size) as usize;
+
+        // decompressed length, only the last block of the file is smaller than block_size
+        let block_len =
+            (u64::from(basic.file_size) - index as u64 * block_size).min(block_size) as usize;
+
+        let location = match basic.block_sizes.get(index) {
+            // sparse block, not stored in the image
+            Some(block) if block.size() == 0 => {
+                BlockLocation { index, key: None, start: 0, size: *block, range: 0..block_len }
+            }
+            Some(block) => {
+                let start = self.starts[index];
+                let key = Some(CacheKey::Data(start));
+                BlockLocation { index, key, start, size: *block, range: 0..block_len }
+            }
+            None => {
+                // the tail end of the file is stored in a fragment
+                if index != basic.block_sizes.len() || basic.frag_index == 0xffffffff {
+                    return Err(BackhandError::CorruptedOrInvalidSquashfs);
+                }
+                let fragment = system
+                    .fragments
+                    .as_ref()
+                    .and_then(|fragments| fragments.get(basic.frag_index as usize))
+                    .ok_or(BackhandError::CorruptedOrInvalidSquashfs)?;
+                let frag_start = basic.block_offset as usize;
+                BlockLocation {
+                    index,
+                    key: Some(CacheKey::Fragment(fragment.start)),
+                    start: fragment.start,
+                    size: fragment.size,
+                    range: frag_start..frag_start + block_len,
+                }
+            }
+        };
+        Ok(Some(location))
+    }
+
+    /// Returns `true` if the block at `index` is the current decompressed block
+    pub fn is_current(&self, index: usize) -> bool {
+        self.current == Some(index)
+    }
+
+    /// Store the file data of the decompressed block `bytes` as the current block
+    pub fn set_current(
+        &mut self,
+        location: &BlockLocation,
+        bytes: &[u8],
+    ) -> Result<(), BackhandError> {
+        self.current = None;
+        self.buf.clear();
+        let data =
+            bytes.get(location.range.clone()).ok_or(BackhandError::CorruptedOrInvalidSquashfs)?;
+        self.buf.extend_from_slice(data);
+        self.current = Some(location.index);
+        Ok(())
+    }
+
+    /// Copy the data at `offset` of the file from the current block into `buf`, returning the
+    /// amount of bytes copied
+    pub fn copy_current(
+        &self,
+        system: &FilesystemReader<'_>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> usize {
+        let start = (offset % u64::from(system.block_size)) as usize;
+        let bytes = self.buf.get(start..).unwrap_or_default();
+        let len = bytes.len().min(buf.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        len
+    }
+}
+
+/// Decompressed block at `location`, from the cache or read from the image
+pub(crate) fn fetch_block(
+    system: &FilesystemReader<'_>,
+    location: &BlockLocation,
+) -> Result<Arc<Vec<u8>>, BackhandError> {
+    let key = match location.key {
+        Some(key) => key,
+        None => return Ok(Arc::new(vec![0; location.range.end])),
+    };
+    let cached = system.cache.lock().unwrap().get(key);
+    if let Some(bytes) = cached {
+        return Ok(bytes);
+    }
+    let mut raw = vec![0; location.size.size() as usize];
+    system.reader.read_exact_at(&mut raw, location.start)?;
+    decode_block(system, location, raw)
+}
+
+/// Decompress `raw` bytes of the block at `location` if needed, and store them in the cache
+pub(crate) fn decode_block(
+    system: &FilesystemReader<'_>,
+    location: &BlockLocation,
+    raw: Vec<u8>,
+) -> Result<Arc<Vec<u8>>, BackhandError> {
+    let bytes = if location.size.uncompressed() {
+        raw
+    } else {
+        let mut bytes = Vec::with_capacity(system.block_size as usize);
+        system.kind.inner.compressor.decompress(&raw, &mut bytes, system.compressor)?;
+        bytes
+    };
+    let bytes = Arc::new(bytes);
+    if let Some(key) = location.key {
+        system.cache.lock().unwrap().insert(key, bytes.clone());
+    }
+    Ok(bytes)
+}
+
+/// Position after seeking to `pos` from `current` in a file of `len` bytes
+pub(crate) fn seek_position(pos: SeekFrom, current: u64, len: u64) -> io::Result<u64> {
+    let (base, offset) = match pos {
+        SeekFrom::Start(pos) => return Ok(pos),
+        SeekFrom::End(offset) => (len, offset),
+        SeekFrom::Current(offset) => (current, offset),
+    };
+    add_signed(base, offset).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+/// Seekable reader of the data of a file, from [`FilesystemReader::open`] or
+/// [`crate::FilesystemReaderFile::seekable`]
+///
+/// Offsets are mapped to the data block containing them, and only that block (or the fragment
+/// holding the tail end of the file) is decompressed. Decompressed fragments are stored in the
+/// block cache of the [`FilesystemReader`], see [`FilesystemReader::set_cache_size`] and
+/// [`FilesystemReader::set_cache_data_blocks`].
+///
+/// # Example
+/// ```rust,no_run
+/// # use std::fs::File;
+/// # use std::io::{BufReader, Read, Seek, SeekFrom};
+/// # use backhand::FilesystemReader;
+/// # let file = BufReader::new(File::open("image.squashfs").unwrap());
+/// # let filesystem = FilesystemReader::from_reader(file).unwrap();
+/// let mut file = filesystem.open("/usr/bin/busybox").unwrap();
+///
+/// // read the ELF header, then the last 16 bytes, without decompressing the rest of the file
+/// let mut header = [0; 64];
+/// file.read_exact(&mut header).unwrap();
+/// let mut tail = [0; 16];
+/// file.seek(SeekFrom::End(-16)).unwrap();
+/// file.read_exact(&mut tail).unwrap();
+/// ```
+pub struct SquashfsSeekFile<'a, 'b> {
+    system: &'a FilesystemReader<'b>,
+    basic: &'a BasicFile,
+    blocks: FileBlocks,
+    pos: u64,
+}
+
+impl<'a, 'b> SquashfsSeekFile<'a, 'b> {
+    pub(crate) fn new(system: &'a FilesystemReader<'b>, basic: &'a BasicFile) -> Self {
+        Self { system, basic, blocks: FileBlocks::new(basic), pos: 0 }
+    }
+
+    /// Size of the file in bytes
+    pub fn len(&self) -> u64 {
+        self.basic.file_size.into()
+    }
+
+    /// Returns `true` if the file has no data
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Read the data of the file at `offset` into `buf`, without changing the position used by
+    /// [`Read`]. Returns the amount of bytes read, `0` at or after the end of the file.
+    ///
+    /// At most the rest of the block containing `offset` is read, see [`Self::read_exact_at`]
+    /// for filling all of `buf`.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, BackhandError> {
+        self.blocks.read_at(self.system, self.basic, offset, buf)
+    }
+
+    /// Fill `buf` with the data of the file at `offset`, without changing the position used by
+    /// [`Read`]
+    pub fn read_exact_at(&mut self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(offset, buf)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => {
+                    offset += n as u64;
+                    buf = &mut buf[n..];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'b> Read for SquashfsSeekFile<'a, 'b> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.read_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a, 'b> Seek for SquashfsSeekFile<'a, 'b> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(pos, self.pos, self.len())?;
+        Ok(self.pos)
+    }
+}
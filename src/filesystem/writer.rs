@@ -15,7 +15,7 @@ use super::normalize_squashfs_path;
 use crate::compressor::{CompressionOptions, Compressor};
 use crate::data::DataWriter;
 use crate::entry::Entry;
-use crate::error::BackhandError;
+use crate::error::{BackhandError, ErrorContext, ResultExt};
 use crate::filesystem::node::SquashfsSymlink;
 use crate::id::Id;
 use crate::kind::Kind;
@@ -79,13 +79,15 @@ pub struct FilesystemWriter<'a, 'b> {
     /// The log2 of the block size. If the two fields do not agree, the archive is considered corrupted.
     pub(crate) block_log: u16,
     pub(crate) pad_len: u32,
+    /// Amount of threads used for compressing data blocks, `0` for the global rayon pool
+    pub(crate) threads: usize,
 }
 
 impl<'a, 'b> Default for FilesystemWriter<'a, 'b> {
     /// Create default FilesystemWriter
     ///
     /// block_size: [`DEFAULT_BLOCK_SIZE`], compressor: default XZ compression, no nodes,
-    /// kind: [`LE_V4_0`], and mod_time: `0`.
+    /// kind: [`LE_V4_0`], mod_time: `0`, and threads: `0`.
     fn default() -> Self {
         let block_size = DEFAULT_BLOCK_SIZE;
         Self {
@@ -97,6 +99,7 @@ impl<'a, 'b> Default for FilesystemWriter<'a, 'b> {
             root: Nodes::new_root(NodeHeader::default()),
             block_log: (block_size as f32).log2() as u16,
             pad_len: DEFAULT_PAD_LEN,
+            threads: 0,
         }
     }
 }
@@ -194,6 +197,19 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
         self.pad_len = 0;
     }
 
+    /// Set amount of threads used for compressing data blocks when calling [`write`].
+    ///
+    /// Data and fragment blocks are read ahead and compressed in parallel, but are always written
+    /// in the same order, so the resulting image is identical no matter the amount of threads.
+    ///
+    /// A value of `0` uses the global rayon pool (the amount of CPUs, or `RAYON_NUM_THREADS`),
+    /// `1` compresses on the calling thread. Other values build a pool for each [`write`].
+    ///
+    /// Default: `0`
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
     /// Inherit filesystem structure and properties from `reader`
     pub fn from_fs_reader(reader: &'a FilesystemReader<'b>) -> Result<Self, BackhandError> {
         let mut root: Vec<Node<_>> = reader
@@ -227,6 +243,7 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
             id_table: reader.id_table.clone(),
             root: Nodes { nodes: root },
             pad_len: DEFAULT_PAD_LEN,
+            threads: 0,
         })
     }
 
@@ -464,61 +481,58 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
     ) -> Result<Entry<'c>, BackhandError> {
         let node = &self.root.node(node_id).unwrap();
         let filename = node.fullpath.file_name().unwrap_or(OsStr::new("/"));
+        let context = || ErrorContext::Path(node.fullpath.clone());
+        let inode: u32 = node_id.get().try_into().context(context)?;
         //if not a dir, return the entry
-        match &node.inner {
-            InnerNode::File(SquashfsFileWriter::Consumed(filesize, added)) => {
-                return Ok(Entry::file(
-                    filename,
-                    node.header,
-                    node_id.get().try_into().unwrap(),
-                    inode_writer,
-                    *filesize,
-                    added,
-                    superblock,
-                    kind,
-                    id_table,
-                ))
-            }
-            InnerNode::File(_) => unreachable!(),
-            InnerNode::Symlink(symlink) => {
-                return Ok(Entry::symlink(
-                    filename,
-                    node.header,
-                    symlink,
-                    node_id.get().try_into().unwrap(),
-                    inode_writer,
-                    superblock,
-                    kind,
-                    id_table,
-                ))
-            }
-            InnerNode::CharacterDevice(char) => {
-                return Ok(Entry::char(
-                    filename,
-                    node.header,
-                    char,
-                    node_id.get().try_into().unwrap(),
-                    inode_writer,
-                    superblock,
-                    kind,
-                    id_table,
-                ))
-            }
-            InnerNode::BlockDevice(block) => {
-                return Ok(Entry::block_device(
-                    filename,
-                    node.header,
-                    block,
-                    node_id.get().try_into().unwrap(),
-                    inode_writer,
-                    superblock,
-                    kind,
-                    id_table,
-                ))
-            }
+        let entry = match &node.inner {
+            InnerNode::File(SquashfsFileWriter::Consumed(filesize, added)) => Some(Entry::file(
+                filename,
+                node.header,
+                inode,
+                inode_writer,
+                *filesize,
+                added,
+                superblock,
+                kind,
+                id_table,
+            )),
+            InnerNode::File(_) => Some(Err(BackhandError::Unreachable)),
+            InnerNode::Symlink(symlink) => Some(Entry::symlink(
+                filename,
+                node.header,
+                symlink,
+                inode,
+                inode_writer,
+                superblock,
+                kind,
+                id_table,
+            )),
+            InnerNode::CharacterDevice(char) => Some(Entry::char(
+                filename,
+                node.header,
+                char,
+                inode,
+                inode_writer,
+                superblock,
+                kind,
+                id_table,
+            )),
+            InnerNode::BlockDevice(block) => Some(Entry::block_device(
+                filename,
+                node.header,
+                block,
+                inode,
+                inode_writer,
+                superblock,
+                kind,
+                id_table,
+            )),
             // if dir, fall through
-            InnerNode::Dir(_) => (),
+            InnerNode::Dir(_) => None,
         };
+        if let Some(entry) = entry {
+            return entry.context(context);
+        }
 
         // ladies and gentlemen, we have a directory
         let entries: Vec<_> = self
@@ -532,7 +546,7 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
                 self.write_inode_dir(
                     inode_writer,
                     dir_writer,
-                    node_id.get().try_into().unwrap(),
+                    inode,
                     child_id,
                     superblock,
                     kind,
@@ -547,7 +561,7 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
         let block_offset = dir_writer.uncompressed_bytes.len() as u16;
         trace!("WRITING DIR: {block_offset:#02x?}");
         let mut total_size: usize = 3;
-        for dir in Entry::into_dir(entries) {
+        for dir in Entry::into_dir(entries).context(context)? {
             let mut bv = BitVec::new();
             dir.write(&mut bv, kind.inner.type_endian)?;
             let bytes = bv.as_raw_slice();
@@ -558,7 +572,7 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
         let entry = Entry::path(
             filename,
             node.header,
-            node_id.get().try_into().unwrap(),
+            inode,
             children_num,
             parent_node_id,
             inode_writer,
@@ -568,7 +582,8 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
             superblock,
             kind,
             id_table,
-        );
+        )
+        .context(context)?;
         trace!("[{:?}] entries: {:#02x?}", filename, &entry);
         Ok(entry)
     }
@@ -615,8 +630,12 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
             metadata.finalize(w)?;
         }
 
-        let mut data_writer =
-            DataWriter::new(self.kind.inner.compressor, self.fs_compressor, self.block_size);
+        let mut data_writer = DataWriter::new(
+            self.kind.inner.compressor,
+            self.fs_compressor,
+            self.block_size,
+            self.threads,
+        )?;
         let mut inode_writer = MetadataWriter::new(
             self.fs_compressor,
             self.block_size,
@@ -648,7 +667,7 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
         )?;
 
         superblock.root_inode = ((root.start as u64) << 16) | ((root.offset as u64) & 0xffff);
-        superblock.inode_count = self.root.nodes.len().try_into().unwrap();
+        superblock.inode_count = self.root.nodes.len().try_into()?;
         superblock.block_size = self.block_size;
         superblock.block_log = self.block_log;
         superblock.mod_time = self.mod_time;
@@ -670,7 +689,7 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
         info!("Writing Id Lookup Table");
         let (table_position, count) = self.write_lookup_table(w, &self.id_table, Id::SIZE)?;
         superblock.id_table = table_position;
-        superblock.id_count = count.try_into().unwrap();
+        superblock.id_count = count.try_into()?;
 
         info!("Finalize Superblock and End Bytes");
         let bytes_written = self.finalize(w, &mut superblock)?;
@@ -691,25 +710,17 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
         if self.pad_len != 0 {
             // Pad out block_size to 4K
             info!("Writing Padding");
-            let blocks_used: u32 = u32::try_from(superblock.bytes_used).unwrap() / self.pad_len;
-            let total_pad_len = (blocks_used + 1) * self.pad_len;
-            pad_len = total_pad_len - u32::try_from(superblock.bytes_used).unwrap();
+            let blocks_used = superblock.bytes_used / u64::from(self.pad_len);
+            let total_pad_len = (blocks_used + 1) * u64::from(self.pad_len);
+            pad_len = total_pad_len - superblock.bytes_used;
 
             // Write 1K at a time
-            let mut total_written = 0;
-            while w.stream_position()? < (superblock.bytes_used + u64::try_from(pad_len).unwrap()) {
-                let arr = &[0x00; 1024];
-
-                // check if last block to write
-                let len = if (pad_len - total_written) < 1024 {
-                    (pad_len - total_written) % 1024
-                } else {
-                    // else, full 1K
-                    1024
-                };
-
-                w.write_all(&arr[..len.try_into().unwrap()])?;
-                total_written += len;
+            let arr = &[0x00; 1024];
+            let mut remaining = pad_len;
+            while remaining != 0 {
+                let len = remaining.min(1024);
+                w.write_all(&arr[..len as usize])?;
+                remaining -= len;
             }
         }
 
@@ -732,7 +743,7 @@ impl<'a, 'b> FilesystemWriter<'a, 'b> {
 
         //clean any cache, make sure the output is on disk
         w.flush()?;
-        Ok(superblock.bytes_used + u64::try_from(pad_len).unwrap())
+        Ok(superblock.bytes_used + pad_len)
     }
 
     /// For example, writing a fragment table:
@@ -5,10 +5,12 @@ use std::io::Write;
 
 use deku::bitvec::{BitVec, Msb0};
 use deku::prelude::*;
+use tracing::error;
 
 use crate::data::DataSize;
 use crate::dir::DirectoryIndex;
 use crate::entry::Entry;
+use crate::error::BackhandError;
 use crate::kind::Kind;
 use crate::metadata::MetadataWriter;
 use crate::squashfs::SuperBlock;
@@ -35,7 +37,7 @@ impl Inode {
         m_writer: &mut MetadataWriter,
         superblock: &SuperBlock,
         kind: &Kind,
-    ) -> Entry<'a> {
+    ) -> Result<Entry<'a>, BackhandError> {
         let mut bytes = BitVec::<u8, Msb0>::new();
         self.write(
             &mut bytes,
@@ -45,20 +47,19 @@ impl Inode {
                 superblock.block_log,
                 kind.inner.type_endian,
             ),
-        )
-        .unwrap();
+        )?;
         let start = m_writer.metadata_start;
         let offset = m_writer.uncompressed_bytes.len() as u16;
-        m_writer.write_all(bytes.as_raw_slice()).unwrap();
+        m_writer.write_all(bytes.as_raw_slice())?;
 
-        Entry {
+        Ok(Entry {
             start,
             offset,
             inode: self.header.inode_number,
             t: self.id,
             name_size: name.len() as u16 - 1,
             name,
-        }
+        })
     }
 }
 
@@ -170,15 +171,23 @@ pub struct BasicFile {
     pub block_sizes: Vec<DataSize>,
 }
 
-impl From<&ExtendedFile> for BasicFile {
-    fn from(ex_file: &ExtendedFile) -> Self {
-        Self {
-            blocks_start: ex_file.blocks_start as u32,
+/// Fails if `file_size` or `blocks_start` of the [`ExtendedFile`] don't fit in a [`BasicFile`]
+impl TryFrom<&ExtendedFile> for BasicFile {
+    type Error = BackhandError;
+
+    fn try_from(ex_file: &ExtendedFile) -> Result<Self, Self::Error> {
+        let too_large = |e| {
+            let (size, start) = (ex_file.file_size, ex_file.blocks_start);
+            error!("file of size {size:#x?} with blocks at {start:#x?} doesn't fit a basic file");
+            BackhandError::TryFromInt(e)
+        };
+        Ok(Self {
+            blocks_start: u32::try_from(ex_file.blocks_start).map_err(too_large)?,
             frag_index: ex_file.frag_index,
             block_offset: ex_file.block_offset,
-            file_size: ex_file.file_size as u32,
+            file_size: u32::try_from(ex_file.file_size).map_err(too_large)?,
             block_sizes: ex_file.block_sizes.clone(),
-        }
+        })
     }
 }
 
@@ -233,7 +242,7 @@ impl fmt::Debug for BasicSymlink {
 }
 impl BasicSymlink {
     pub fn target(&self) -> String {
-        std::str::from_utf8(&self.target_path).unwrap().to_string()
+        String::from_utf8_lossy(&self.target_path).into_owned()
     }
 }
 
@@ -243,3 +252,27 @@ pub struct BasicDeviceSpecialFile {
     pub link_count: u32,
     pub device_number: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_file_too_large_for_basic_file() {
+        let file = ExtendedFile {
+            blocks_start: 0x60,
+            file_size: 0x1_0000_0000,
+            sparse: 0,
+            link_count: 1,
+            frag_index: 0xffffffff,
+            block_offset: 0,
+            xattr_index: 0xffffffff,
+            block_sizes: vec![],
+        };
+        assert!(BasicFile::try_from(&file).is_err());
+        let file = ExtendedFile { file_size: 0x100, blocks_start: 0x1_0000_0060, ..file };
+        assert!(BasicFile::try_from(&file).is_err());
+        let file = ExtendedFile { blocks_start: 0x60, ..file };
+        assert_eq!(BasicFile::try_from(&file).unwrap().file_size, 0x100);
+    }
+}
@@ -52,6 +52,7 @@
 #[doc = include_str!("../README.md")]
 type _ReadmeTest = ();
 
+mod cache;
 mod compressor;
 mod data;
 mod dir;
@@ -63,28 +64,48 @@ mod fragment;
 mod id;
 mod inode;
 mod kinds;
+mod limits;
 mod metadata;
 mod reader;
+mod salvage;
+mod scan;
 mod squashfs;
+mod verify;
 
+pub use crate::cache::{CacheStats, DEFAULT_CACHE_SIZE};
 pub use crate::data::DataSize;
-pub use crate::error::BackhandError;
+pub use crate::error::{BackhandError, ErrorContext};
 pub use crate::export::Export;
+#[cfg(feature = "tokio")]
+pub use crate::filesystem::async_reader::{AsyncFile, AsyncFilesystemReader, AsyncReadSeek};
+pub use crate::filesystem::extract::{
+    ExtractEvent, ExtractOptions, ExtractReport, ExtractSink, HostSink, MemoryNode, MemorySink,
+    TarSink,
+};
+pub use crate::filesystem::lazy::{LazyDirEntry, LazyFiles, LazyFilesystemReader};
 pub use crate::filesystem::node::{
     InnerNode, Node, NodeHeader, SquashfsBlockDevice, SquashfsCharacterDevice, SquashfsDir,
     SquashfsFileReader, SquashfsFileWriter, SquashfsSymlink,
 };
+pub use crate::filesystem::owned::{OwnedFile, OwnedFilesystemReader};
+pub use crate::filesystem::pattern::Pattern;
 pub use crate::filesystem::reader::{FilesystemReader, FilesystemReaderFile, SquashfsReadFile};
+pub use crate::filesystem::seek::SquashfsSeekFile;
 pub use crate::filesystem::writer::{
     CompressionExtra, ExtraXz, FilesystemCompressor, FilesystemWriter,
 };
 pub use crate::fragment::Fragment;
 pub use crate::id::Id;
 pub use crate::inode::{BasicFile, Inode};
-pub use crate::reader::BufReadSeek;
+pub use crate::limits::ReadLimits;
+pub use crate::metadata::MetadataStore;
+pub use crate::reader::{BufReadSeek, ReadAt};
+pub use crate::salvage::Damage;
+pub use crate::scan::ScanResult;
 pub use crate::squashfs::{
     Squashfs, SuperBlock, DEFAULT_BLOCK_SIZE, DEFAULT_PAD_LEN, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
 };
+pub use crate::verify::{VerifyProblem, VerifyReport};
 
 /// Support the wonderful world of vendor formats
 pub mod kind {
@@ -0,0 +1,85 @@
+//! Limits of the resources used when reading untrusted images
+
+use std::cell::Cell;
+
+use crate::error::BackhandError;
+use crate::squashfs::MAX_BLOCK_SIZE;
+
+/// Limits enforced while reading an image, see [`crate::Squashfs::from_reader_with_limits`]
+///
+/// Counts and sizes read from an image are checked against these limits before allocating, so
+/// crafted images, such as decompression bombs, fail with [`BackhandError::LimitExceeded`]
+/// instead of exhausting memory.
+///
+/// The [`Default`] limits allow all images that can be created with squashfs-tools.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReadLimits {
+    /// Max amount of inodes
+    pub max_inodes: u64,
+    /// Max amount of bytes allocated for the decompressed inode and directory tables and the
+    /// fragment, export and id lookup tables
+    pub max_allocation: u64,
+    /// Max depth of nested directories
+    pub max_dir_depth: usize,
+    /// Max size of a decompressed data block, images with a larger `block_size` are rejected
+    pub max_block_size: u32,
+    /// Max size of a file
+    pub max_file_size: u64,
+}
+
+impl Default for ReadLimits {
+    fn default() -> Self {
+        Self {
+            // inode numbers are u32
+            max_inodes: u64::from(u32::MAX),
+            // 4GiB
+            max_allocation: 0x1_0000_0000,
+            max_dir_depth: 1024,
+            max_block_size: MAX_BLOCK_SIZE,
+            // 1TiB
+            max_file_size: 0x100_0000_0000,
+        }
+    }
+}
+
+impl ReadLimits {
+    /// No limits, except the ones of the image format
+    pub fn unlimited() -> Self {
+        Self {
+            max_inodes: u64::MAX,
+            max_allocation: u64::MAX,
+            max_dir_depth: usize::MAX,
+            max_block_size: MAX_BLOCK_SIZE,
+            max_file_size: u64::MAX,
+        }
+    }
+
+    /// Error if `value` is over `max`
+    pub(crate) fn check(limit: &'static str, value: u64, max: u64) -> Result<(), BackhandError> {
+        if value > max {
+            tracing::error!("{limit} of {value:#x?} is over the limit of {max:#x?}");
+            return Err(BackhandError::LimitExceeded { limit, max });
+        }
+        Ok(())
+    }
+}
+
+/// Bytes allocated while reading an image, checked against [`ReadLimits::max_allocation`]
+pub(crate) struct Allocation {
+    pub limits: ReadLimits,
+    used: Cell<u64>,
+}
+
+impl Allocation {
+    pub fn new(limits: ReadLimits) -> Self {
+        Self { limits, used: Cell::new(0) }
+    }
+
+    /// Account for `bytes` more, failing if over the limit
+    pub fn add(&self, bytes: u64) -> Result<(), BackhandError> {
+        let used = self.used.get().saturating_add(bytes);
+        ReadLimits::check("allocation", used, self.limits.max_allocation)?;
+        self.used.set(used);
+        Ok(())
+    }
+}
@@ -8,6 +8,7 @@ use tracing::{instrument, trace};
 use crate::error::BackhandError;
 use crate::filesystem::writer::FilesystemCompressor;
 use crate::kinds::Kind;
+use crate::limits::ReadLimits;
 use crate::squashfs::SuperBlock;
 
 pub const METADATA_MAXSIZE: usize = 0x2000;
@@ -118,6 +119,58 @@ impl Write for MetadataWriter {
     }
 }
 
+/// Decompressed metadata blocks of a table, indexed by the position of each block relative to the
+/// start of the table
+///
+/// Blocks are stored back to back, so bytes spanning multiple blocks are available as one slice
+/// without copying.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MetadataStore {
+    /// Bytes of all blocks
+    bytes: Vec<u8>,
+    /// Position of each block relative to the table start, and start of its bytes in `bytes`.
+    /// Sorted by position.
+    index: Vec<(u64, usize)>,
+}
+
+impl MetadataStore {
+    /// Add decompressed `block` read from `position` relative to the table start. Blocks must be
+    /// pushed in order of their position.
+    pub(crate) fn push(&mut self, position: u64, block: &[u8]) {
+        debug_assert!(self.index.last().map_or(true, |(last, _)| *last < position));
+        self.index.push((position, self.bytes.len()));
+        self.bytes.extend_from_slice(block);
+    }
+
+    /// Bytes starting at `offset` into the block at `position`, continuing into the following
+    /// blocks until the end of the table
+    ///
+    /// Returns `None` if no block starts at `position`, or `offset` is past the end of the table.
+    pub fn get(&self, position: u64, offset: usize) -> Option<&[u8]> {
+        let i = self.index.binary_search_by_key(&position, |(position, _)| *position).ok()?;
+        let start = self.index[i].1.checked_add(offset)?;
+        self.bytes.get(start..)
+    }
+
+    /// Iterator of the position and bytes of each block
+    pub fn blocks(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.index.iter().enumerate().map(move |(i, (position, start))| {
+            let end = self.index.get(i + 1).map_or(self.bytes.len(), |(_, end)| *end);
+            (*position, &self.bytes[*start..end])
+        })
+    }
+
+    /// Iterator of the position of each block, and the start of its bytes in [`Self::as_bytes`]
+    pub(crate) fn starts(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.index.iter().copied()
+    }
+
+    /// Bytes of all blocks
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
 #[instrument(skip_all)]
 pub fn read_block<R: Read + ?Sized>(
     reader: &mut R,
@@ -133,13 +186,15 @@ pub fn read_block<R: Read + ?Sized>(
 
     let byte_len = len(metadata_len);
     tracing::trace!("len: 0x{:02x?}", byte_len);
+    ReadLimits::check("metadata block size", byte_len.into(), METADATA_MAXSIZE as u64)?;
     let mut buf = vec![0u8; byte_len as usize];
     reader.read_exact(&mut buf)?;
 
     let bytes = if is_compressed(metadata_len) {
         tracing::trace!("compressed");
-        let mut out = Vec::with_capacity(8 * 1024);
+        let mut out = Vec::with_capacity(METADATA_MAXSIZE);
         kind.inner.compressor.decompress(&buf, &mut out, superblock.compressor)?;
+        ReadLimits::check("metadata block size", out.len() as u64, METADATA_MAXSIZE as u64)?;
         out
     } else {
         tracing::trace!("uncompressed");
@@ -1,7 +1,10 @@
 //! Reader traits
 
 use std::collections::HashMap;
-use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::fs::File;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
 
 use deku::bitvec::{BitView, Msb0};
 use deku::prelude::*;
@@ -14,7 +17,9 @@ use crate::fragment::Fragment;
 use crate::id::Id;
 use crate::inode::Inode;
 use crate::kinds::Kind;
-use crate::metadata::METADATA_MAXSIZE;
+use crate::limits::{Allocation, ReadLimits};
+use crate::metadata::{MetadataStore, METADATA_MAXSIZE};
+use crate::salvage::{tolerate, Damage};
 use crate::squashfs::{SuperBlock, NOT_SET};
 use crate::{fragment, metadata};
 
@@ -87,6 +92,221 @@ impl<T: BufRead + Seek + Send> BufReadSeek for T {}
 pub trait WriteSeek: Write + Seek {}
 impl<T: Write + Seek> WriteSeek for T {}
 
+/// Positional reads, without a shared stream position
+///
+/// Unlike [`BufReadSeek`], reads don't require `&mut self`, so multiple threads can read from the
+/// image at the same time without contending on a lock.
+pub trait ReadAt: Send + Sync {
+    /// Read exactly `buf.len()` bytes starting at `offset`
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Total size in bytes
+    fn size(&self) -> io::Result<u64>;
+
+    /// All bytes, if already in memory
+    ///
+    /// When available, data blocks are read directly from the returned slice without copying.
+    fn as_slice(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl ReadAt for File {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        FileExt::read_exact_at(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let bytes = usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.get(start..start.checked_add(buf.len())?))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self[..].read_exact_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self[..].size()
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(&self[..])
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl ReadAt for memmap2::Mmap {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        (**self).read_exact_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        (**self).as_slice()
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        (**self).read_exact_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        (**self).as_slice()
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Box<T> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        (**self).read_exact_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        (**self).as_slice()
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Arc<T> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        (**self).read_exact_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        (**self).as_slice()
+    }
+}
+
+/// Private struct implementing [`Read`] and [`Seek`] over a [`ReadAt`], used for parsing the
+/// image tables
+pub(crate) struct ReadAtCursor<'b> {
+    inner: Arc<dyn ReadAt + 'b>,
+    pos: u64,
+    len: u64,
+}
+
+impl<'b> ReadAtCursor<'b> {
+    pub fn new(inner: Arc<dyn ReadAt + 'b>) -> io::Result<Self> {
+        let len = inner.size()?;
+        Ok(Self { inner, pos: 0, len })
+    }
+}
+
+impl<'b> Read for ReadAtCursor<'b> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let read_len = (buf.len() as u64).min(remaining) as usize;
+        self.inner.read_exact_at(&mut buf[..read_len], self.pos)?;
+        self.pos += read_len as u64;
+        Ok(read_len)
+    }
+}
+
+impl<'b> Seek for ReadAtCursor<'b> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(start) => Some(start),
+            SeekFrom::End(end) => add_signed(self.len, end),
+            SeekFrom::Current(current) => add_signed(self.pos, current),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+pub(crate) fn add_signed(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
+
+/// Source of data and fragment blocks for [`crate::FilesystemReader`]
+pub(crate) enum ReaderBackend<'b> {
+    /// Generic stream, every read locks, seeks and reads
+    Stream(Mutex<Box<dyn BufReadSeek + 'b>>),
+    /// Positional reads at `offset` from the start of `reader`, not requiring a lock
+    Positional { reader: Arc<dyn ReadAt + 'b>, offset: u64 },
+}
+
+impl<'b> ReaderBackend<'b> {
+    /// Read exactly `buf.len()` bytes starting at `pos` from the start of the image
+    pub(crate) fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        match self {
+            Self::Stream(reader) => {
+                let mut reader = reader.lock().unwrap();
+                reader.seek(SeekFrom::Start(pos))?;
+                reader.read_exact(buf)
+            }
+            Self::Positional { reader, offset } => {
+                let pos = offset.checked_add(pos).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "position overflows image")
+                })?;
+                reader.read_exact_at(buf, pos)
+            }
+        }
+    }
+
+    /// Borrow `len` bytes starting at `pos` from the start of the image, if the image is in memory
+    pub(crate) fn slice(&self, pos: u64, len: usize) -> Option<io::Result<&[u8]>> {
+        match self {
+            Self::Stream(_) => None,
+            Self::Positional { reader, offset } => {
+                let bytes = reader.as_slice()?;
+                let slice = offset
+                    .checked_add(pos)
+                    .and_then(|start| usize::try_from(start).ok())
+                    .and_then(|start| bytes.get(start..start.checked_add(len)?))
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof));
+                Some(slice)
+            }
+        }
+    }
+}
+
 impl<T: BufReadSeek> SquashFsReader for T {}
 
 /// Squashfs data extraction methods implemented over [`Read`] and [`Seek`]
@@ -96,62 +316,88 @@ pub trait SquashFsReader: BufReadSeek {
         &mut self,
         superblock: &SuperBlock,
         kind: &Kind,
+        alloc: &Allocation,
     ) -> Result<FxHashMap<u32, Inode>, BackhandError> {
-        self.seek(SeekFrom::Start(superblock.inode_table))?;
-
         // The directory inodes store the total, uncompressed size of the entire listing, including headers.
         // Using this size, a SquashFS reader can determine if another header with further entries
         // should be following once it reaches the end of a run.
+        let max_inodes = alloc.limits.max_inodes;
+        ReadLimits::check("inode count", u64::from(superblock.inode_count), max_inodes)?;
+        self.seek(SeekFrom::Start(superblock.inode_table))?;
 
-        let mut ret_bytes = Vec::with_capacity(METADATA_MAXSIZE);
-
-        let mut metadata_offsets = vec![];
-        let mut ret_vec = HashMap::default();
-        let start = self.stream_position()?;
-
+        // only the bytes of the inodes not parsed yet are kept, not the whole inode table
+        let mut bytes = Vec::with_capacity(METADATA_MAXSIZE);
+        // start in `bytes` and position in the image of the blocks in `bytes`
+        let mut blocks: Vec<(usize, u64)> = vec![];
+        let mut inodes = HashMap::default();
         while self.stream_position()? < superblock.dir_table {
-            trace!("offset: {:02x?}", self.stream_position());
-            metadata_offsets.push(self.stream_position()? - start);
-            // parse into metadata
-            let mut bytes = metadata::read_block(self, superblock, kind)?;
-
-            // parse as many inodes as you can
-            ret_bytes.append(&mut bytes);
-
-            let mut input_bits = ret_bytes.view_bits::<deku::bitvec::Msb0>();
-            while !input_bits.is_empty() {
-                match Inode::read(
-                    input_bits,
-                    (
-                        superblock.bytes_used,
-                        superblock.block_size,
-                        superblock.block_log,
-                        kind.inner.type_endian,
-                    ),
-                ) {
-                    Ok((rest, inode)) => {
-                        // Push the new Inode to the return, with the position this was read from
-                        ret_vec.insert(inode.header.inode_number, inode);
-                        input_bits = rest;
-                    }
-                    Err(e) => {
-                        if let DekuError::Incomplete(_) = e {
-                            // try next block, inodes can span multiple blocks!
-                            break;
-                        } else {
-                            error!("{e}");
-                            return Err(BackhandError::Deku(e));
-                        }
-                    }
-                }
+            let position = self.stream_position()?;
+            trace!("offset: {:02x?}", position);
+            let block = metadata::read_block(self, superblock, kind)?;
+            alloc.add(block.len() as u64)?;
+            blocks.push((bytes.len(), position));
+            bytes.extend_from_slice(&block);
+
+            // inodes can span multiple blocks, the rest is parsed with the next block
+            let position = |offset| block_position(&blocks, offset);
+            let parsed =
+                parse_inodes(&bytes, superblock, kind, max_inodes, None, position, &mut inodes)?;
+            bytes.drain(..parsed);
+            let first = blocks.iter().rposition(|(start, _)| *start <= parsed).unwrap_or(0);
+            blocks.drain(..first);
+            for (start, _) in &mut blocks {
+                *start = start.saturating_sub(parsed);
             }
-
-            // save leftover bits to new bits to leave for the next metadata block
-            // this is safe, input_bits is always byte aligned
-            ret_bytes.drain(..(ret_bytes.len() - (input_bits.len() / 8)));
         }
 
-        Ok(ret_vec)
+        Ok(inodes)
+    }
+
+    /// Same as [`Self::inodes`], but skipping damaged blocks of the inode table, adding them to
+    /// `damage`
+    ///
+    /// Only the inodes before the first damaged block are parsed, as the position of the inodes
+    /// following it is unknown. The inode table is returned to find the other inodes by their
+    /// reference from the directory table.
+    fn inodes_salvage(
+        &mut self,
+        superblock: &SuperBlock,
+        kind: &Kind,
+        alloc: &Allocation,
+        damage: &Mutex<Vec<Damage>>,
+    ) -> Result<(FxHashMap<u32, Inode>, MetadataStore), BackhandError> {
+        let max_inodes = alloc.limits.max_inodes;
+        ReadLimits::check("inode count", u64::from(superblock.inode_count), max_inodes)?;
+        let damaged = damage.lock().unwrap().len();
+        let inode_table = self.metadata_store_salvage(
+            superblock,
+            superblock.inode_table,
+            superblock.dir_table,
+            kind,
+            alloc,
+            "inode table",
+            damage,
+        )?;
+
+        // blocks are contiguous until the first damaged one
+        let first_damaged = damage.lock().unwrap()[damaged..]
+            .iter()
+            .map(|damage| damage.offset - superblock.inode_table)
+            .min();
+        let len = inode_table
+            .blocks()
+            .take_while(|(position, _)| first_damaged.map_or(true, |first| *position < first))
+            .map(|(_, bytes)| bytes.len())
+            .sum::<usize>();
+        let bytes = &inode_table.as_bytes()[..len];
+        let blocks: Vec<(usize, u64)> = inode_table
+            .starts()
+            .map(|(position, start)| (start, superblock.inode_table + position))
+            .collect();
+        let position = |offset| block_position(&blocks, offset);
+        let mut inodes = HashMap::default();
+        parse_inodes(bytes, superblock, kind, max_inodes, Some(damage), position, &mut inodes)?;
+        Ok((inodes, inode_table))
     }
 
     /// Extract the root `Inode` as a `BasicDirectory`
@@ -221,17 +467,80 @@ pub trait SquashFsReader: BufReadSeek {
         superblock: &SuperBlock,
         end_ptr: u64,
         kind: &Kind,
-    ) -> Result<Vec<(u64, Vec<u8>)>, BackhandError> {
-        let seek = superblock.dir_table;
-        self.seek(SeekFrom::Start(seek))?;
-        let mut all_bytes = vec![];
-        while self.stream_position()? != end_ptr {
-            let metadata_start = self.stream_position()?;
+        alloc: &Allocation,
+    ) -> Result<MetadataStore, BackhandError> {
+        self.metadata_store(superblock, superblock.dir_table, end_ptr, kind, alloc)
+    }
+
+    /// Parse all `Metadata` blocks from `start` until `end` into a [`MetadataStore`], indexed by
+    /// their position relative to `start`
+    ///
+    /// The decompressed blocks are accounted in `alloc`.
+    #[instrument(skip_all)]
+    fn metadata_store(
+        &mut self,
+        superblock: &SuperBlock,
+        start: u64,
+        end: u64,
+        kind: &Kind,
+        alloc: &Allocation,
+    ) -> Result<MetadataStore, BackhandError> {
+        self.seek(SeekFrom::Start(start))?;
+        let mut store = MetadataStore::default();
+        while self.stream_position()? < end {
+            let position = self.stream_position()? - start;
+            trace!("offset: {:02x?}", position);
             let bytes = metadata::read_block(self, superblock, kind)?;
-            all_bytes.push((metadata_start - seek, bytes));
+            alloc.add(bytes.len() as u64)?;
+            store.push(position, &bytes);
         }
 
-        Ok(all_bytes)
+        Ok(store)
+    }
+
+    /// Same as [`Self::metadata_store`], but skipping blocks that can't be read, adding them to
+    /// `damage` as `structure`
+    ///
+    /// Reading stops at the first block with an unreadable length, as the position of the
+    /// following blocks is unknown.
+    #[instrument(skip_all)]
+    #[allow(clippy::too_many_arguments)]
+    fn metadata_store_salvage(
+        &mut self,
+        superblock: &SuperBlock,
+        start: u64,
+        end: u64,
+        kind: &Kind,
+        alloc: &Allocation,
+        structure: &'static str,
+        damage: &Mutex<Vec<Damage>>,
+    ) -> Result<MetadataStore, BackhandError> {
+        self.seek(SeekFrom::Start(start))?;
+        let mut store = MetadataStore::default();
+        let mut block_start = start;
+        while block_start < end {
+            let block = metadata::read_block(self, superblock, kind);
+            if let Some(bytes) = tolerate(Some(damage), block, block_start, structure, None)? {
+                alloc.add(bytes.len() as u64)?;
+                store.push(block_start - start, &bytes);
+            } else {
+                // skip the damaged block, if its length can still be read
+                self.seek(SeekFrom::Start(block_start))?;
+                let mut buf = [0u8; 2];
+                if self.read_exact(&mut buf).is_err() {
+                    break;
+                }
+                let (_, metadata_len) = u16::read(buf.view_bits::<Msb0>(), kind.inner.data_endian)?;
+                let byte_len = metadata::len(metadata_len);
+                if usize::from(byte_len) > METADATA_MAXSIZE {
+                    break;
+                }
+                self.seek(SeekFrom::Current(i64::from(byte_len)))?;
+            }
+            block_start = self.stream_position()?;
+        }
+
+        Ok(store)
     }
 
     /// Parse Fragment Table
@@ -240,6 +549,7 @@ pub trait SquashFsReader: BufReadSeek {
         &mut self,
         superblock: &SuperBlock,
         kind: &Kind,
+        alloc: &Allocation,
     ) -> Result<Option<(u64, Vec<Fragment>)>, BackhandError> {
         if superblock.frag_count == 0 || superblock.frag_table == NOT_SET {
             return Ok(None);
@@ -249,6 +559,7 @@ pub trait SquashFsReader: BufReadSeek {
             superblock.frag_table,
             u64::from(superblock.frag_count) * fragment::SIZE as u64,
             kind,
+            alloc,
         )?;
 
         Ok(Some((ptr, table)))
@@ -260,11 +571,12 @@ pub trait SquashFsReader: BufReadSeek {
         &mut self,
         superblock: &SuperBlock,
         kind: &Kind,
+        alloc: &Allocation,
     ) -> Result<Option<(u64, Vec<Export>)>, BackhandError> {
         if superblock.nfs_export_table_exists() && superblock.export_table != NOT_SET {
             let ptr = superblock.export_table;
             let count = (superblock.inode_count as f32 / 1024_f32).ceil() as u64;
-            let (ptr, table) = self.lookup_table::<Export>(superblock, ptr, count, kind)?;
+            let (ptr, table) = self.lookup_table::<Export>(superblock, ptr, count, kind, alloc)?;
             Ok(Some((ptr, table)))
         } else {
             Ok(None)
@@ -277,10 +589,11 @@ pub trait SquashFsReader: BufReadSeek {
         &mut self,
         superblock: &SuperBlock,
         kind: &Kind,
+        alloc: &Allocation,
     ) -> Result<(u64, Vec<Id>), BackhandError> {
         let ptr = superblock.id_table;
         let count = superblock.id_count as u64;
-        let (ptr, table) = self.lookup_table::<Id>(superblock, ptr, count, kind)?;
+        let (ptr, table) = self.lookup_table::<Id>(superblock, ptr, count, kind, alloc)?;
         Ok((ptr, table))
     }
 
@@ -292,7 +605,10 @@ pub trait SquashFsReader: BufReadSeek {
         seek: u64,
         size: u64,
         kind: &Kind,
+        alloc: &Allocation,
     ) -> Result<(u64, Vec<T>), BackhandError> {
+        alloc.add(size)?;
+
         // find the pointer at the initial offset
         trace!("seek: {:02x?}", seek);
         self.seek(SeekFrom::Start(seek))?;
@@ -340,3 +656,60 @@ pub trait SquashFsReader: BufReadSeek {
         Ok(ret_vec)
     }
 }
+
+/// Parse the inodes of the inode table `bytes` into `inodes`, returning the amount of bytes
+/// parsed. The bytes of an inode continuing past the end of `bytes` are not parsed.
+///
+/// `position` returns the position in the image of the metadata block containing an offset of
+/// `bytes`, used to report inodes that can't be parsed.
+///
+/// When salvaging (`damage` is `Some`), an inode that can't be parsed ends the table instead of
+/// returning an error.
+fn parse_inodes(
+    bytes: &[u8],
+    superblock: &SuperBlock,
+    kind: &Kind,
+    max_inodes: u64,
+    damage: Option<&Mutex<Vec<Damage>>>,
+    position: impl Fn(usize) -> u64,
+    inodes: &mut FxHashMap<u32, Inode>,
+) -> Result<usize, BackhandError> {
+    let mut input_bits = bytes.view_bits::<deku::bitvec::Msb0>();
+    while !input_bits.is_empty() {
+        match Inode::read(
+            input_bits,
+            (
+                superblock.bytes_used,
+                superblock.block_size,
+                superblock.block_log,
+                kind.inner.type_endian,
+            ),
+        ) {
+            Ok((rest, inode)) => {
+                // Push the new Inode to the return, with the position this was read from
+                inodes.insert(inode.header.inode_number, inode);
+                ReadLimits::check("inode count", inodes.len() as u64, max_inodes)?;
+                input_bits = rest;
+            }
+            // inode continuing in the next block
+            Err(DekuError::Incomplete(_)) => break,
+            Err(e) => {
+                error!("{e}");
+                // metadata block containing the start of the inode
+                let position = position(bytes.len() - input_bits.len() / 8);
+                tolerate::<()>(damage, Err(BackhandError::Deku(e)), position, "inode table", None)?;
+                return Ok(bytes.len());
+            }
+        }
+    }
+
+    // this is safe, input_bits is always byte aligned
+    Ok(bytes.len() - input_bits.len() / 8)
+}
+
+/// Position of the block containing `offset`, from the start in the bytes and position in the
+/// image of each block
+fn block_position(blocks: &[(usize, u64)], offset: usize) -> u64 {
+    let i = blocks.partition_point(|(start, _)| *start <= offset);
+    blocks[i.saturating_sub(1)].1
+}
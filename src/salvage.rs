@@ -0,0 +1,66 @@
+//! Best-effort reading of damaged images, see [`Squashfs::from_reader_salvage`]
+//!
+//! [`Squashfs::from_reader_salvage`]: crate::Squashfs::from_reader_salvage
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use tracing::warn;
+
+use crate::error::BackhandError;
+use crate::metadata::MetadataStore;
+
+/// Part of an image that couldn't be read in salvage mode, and was skipped
+#[derive(Debug)]
+pub struct Damage {
+    /// Byte offset in the image of the unreadable structure
+    pub offset: u64,
+    /// Name of the unreadable structure, such as `"directory table"`
+    pub structure: &'static str,
+    /// Node that is missing, or is missing children or data because of it
+    pub path: Option<PathBuf>,
+    pub error: BackhandError,
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}: ", path.display())?;
+        }
+        write!(f, "{:#010x} {}: {}", self.offset, self.structure, self.error)
+    }
+}
+
+/// State of a [`crate::Squashfs`] read in salvage mode
+pub(crate) struct Salvage {
+    /// Inode table, used to find the inodes after a damaged block by their reference
+    pub(crate) inode_table: MetadataStore,
+    pub(crate) damage: Mutex<Vec<Damage>>,
+}
+
+/// When salvaging (`damage` is `Some`), record the error of `result` in `damage` and return
+/// `Ok(None)`. Otherwise the error is returned.
+///
+/// Exceeded limits are never tolerated.
+pub(crate) fn tolerate<T>(
+    damage: Option<&Mutex<Vec<Damage>>>,
+    result: Result<T, BackhandError>,
+    offset: u64,
+    structure: &'static str,
+    path: Option<&Path>,
+) -> Result<Option<T>, BackhandError> {
+    let error = match result {
+        Ok(t) => return Ok(Some(t)),
+        Err(error) => error,
+    };
+    match damage {
+        Some(damage) if !matches!(error.root_cause(), BackhandError::LimitExceeded { .. }) => {
+            warn!("skipping {structure} at {offset:#x?}: {error}");
+            let path = path.map(Path::to_path_buf);
+            damage.lock().unwrap().push(Damage { offset, structure, path, error });
+            Ok(None)
+        }
+        _ => Err(error),
+    }
+}
@@ -0,0 +1,167 @@
+//! Find images embedded in a blob, see [`Squashfs::scan`]
+
+use std::io::BufReader;
+use std::sync::Arc;
+
+use deku::bitvec::{BitView, Msb0};
+use deku::prelude::*;
+use tracing::{info, trace};
+
+use crate::error::BackhandError;
+use crate::inode::InodeInner;
+use crate::kinds::{Kind, AVM_BE_V4_0, BE_V4_0, LE_V4_0};
+use crate::reader::{ReadAt, ReadAtCursor, SquashFsReader, SquashfsReaderWithOffset};
+use crate::squashfs::{Squashfs, SuperBlock, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE, NOT_SET};
+
+/// Size of the chunks of the blob searched for magics
+const CHUNK_SIZE: usize = 0x10_0000;
+
+/// Image found by [`Squashfs::scan`]
+#[derive(Debug)]
+pub struct ScanResult {
+    /// Byte offset of the superblock in the blob
+    pub offset: u64,
+    pub kind: Kind,
+    /// Name of `kind`, as accepted by [`Kind::from_target`]
+    pub name: &'static str,
+    /// Size of the image, from the superblock
+    pub bytes_used: u64,
+}
+
+impl<'b> Squashfs<'b> {
+    /// Find all images in `reader`, such as a firmware update or a flash dump
+    ///
+    /// Every occurrence of the magic of a known kind is a candidate. A candidate is returned if
+    /// its superblock has a valid block size, all its tables are inside the image and the blob,
+    /// and its root inode is a directory. A big endian image can be returned for both the
+    /// `be_v4_0` and `avm_be_v4_0` kinds.
+    ///
+    /// Returns `Err` only if `reader` can't be read.
+    pub fn scan<'a, R: ReadAt + 'a>(reader: R) -> Result<Vec<ScanResult>, BackhandError> {
+        let reader: Arc<dyn ReadAt + 'a> = Arc::new(reader);
+        let kinds = kinds();
+
+        let len = reader.size()?;
+        let mut found = vec![];
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut pos = 0;
+        while pos < len {
+            let read_len = (len - pos).min(CHUNK_SIZE as u64) as usize;
+            let chunk = &mut chunk[..read_len];
+            reader.read_exact_at(chunk, pos)?;
+            for (i, window) in chunk.windows(4).enumerate() {
+                for (name, kind) in &kinds {
+                    if window != kind.magic() {
+                        continue;
+                    }
+                    let offset = pos + i as u64;
+                    match validate(&reader, offset, len, kind) {
+                        Ok(bytes_used) => {
+                            info!("found {name} image at {offset:#x}");
+                            let kind = Kind::from_kind(kind);
+                            found.push(ScanResult { offset, kind, name: *name, bytes_used });
+                        }
+                        Err(e) => trace!("invalid {name} image at {offset:#x}: {e}"),
+                    }
+                }
+            }
+            if pos + read_len as u64 >= len {
+                break;
+            }
+            // overlap the chunks, to find the magics crossing them
+            pos += read_len as u64 - 3;
+        }
+
+        Ok(found)
+    }
+}
+
+/// Known kinds, by name
+fn kinds() -> [(&'static str, Kind); 3] {
+    [
+        ("le_v4_0", Kind { inner: Arc::new(LE_V4_0) }),
+        ("be_v4_0", Kind { inner: Arc::new(BE_V4_0) }),
+        ("avm_be_v4_0", Kind { inner: Arc::new(AVM_BE_V4_0) }),
+    ]
+}
+
+/// Find a valid image of any kind at the start of `reader`, validated like [`Squashfs::scan`]
+pub(crate) fn detect<'a>(reader: &Arc<dyn ReadAt + 'a>) -> Option<ScanResult> {
+    let len = reader.size().ok()?;
+    let mut magic = [0u8; 4];
+    reader.read_exact_at(&mut magic, 0).ok()?;
+    kinds().into_iter().find_map(|(name, kind)| {
+        if magic != kind.magic() {
+            return None;
+        }
+        match validate(reader, 0, len, &kind) {
+            Ok(bytes_used) => Some(ScanResult { offset: 0, kind, name, bytes_used }),
+            Err(e) => {
+                trace!("invalid {name} image: {e}");
+                None
+            }
+        }
+    })
+}
+
+/// Check the image of `kind` at `offset` in the blob of size `len`, returning its `bytes_used`
+fn validate<'a>(
+    reader: &Arc<dyn ReadAt + 'a>,
+    offset: u64,
+    len: u64,
+    kind: &Kind,
+) -> Result<u64, BackhandError> {
+    let mut buf = [0u8; 96];
+    reader.read_exact_at(&mut buf, offset)?;
+    let (_, superblock) = SuperBlock::read(
+        buf.view_bits::<Msb0>(),
+        (
+            kind.inner.magic,
+            kind.inner.version_major,
+            kind.inner.version_minor,
+            kind.inner.type_endian,
+        ),
+    )?;
+
+    let block_size = superblock.block_size;
+    if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+        || !block_size.is_power_of_two()
+        || u32::from(superblock.block_log) != block_size.trailing_zeros()
+    {
+        return Err(BackhandError::InvalidField { field: "block_size", value: block_size.into() });
+    }
+
+    let bytes_used = superblock.bytes_used;
+    if bytes_used > len - offset {
+        return Err(BackhandError::InvalidField { field: "bytes_used", value: bytes_used });
+    }
+    let tables = [
+        ("inode_table", superblock.inode_table),
+        ("dir_table", superblock.dir_table),
+        ("id_table", superblock.id_table),
+    ];
+    let optional_tables = [
+        ("frag_table", superblock.frag_table),
+        ("export_table", superblock.export_table),
+        ("xattr_table", superblock.xattr_table),
+    ];
+    let invalid_table = tables
+        .into_iter()
+        .chain(optional_tables.into_iter().filter(|(_, table)| *table != NOT_SET))
+        .find(|(_, table)| *table >= bytes_used);
+    if let Some((field, value)) = invalid_table {
+        return Err(BackhandError::InvalidField { field, value });
+    }
+    if superblock.inode_table >= superblock.dir_table {
+        let value = superblock.inode_table;
+        return Err(BackhandError::InvalidField { field: "inode_table", value });
+    }
+
+    let stream = BufReader::new(ReadAtCursor::new(reader.clone())?);
+    let mut stream = SquashfsReaderWithOffset::new(stream, offset)?;
+    let root_inode = stream.root_inode(&superblock, kind)?;
+    match root_inode.inner {
+        InodeInner::BasicDirectory(_) | InodeInner::ExtendedDirectory(_) => Ok(bytes_used),
+        inner => Err(BackhandError::UnexpectedInode(inner)),
+    }
+}
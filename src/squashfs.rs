@@ -1,25 +1,32 @@
 //! Read from on-disk image
 
+use std::borrow::Cow;
 use std::ffi::OsString;
-use std::io::{Seek, SeekFrom};
+use std::io::{BufReader, Seek, SeekFrom};
 use std::os::unix::prelude::OsStringExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
 
 use deku::bitvec::{BitVec, BitView, Msb0};
 use deku::prelude::*;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use tracing::{error, info, instrument, trace};
 
+use crate::cache::Cache;
 use crate::compressor::{CompressionOptions, Compressor};
-use crate::dir::Dir;
-use crate::error::BackhandError;
+use crate::dir::{Dir, DirEntry};
+use crate::error::{table, BackhandError, ErrorContext, ResultExt};
 use crate::filesystem::node::{InnerNode, Nodes};
 use crate::fragment::Fragment;
-use crate::inode::{Inode, InodeId, InodeInner};
+use crate::inode::{BasicFile, Inode, InodeHeader, InodeId, InodeInner};
 use crate::kinds::{Kind, LE_V4_0};
-use crate::reader::{BufReadSeek, SquashFsReader, SquashfsReaderWithOffset};
+use crate::limits::{Allocation, ReadLimits};
+use crate::metadata::MetadataStore;
+use crate::reader::{
+    BufReadSeek, ReadAt, ReadAtCursor, ReaderBackend, SquashFsReader, SquashfsReaderWithOffset,
+};
+use crate::salvage::{tolerate, Salvage};
 use crate::{
     metadata, Export, FilesystemReader, Id, Node, NodeHeader, SquashfsBlockDevice,
     SquashfsCharacterDevice, SquashfsDir, SquashfsFileReader, SquashfsSymlink,
@@ -140,6 +147,11 @@ impl SuperBlock {
     pub fn compressor_options_are_present(&self) -> bool {
         self.flags & Flags::CompressorOptionsArePresent as u16 != 0
     }
+
+    /// flag value
+    pub fn ids_uncompressed(&self) -> bool {
+        self.flags & Flags::IdsStoredUncompressed as u16 != 0
+    }
 }
 
 impl SuperBlock {
@@ -183,13 +195,7 @@ pub(crate) enum Flags {
     XattrsAreStoredUncompressed = 0b0000_0001_0000_0000,
     NoXattrsInArchive           = 0b0000_0010_0000_0000,
     CompressorOptionsArePresent = 0b0000_0100_0000_0000,
-}
-
-#[derive(Default, Clone, Debug)]
-pub(crate) struct Cache {
-    /// The first time a fragment bytes is read, those bytes are added to this map with the key
-    /// representing the start position
-    pub(crate) fragment_cache: FxHashMap<u64, Vec<u8>>,
+    IdsStoredUncompressed       = 0b0000_1000_0000_0000,
 }
 
 /// Squashfs Image initial read information
@@ -204,8 +210,8 @@ pub struct Squashfs<'b> {
     pub inodes: FxHashMap<u32, Inode>,
     /// Root Inode
     pub root_inode: Inode,
-    /// Bytes containing Directory Table
-    pub dir_blocks: Vec<(u64, Vec<u8>)>,
+    /// Decompressed blocks of Directory Table
+    pub dir_blocks: MetadataStore,
     /// Fragments Lookup Table
     pub fragments: Option<Vec<Fragment>>,
     /// Export Lookup Table
@@ -214,6 +220,12 @@ pub struct Squashfs<'b> {
     pub id: Vec<Id>,
     //file reader
     file: Box<dyn BufReadSeek + 'b>,
+    // positional reader and offset of image, if created from a `ReadAt`
+    read_at: Option<(Arc<dyn ReadAt + 'b>, u64)>,
+    // limits checked while reading, also while creating the filesystem tree
+    limits: ReadLimits,
+    // damage found while reading in salvage mode, with the inode table
+    salvage: Option<Salvage>,
 }
 
 impl<'b> Squashfs<'b> {
@@ -303,6 +315,19 @@ impl<'b> Squashfs<'b> {
         reader: impl BufReadSeek + 'b,
         offset: u64,
         kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        Self::from_reader_with_limits(reader, offset, kind, ReadLimits::default())
+    }
+
+    /// Same as [`Self::from_reader_with_offset_and_kind`], but with custom `limits` instead of
+    /// the [`ReadLimits::default`] ones
+    ///
+    /// Use this for images from untrusted sources, to bound the memory used while reading them.
+    pub fn from_reader_with_limits(
+        reader: impl BufReadSeek + 'b,
+        offset: u64,
+        kind: Kind,
+        limits: ReadLimits,
     ) -> Result<Self, BackhandError> {
         let reader: Box<dyn BufReadSeek + 'b> = if offset == 0 {
             Box::new(reader)
@@ -310,73 +335,174 @@ impl<'b> Squashfs<'b> {
             let reader = SquashfsReaderWithOffset::new(reader, offset)?;
             Box::new(reader)
         };
-        Self::inner_from_reader_with_offset_and_kind(reader, kind)
+        Self::inner_from_reader_with_offset_and_kind(reader, kind, limits, false)
+    }
+
+    /// Same as [`Self::from_reader_with_offset_and_kind`], but reading from a [`ReadAt`].
+    ///
+    /// The resulting [`FilesystemReader`] from [`Self::into_filesystem_reader`] reads file data
+    /// with positional reads, allowing concurrent reads of files without contending on a lock.
+    pub fn from_read_at_with_offset_and_kind(
+        reader: impl ReadAt + 'b,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        Self::from_read_at_with_limits(reader, offset, kind, ReadLimits::default())
+    }
+
+    /// Same as [`Self::from_read_at_with_offset_and_kind`], but with custom `limits`
+    pub fn from_read_at_with_limits(
+        reader: impl ReadAt + 'b,
+        offset: u64,
+        kind: Kind,
+        limits: ReadLimits,
+    ) -> Result<Self, BackhandError> {
+        let reader: Arc<dyn ReadAt + 'b> = Arc::new(reader);
+        let stream = BufReader::new(ReadAtCursor::new(reader.clone())?);
+        let mut squashfs = Self::from_reader_with_limits(stream, offset, kind, limits)?;
+        squashfs.read_at = Some((reader, offset));
+        Ok(squashfs)
+    }
+
+    /// Same as [`Self::from_reader_with_offset_and_kind`], but reading as much as possible of a
+    /// damaged image instead of failing at the first error
+    ///
+    /// Metadata blocks, directory listings and inodes that can't be read are skipped, and
+    /// recorded as [`Damage`] of the affected nodes, see [`FilesystemReader::take_damage`].
+    /// Data blocks that can't be read are replaced with zeros by [`crate::SquashfsReadFile`].
+    ///
+    /// Only an unreadable superblock or root inode, or exceeding the [`ReadLimits::default`], is
+    /// an error.
+    ///
+    /// [`Damage`]: crate::Damage
+    pub fn from_reader_salvage(
+        reader: impl BufReadSeek + 'b,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        let reader: Box<dyn BufReadSeek + 'b> = if offset == 0 {
+            Box::new(reader)
+        } else {
+            let reader = SquashfsReaderWithOffset::new(reader, offset)?;
+            Box::new(reader)
+        };
+        Self::inner_from_reader_with_offset_and_kind(reader, kind, ReadLimits::default(), true)
+    }
+
+    /// Same as [`Self::from_reader_salvage`], but reading from a [`ReadAt`]
+    pub fn from_read_at_salvage(
+        reader: impl ReadAt + 'b,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<Self, BackhandError> {
+        let reader: Arc<dyn ReadAt + 'b> = Arc::new(reader);
+        let stream = BufReader::new(ReadAtCursor::new(reader.clone())?);
+        let mut squashfs = Self::from_reader_salvage(stream, offset, kind)?;
+        squashfs.read_at = Some((reader, offset));
+        Ok(squashfs)
     }
 
     fn inner_from_reader_with_offset_and_kind(
         mut reader: Box<dyn BufReadSeek + 'b>,
         kind: Kind,
+        limits: ReadLimits,
+        salvage: bool,
     ) -> Result<Self, BackhandError> {
         let (superblock, compression_options) =
             Self::superblock_and_compression_options(&mut reader, &kind)?;
+        ReadLimits::check(
+            "block size",
+            superblock.block_size.into(),
+            limits.max_block_size.into(),
+        )?;
+
+        // damage found in salvage mode, errors are returned otherwise
+        let damage = salvage.then(|| Mutex::new(vec![]));
+        let invalid = |field: &str| {
+            error!("corrupted or invalid {field}");
+            let error = Err(BackhandError::CorruptedOrInvalidSquashfs);
+            tolerate::<()>(damage.as_ref(), error, 0, "superblock", None)
+        };
 
         // Check if legal image
         let total_length = reader.seek(SeekFrom::End(0))?;
         reader.rewind()?;
         if superblock.bytes_used > total_length {
-            error!("corrupted or invalid bytes_used");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            invalid("bytes_used")?;
         }
 
         // check required fields
         if superblock.id_table > total_length {
-            error!("corrupted or invalid xattr_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            invalid("id_table")?;
         }
         if superblock.inode_table > total_length {
-            error!("corrupted or invalid inode_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            invalid("inode_table")?;
         }
         if superblock.dir_table > total_length {
-            error!("corrupted or invalid dir_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            invalid("dir_table")?;
         }
 
         // check optional fields
         if superblock.xattr_table != NOT_SET && superblock.xattr_table > total_length {
-            error!("corrupted or invalid frag_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            invalid("xattr_table")?;
         }
         if superblock.frag_table != NOT_SET && superblock.frag_table > total_length {
-            error!("corrupted or invalid frag_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            invalid("frag_table")?;
         }
         if superblock.export_table != NOT_SET && superblock.export_table > total_length {
-            error!("corrupted or invalid export_table");
-            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            invalid("export_table")?;
         }
 
         // Read all fields from filesystem to make a Squashfs
+        let alloc = Allocation::new(limits);
         info!("Reading Inodes");
-        let inodes = reader.inodes(&superblock, &kind)?;
+        let (inodes, inode_table) = match &damage {
+            None => {
+                let inodes = reader
+                    .inodes(&superblock, &kind, &alloc)
+                    .context(|| table("inode table", superblock.inode_table))?;
+                (inodes, None)
+            }
+            Some(damage) => {
+                let (inodes, inode_table) =
+                    reader.inodes_salvage(&superblock, &kind, &alloc, damage)?;
+                (inodes, Some(inode_table))
+            }
+        };
 
         info!("Reading Root Inode");
-        let root_inode = reader.root_inode(&superblock, &kind)?;
+        let root_inode = reader
+            .root_inode(&superblock, &kind)
+            .context(|| table("inode table", superblock.inode_table))?;
 
         info!("Reading Fragments");
-        let fragments = reader.fragments(&superblock, &kind)?;
+        let fragments = reader
+            .fragments(&superblock, &kind, &alloc)
+            .context(|| table("fragment table", superblock.frag_table));
+        let fragments =
+            tolerate(damage.as_ref(), fragments, superblock.frag_table, "fragment table", None)?
+                .flatten();
         let fragment_ptr = fragments.as_ref().map(|frag| frag.0);
         let fragment_table = fragments.map(|a| a.1);
 
         info!("Reading Exports");
-        let export = reader.export(&superblock, &kind)?;
+        let export = reader
+            .export(&superblock, &kind, &alloc)
+            .context(|| table("export table", superblock.export_table));
+        let export =
+            tolerate(damage.as_ref(), export, superblock.export_table, "export table", None)?
+                .flatten();
         let export_ptr = export.as_ref().map(|export| export.0);
         let export_table = export.map(|a| a.1);
 
         info!("Reading Ids");
-        let id = reader.id(&superblock, &kind)?;
-        let id_ptr = id.0;
-        let id_table = id.1;
+        let id = reader
+            .id(&superblock, &kind, &alloc)
+            .context(|| table("id table", superblock.id_table));
+        // without ids, the ids of all nodes are replaced by 0 in salvage mode
+        let (id_ptr, id_table) =
+            tolerate(damage.as_ref(), id, superblock.id_table, "id table", None)?
+                .unwrap_or((superblock.id_table, vec![]));
 
         let last_dir_position = if let Some(fragment_ptr) = fragment_ptr {
             trace!("using fragment for end of dir");
@@ -390,7 +516,20 @@ impl<'b> Squashfs<'b> {
         };
 
         info!("Reading Dirs");
-        let dir_blocks = reader.dir_blocks(&superblock, last_dir_position, &kind)?;
+        let dir_blocks = match &damage {
+            None => reader
+                .dir_blocks(&superblock, last_dir_position, &kind, &alloc)
+                .context(|| table("directory table", superblock.dir_table))?,
+            Some(damage) => reader.metadata_store_salvage(
+                &superblock,
+                superblock.dir_table,
+                last_dir_position,
+                &kind,
+                &alloc,
+                "directory table",
+                damage,
+            )?,
+        };
 
         let squashfs = Squashfs {
             kind,
@@ -403,6 +542,11 @@ impl<'b> Squashfs<'b> {
             export: export_table,
             id: id_table,
             file: reader,
+            read_at: None,
+            limits,
+            salvage: inode_table
+                .zip(damage)
+                .map(|(inode_table, damage)| Salvage { inode_table, damage }),
         };
 
         // show info about flags
@@ -463,17 +607,17 @@ impl<'b> Squashfs<'b> {
             return Ok(None);
         }
 
-        // ignore blocks before our block_index, grab all the rest of the bytes
-        // TODO: perf
-        let block: Vec<u8> = self
+        let bytes = match self
             .dir_blocks
-            .iter()
-            .filter(|(a, _)| *a >= block_index)
-            .flat_map(|(_, b)| b.iter())
-            .copied()
-            .collect();
-
-        let bytes = &block[block_offset..][..file_size as usize - 3];
+            .get(block_index, block_offset)
+            .and_then(|bytes| bytes.get(..file_size as usize - 3))
+        {
+            Some(bytes) => bytes,
+            None => {
+                error!("dir outside of directory table");
+                return Err(BackhandError::CorruptedOrInvalidSquashfs);
+            }
+        };
         let mut dirs = vec![];
         let mut all_bytes = bytes.view_bits::<Msb0>();
         // Read until we fail to turn bytes into `T`
@@ -493,90 +637,236 @@ impl<'b> Squashfs<'b> {
         root: &mut Nodes<SquashfsFileReader>,
         dir_inode: &Inode,
         id_table: &[Id],
+        visited: &mut FxHashSet<u32>,
     ) -> Result<(), BackhandError> {
-        let dirs = match &dir_inode.inner {
+        let (block_index, file_size, block_offset) = match &dir_inode.inner {
             InodeInner::BasicDirectory(basic_dir) => {
                 trace!("BASIC_DIR inodes: {:02x?}", basic_dir);
-                self.dir_from_index(
-                    basic_dir.block_index.try_into().unwrap(),
-                    basic_dir.file_size.try_into().unwrap(),
+                (
+                    u64::from(basic_dir.block_index),
+                    u32::from(basic_dir.file_size),
                     basic_dir.block_offset as usize,
-                )?
+                )
             }
             InodeInner::ExtendedDirectory(ext_dir) => {
                 trace!("EXT_DIR: {:#02x?}", ext_dir);
-                self.dir_from_index(
-                    ext_dir.block_index.try_into().unwrap(),
-                    ext_dir.file_size,
-                    ext_dir.block_offset as usize,
-                )?
+                (u64::from(ext_dir.block_index), ext_dir.file_size, ext_dir.block_offset as usize)
+            }
+            _ => {
+                return Err(BackhandError::UnexpectedInode(dir_inode.inner.clone()))
+                    .context(|| ErrorContext::Path(fullpath.clone()))
             }
-            _ => return Err(BackhandError::UnexpectedInode(dir_inode.inner.clone())),
         };
-        if let Some(dirs) = dirs {
-            for d in &dirs {
-                trace!("extracing entry: {:#?}", d.dir_entries);
-                for entry in &d.dir_entries {
-                    let inode_key =
-                        (d.inode_num as i32 + entry.inode_offset as i32).try_into().unwrap();
-                    let found_inode = &self.inodes[&inode_key];
-                    let header = found_inode.header;
-                    fullpath.push(entry.name()?);
-
-                    let inner: InnerNode<SquashfsFileReader> = match entry.t {
-                        // BasicDirectory, ExtendedDirectory
-                        InodeId::BasicDirectory | InodeId::ExtendedDirectory => {
-                            // its a dir, extract all children inodes
-                            self.extract_dir(fullpath, root, found_inode, &self.id)?;
-                            InnerNode::Dir(SquashfsDir::default())
-                        }
-                        // BasicFile
-                        InodeId::BasicFile => {
-                            trace!("before_file: {:#02x?}", entry);
-                            let basic = match &found_inode.inner {
-                                InodeInner::BasicFile(file) => file.clone(),
-                                InodeInner::ExtendedFile(file) => file.into(),
-                                _ => {
-                                    return Err(BackhandError::UnexpectedInode(
-                                        found_inode.inner.clone(),
-                                    ))
-                                }
-                            };
-                            InnerNode::File(SquashfsFileReader { basic })
-                        }
-                        // Basic Symlink
-                        InodeId::BasicSymlink => {
-                            let link = self.symlink(found_inode)?;
-                            InnerNode::Symlink(SquashfsSymlink { link })
-                        }
-                        // Basic CharacterDevice
-                        InodeId::BasicCharacterDevice => {
-                            let device_number = self.char_device(found_inode)?;
-                            InnerNode::CharacterDevice(SquashfsCharacterDevice { device_number })
-                        }
-                        // Basic CharacterDevice
-                        InodeId::BasicBlockDevice => {
-                            let device_number = self.block_device(found_inode)?;
-                            InnerNode::BlockDevice(SquashfsBlockDevice { device_number })
-                        }
-                        InodeId::ExtendedFile => {
-                            return Err(BackhandError::UnsupportedInode(found_inode.inner.clone()))
-                        }
-                    };
-                    let node = Node::new(
-                        fullpath.clone(),
-                        NodeHeader::from_inode(header, id_table),
-                        inner,
-                    );
-                    root.nodes.push(node);
-                    fullpath.pop();
-                }
+        let dir_offset = self.superblock.dir_table + block_index;
+        let dirs = self
+            .dir_from_index(block_index, file_size, block_offset)
+            .context(|| ErrorContext::Path(fullpath.clone()));
+        let dirs = match self.tolerate(dirs, dir_offset, "directory table", fullpath)? {
+            Some(Some(dirs)) => dirs,
+            _ => return Ok(()),
+        };
+        for d in &dirs {
+            trace!("extracing entry: {:#?}", d.dir_entries);
+            let inode_offset = self.superblock.inode_table + u64::from(d.start);
+            for entry in &d.dir_entries {
+                let name = entry.name().context(|| ErrorContext::Path(fullpath.clone()));
+                let Some(name) = self.tolerate(name, dir_offset, "directory table", fullpath)?
+                else {
+                    continue;
+                };
+                fullpath.push(name);
+                let result =
+                    self.extract_entry(fullpath, root, dir_inode, d, entry, id_table, visited);
+                self.tolerate(result, inode_offset, "inode table", fullpath)?;
+                fullpath.pop();
             }
         }
         //TODO: todo!("verify all the paths are valid");
         Ok(())
     }
 
+    /// Push the node of `entry` of the directory `dir_inode` to `root`, with the children of
+    /// `entry` if it is a directory
+    #[allow(clippy::too_many_arguments)]
+    fn extract_entry(
+        &self,
+        fullpath: &mut PathBuf,
+        root: &mut Nodes<SquashfsFileReader>,
+        dir_inode: &Inode,
+        dir: &Dir,
+        entry: &DirEntry,
+        id_table: &[Id],
+        visited: &mut FxHashSet<u32>,
+    ) -> Result<(), BackhandError> {
+        let inode_key = u32::try_from(i64::from(dir.inode_num) + i64::from(entry.inode_offset))
+            .context(|| ErrorContext::Path(fullpath.clone()))?;
+        let found_inode = match self.inode(inode_key, dir.start, entry.offset) {
+            Some(inode) => inode,
+            None => {
+                error!("inode {inode_key} of {fullpath:?} not found");
+                return Err(BackhandError::InodeNotFound {
+                    path: fullpath.clone(),
+                    inode: inode_key,
+                });
+            }
+        };
+        ReadLimits::check(
+            "directory depth",
+            fullpath.components().count() as u64 - 1,
+            self.limits.max_dir_depth as u64,
+        )?;
+
+        let inner: InnerNode<SquashfsFileReader> = match entry.t {
+            // BasicDirectory, ExtendedDirectory
+            InodeId::BasicDirectory | InodeId::ExtendedDirectory => {
+                // its a dir, extract all children inodes
+                let parent = dir_inode.header.inode_number;
+                Self::check_dir(fullpath, &found_inode, parent, visited)?;
+                self.extract_dir(fullpath, root, &found_inode, &self.id, visited)?;
+                InnerNode::Dir(SquashfsDir::default())
+            }
+            t => {
+                self.leaf_node(t, &found_inode).context(|| ErrorContext::Path(fullpath.clone()))?
+            }
+        };
+        let header = self.node_header(found_inode.header, id_table, fullpath)?;
+        root.nodes.push(Node::new(fullpath.clone(), header, inner));
+        Ok(())
+    }
+
+    /// Inode with the number `key`. In salvage mode, inodes that weren't read are found by their
+    /// reference `start` and `offset` in the inode table.
+    fn inode(&self, key: u32, start: u32, offset: u16) -> Option<Cow<'_, Inode>> {
+        if let Some(inode) = self.inodes.get(&key) {
+            return Some(Cow::Borrowed(inode));
+        }
+        let salvage = self.salvage.as_ref()?;
+        let bytes = salvage.inode_table.get(u64::from(start), usize::from(offset))?;
+        let (_, inode) = Inode::read(
+            bytes.view_bits::<Msb0>(),
+            (
+                self.superblock.bytes_used,
+                self.superblock.block_size,
+                self.superblock.block_log,
+                self.kind.inner.type_endian,
+            ),
+        )
+        .ok()?;
+        // the reference could point into the middle of another inode
+        (inode.header.inode_number == key).then_some(Cow::Owned(inode))
+    }
+
+    /// Header of the node at `path`. In salvage mode, ids missing from `id_table` are replaced
+    /// by `0`.
+    fn node_header(
+        &self,
+        header: InodeHeader,
+        id_table: &[Id],
+        path: &Path,
+    ) -> Result<NodeHeader, BackhandError> {
+        let node_header = NodeHeader::from_inode(header, id_table)
+            .context(|| ErrorContext::Path(path.to_path_buf()));
+        let node_header = self.tolerate(node_header, self.superblock.id_table, "id table", path)?;
+        Ok(node_header.unwrap_or_else(|| NodeHeader::new(header.permissions, 0, 0, header.mtime)))
+    }
+
+    /// See [`tolerate`], with `path` as the damaged node
+    fn tolerate<T>(
+        &self,
+        result: Result<T, BackhandError>,
+        offset: u64,
+        structure: &'static str,
+        path: &Path,
+    ) -> Result<Option<T>, BackhandError> {
+        let damage = self.salvage.as_ref().map(|salvage| &salvage.damage);
+        tolerate(damage, result, offset, structure, Some(path))
+    }
+
+    /// Node of a directory entry of type `t`, that isn't a directory
+    fn leaf_node(
+        &self,
+        t: InodeId,
+        inode: &Inode,
+    ) -> Result<InnerNode<SquashfsFileReader>, BackhandError> {
+        let inner = match t {
+            // BasicFile
+            InodeId::BasicFile => {
+                let basic = match &inode.inner {
+                    InodeInner::BasicFile(file) => file.clone(),
+                    InodeInner::ExtendedFile(file) => {
+                        // before converting, as a basic file has a u32 size
+                        let max_file_size = self.limits.max_file_size;
+                        ReadLimits::check("file size", file.file_size, max_file_size)?;
+                        BasicFile::try_from(file)?
+                    }
+                    _ => return Err(BackhandError::UnexpectedInode(inode.inner.clone())),
+                };
+                self.check_file(&basic)?;
+                InnerNode::File(SquashfsFileReader { basic })
+            }
+            // Basic Symlink
+            InodeId::BasicSymlink => {
+                let link = self.symlink(inode)?;
+                InnerNode::Symlink(SquashfsSymlink { link })
+            }
+            // Basic CharacterDevice
+            InodeId::BasicCharacterDevice => {
+                let device_number = self.char_device(inode)?;
+                InnerNode::CharacterDevice(SquashfsCharacterDevice { device_number })
+            }
+            // Basic BlockDevice
+            InodeId::BasicBlockDevice => {
+                let device_number = self.block_device(inode)?;
+                InnerNode::BlockDevice(SquashfsBlockDevice { device_number })
+            }
+            _ => return Err(BackhandError::UnsupportedInode(inode.inner.clone())),
+        };
+        Ok(inner)
+    }
+
+    /// Check that the directory `inode` at `fullpath` wasn't read before, and that it is a child of
+    /// the directory with inode number `parent`
+    fn check_dir(
+        fullpath: &Path,
+        inode: &Inode,
+        parent: u32,
+        visited: &mut FxHashSet<u32>,
+    ) -> Result<(), BackhandError> {
+        let inode_number = inode.header.inode_number;
+        if !visited.insert(inode_number) {
+            error!("directory {fullpath:?} with inode {inode_number} was already read");
+            return Err(BackhandError::DirectoryCycle {
+                path: fullpath.to_path_buf(),
+                inode: inode_number,
+            });
+        }
+        let parent_inode = match &inode.inner {
+            InodeInner::BasicDirectory(dir) => dir.parent_inode,
+            InodeInner::ExtendedDirectory(dir) => dir.parent_inode,
+            _ => return Err(BackhandError::UnexpectedInode(inode.inner.clone())),
+        };
+        if parent_inode != parent {
+            error!("directory {fullpath:?} has parent inode {parent_inode}, expected {parent}");
+            return Err(BackhandError::InvalidParentInode {
+                path: fullpath.to_path_buf(),
+                expected: parent,
+                found: parent_inode,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check the size of `file` against the limits, and that its blocks are inside the image
+    fn check_file(&self, file: &BasicFile) -> Result<(), BackhandError> {
+        ReadLimits::check("file size", file.file_size.into(), self.limits.max_file_size)?;
+        let blocks_len: u64 = file.block_sizes.iter().map(|size| u64::from(size.size())).sum();
+        if u64::from(file.blocks_start) + blocks_len > self.superblock.bytes_used {
+            error!("file blocks are outside of the image");
+            return Err(BackhandError::CorruptedOrInvalidSquashfs);
+        }
+        Ok(())
+    }
+
     /// Symlink Details
     ///
     /// # Returns
@@ -625,9 +915,31 @@ impl<'b> Squashfs<'b> {
     #[instrument(skip_all)]
     pub fn into_filesystem_reader(self) -> Result<FilesystemReader<'b>, BackhandError> {
         info!("creating fs tree");
-        let mut root = Nodes::new_root(NodeHeader::from_inode(self.root_inode.header, &self.id));
-        self.extract_dir(&mut PathBuf::from("/"), &mut root, &self.root_inode, &self.id)?;
+        let header = self.node_header(self.root_inode.header, &self.id, Path::new("/"))?;
+        let mut root = Nodes::new_root(header);
+        let mut visited = FxHashSet::default();
+        visited.insert(self.root_inode.header.inode_number);
+        self.extract_dir(
+            &mut PathBuf::from("/"),
+            &mut root,
+            &self.root_inode,
+            &self.id,
+            &mut visited,
+        )?;
         root.nodes.sort();
+        // names are unique in a directory, a node of a duplicate name could shadow the other
+        let duplicate = root
+            .nodes
+            .windows(2)
+            .find(|nodes| nodes[0].fullpath == nodes[1].fullpath)
+            .map(|nodes| nodes[0].fullpath.clone());
+        if let Some(path) = duplicate {
+            error!("duplicated file name in directory");
+            let error = Err(BackhandError::DuplicatedFileName);
+            self.tolerate::<()>(error, self.superblock.dir_table, "directory table", &path)?;
+            // keep the first of the nodes with the same name
+            root.nodes.dedup();
+        }
 
         info!("created fs tree");
         let filesystem = FilesystemReader {
@@ -640,9 +952,127 @@ impl<'b> Squashfs<'b> {
             id_table: self.id,
             fragments: self.fragments,
             root,
-            reader: Mutex::new(Box::new(self.file)),
+            reader: match self.read_at {
+                Some((reader, offset)) => ReaderBackend::Positional { reader, offset },
+                None => ReaderBackend::Stream(Mutex::new(self.file)),
+            },
             cache: Mutex::new(Cache::default()),
+            damage: self.salvage.map(|salvage| salvage.damage),
+            limits: self.limits,
         };
         Ok(filesystem)
     }
 }
+
+#[cfg(test)]
+#[cfg(feature = "xz")]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{FilesystemWriter, NodeHeader};
+
+    fn squashfs() -> Squashfs<'static> {
+        let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+        let mut fs = FilesystemWriter::default();
+        fs.push_dir_all("usr/bin", header).unwrap();
+        fs.push_file(Cursor::new(vec![0x00]), "usr/bin/heyo", header).unwrap();
+
+        let mut output = Cursor::new(vec![]);
+        fs.write(&mut output).unwrap();
+        Squashfs::from_reader(Cursor::new(output.into_inner())).unwrap()
+    }
+
+    /// Inode numbers of the directories, except the root
+    fn dirs(squashfs: &Squashfs) -> Vec<u32> {
+        let root = squashfs.root_inode.header.inode_number;
+        squashfs
+            .inodes
+            .values()
+            .filter(|inode| inode.header.inode_number != root)
+            .filter(|inode| {
+                matches!(
+                    inode.inner,
+                    InodeInner::BasicDirectory(_) | InodeInner::ExtendedDirectory(_)
+                )
+            })
+            .map(|inode| inode.header.inode_number)
+            .collect()
+    }
+
+    #[test]
+    fn test_missing_inode() {
+        let mut squashfs = squashfs();
+        let file = squashfs
+            .inodes
+            .values()
+            .find(|inode| matches!(inode.inner, InodeInner::BasicFile(_)))
+            .unwrap()
+            .header
+            .inode_number;
+        squashfs.inodes.remove(&file);
+        match squashfs.into_filesystem_reader() {
+            Err(BackhandError::InodeNotFound { path, inode }) => {
+                assert_eq!(path, Path::new("/usr/bin/heyo"));
+                assert_eq!(inode, file);
+            }
+            _ => panic!("expected InodeNotFound"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_id_index() {
+        let mut squashfs = squashfs();
+        for inode in squashfs.inodes.values_mut() {
+            if matches!(inode.inner, InodeInner::BasicFile(_)) {
+                inode.header.uid = 0xff;
+            }
+        }
+        match squashfs.into_filesystem_reader() {
+            Err(BackhandError::Context { context: ErrorContext::Path(path), source }) => {
+                assert_eq!(path, Path::new("/usr/bin/heyo"));
+                assert!(matches!(*source, BackhandError::InvalidIdIndex(0xff)));
+            }
+            _ => panic!("expected InvalidIdIndex with path"),
+        }
+    }
+
+    #[test]
+    fn test_directory_cycle() {
+        let mut squashfs = squashfs();
+        // every directory lists the entries of the root
+        let root = match &squashfs.root_inode.inner {
+            InodeInner::BasicDirectory(root) => root.clone(),
+            _ => unreachable!(),
+        };
+        for dir in dirs(&squashfs) {
+            if let InodeInner::BasicDirectory(dir) =
+                &mut squashfs.inodes.get_mut(&dir).unwrap().inner
+            {
+                dir.block_index = root.block_index;
+                dir.block_offset = root.block_offset;
+                dir.file_size = root.file_size;
+            }
+        }
+        assert!(matches!(
+            squashfs.into_filesystem_reader(),
+            Err(BackhandError::DirectoryCycle { .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_parent_inode() {
+        let mut squashfs = squashfs();
+        for dir in dirs(&squashfs) {
+            if let InodeInner::BasicDirectory(dir) =
+                &mut squashfs.inodes.get_mut(&dir).unwrap().inner
+            {
+                dir.parent_inode = 0xdead;
+            }
+        }
+        assert!(matches!(
+            squashfs.into_filesystem_reader(),
+            Err(BackhandError::InvalidParentInode { found: 0xdead, .. })
+        ));
+    }
+}
@@ -0,0 +1,511 @@
+//! Integrity checks of images, see [`Squashfs::verify`]
+
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+
+use deku::bitvec::{BitView, Msb0};
+use deku::prelude::*;
+use rustc_hash::FxHashMap;
+use tracing::{info, warn};
+
+use crate::data::DataSize;
+use crate::dir::{Dir, DirEntry};
+use crate::error::{table, BackhandError, ErrorContext, ResultExt};
+use crate::fragment::Fragment;
+use crate::inode::{Inode, InodeInner};
+use crate::kinds::Kind;
+use crate::limits::{Allocation, ReadLimits};
+use crate::metadata::{self, MetadataStore};
+use crate::reader::{BufReadSeek, SquashFsReader, SquashfsReaderWithOffset};
+use crate::squashfs::{Squashfs, SuperBlock, NOT_SET};
+
+/// Problem found by [`Squashfs::verify`]
+#[derive(Debug)]
+pub struct VerifyProblem {
+    /// Byte offset in the image of the broken structure
+    pub offset: u64,
+    /// Name of the broken structure, such as `"inode table"`
+    pub structure: &'static str,
+    pub error: BackhandError,
+}
+
+impl fmt::Display for VerifyProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010x} {}: {}", self.offset, self.structure, self.error)
+    }
+}
+
+/// Result of [`Squashfs::verify`], with the amount of checked structures and all problems found
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub metadata_blocks: u64,
+    pub inodes: u64,
+    pub directories: u64,
+    pub data_blocks: u64,
+    pub fragments: u64,
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl<'b> Squashfs<'b> {
+    /// Check the integrity of the image starting at `offset` in `reader`
+    ///
+    /// Checks the consistency of the superblock, reads every metadata block, inode and directory
+    /// header, decompresses every data block and fragment and checks the bounds of the fragment,
+    /// id and export tables. Unlike creating a [`Squashfs`], this doesn't stop at the first
+    /// problem: all problems found are returned in the [`VerifyReport`].
+    ///
+    /// Returns `Err` only if the superblock can't be read.
+    pub fn verify(
+        reader: impl BufReadSeek + 'b,
+        offset: u64,
+        kind: Kind,
+    ) -> Result<VerifyReport, BackhandError> {
+        let mut reader: Box<dyn BufReadSeek + 'b> =
+            Box::new(SquashfsReaderWithOffset::new(reader, offset)?);
+        let image_len = reader.seek(SeekFrom::End(0))?;
+        reader.rewind()?;
+        let (superblock, _) = Self::superblock_and_compression_options(&mut reader, &kind)
+            .context(|| table("superblock", 0))?;
+
+        let mut verifier = Verifier {
+            reader,
+            superblock,
+            kind,
+            image_len,
+            inodes: FxHashMap::default(),
+            fragments: vec![],
+            report: VerifyReport::default(),
+        };
+        verifier.run();
+        info!("found {} problems", verifier.report.problems.len());
+        Ok(verifier.report)
+    }
+}
+
+struct Verifier<'b> {
+    reader: Box<dyn BufReadSeek + 'b>,
+    superblock: SuperBlock,
+    kind: Kind,
+    image_len: u64,
+    /// Inode number to inode and position in the image
+    inodes: FxHashMap<u32, (u64, Inode)>,
+    /// Decompressed size of each fragment, `None` if broken
+    fragments: Vec<Option<usize>>,
+    report: VerifyReport,
+}
+
+impl<'b> Verifier<'b> {
+    fn problem(&mut self, offset: u64, structure: &'static str, error: impl Into<BackhandError>) {
+        let error = error.into();
+        warn!("{offset:#x?} {structure}: {error}");
+        self.report.problems.push(VerifyProblem { offset, structure, error });
+    }
+
+    fn invalid(&mut self, offset: u64, structure: &'static str, field: &'static str, value: u64) {
+        self.problem(offset, structure, BackhandError::InvalidField { field, value });
+    }
+
+    fn run(&mut self) {
+        self.check_superblock();
+
+        let alloc = Allocation::new(ReadLimits::default());
+        let sb = self.superblock;
+        let mut tables = vec![];
+        match self.reader.fragments(&sb, &self.kind, &alloc) {
+            Ok(Some((ptr, table))) => {
+                tables.push(ptr);
+                self.fragments(&table);
+            }
+            Ok(None) => (),
+            Err(e) => self.problem(sb.frag_table, "fragment table", e),
+        }
+        match self.reader.export(&sb, &self.kind, &alloc) {
+            Ok(Some((ptr, table))) => {
+                tables.push(ptr);
+                let inode_table_len = sb.dir_table.saturating_sub(sb.inode_table);
+                if table.len() as u64 != u64::from(sb.inode_count) {
+                    self.invalid(sb.export_table, "export table", "length", table.len() as u64);
+                }
+                for export in table {
+                    if export.num >> 16 >= inode_table_len {
+                        self.invalid(
+                            sb.export_table,
+                            "export table",
+                            "inode reference",
+                            export.num,
+                        );
+                    }
+                }
+            }
+            Ok(None) => (),
+            Err(e) => self.problem(sb.export_table, "export table", e),
+        }
+        let id_count = match self.reader.id(&sb, &self.kind, &alloc) {
+            Ok((ptr, table)) => {
+                tables.push(ptr);
+                table.len()
+            }
+            Err(e) => {
+                self.problem(sb.id_table, "id table", e);
+                0
+            }
+        };
+
+        let inode_table = self.metadata("inode table", sb.inode_table, sb.dir_table);
+        self.inodes(&inode_table, id_count);
+        match self.reader.root_inode(&sb, &self.kind) {
+            Ok(root) if self.is_dir(&root) => (),
+            Ok(_) => self.invalid(0, "superblock", "root_inode", sb.root_inode),
+            Err(e) => self.problem(sb.inode_table, "root inode", e),
+        }
+
+        // the directory table ends at the first lookup table
+        let dir_end = tables.into_iter().min().unwrap_or(sb.bytes_used);
+        let dir_table = self.metadata("directory table", sb.dir_table, dir_end);
+        self.dirs(&dir_table);
+        self.files();
+    }
+
+    /// Check the sizes and positions of the superblock
+    fn check_superblock(&mut self) {
+        let sb = self.superblock;
+        if sb.bytes_used > self.image_len {
+            self.invalid(0, "superblock", "bytes_used", sb.bytes_used);
+        }
+        if 1_u64.checked_shl(u32::from(sb.block_log)) != Some(u64::from(sb.block_size)) {
+            self.invalid(0, "superblock", "block_log", u64::from(sb.block_log));
+        }
+
+        // tables are written in this order, the optional ones can be missing
+        let tables = [
+            ("inode_table", sb.inode_table),
+            ("dir_table", sb.dir_table),
+            ("frag_table", sb.frag_table),
+            ("export_table", sb.export_table),
+            ("id_table", sb.id_table),
+            ("xattr_table", sb.xattr_table),
+        ];
+        let mut previous = 0;
+        for (field, position) in tables {
+            if position == NOT_SET && field != "inode_table" && field != "dir_table" {
+                continue;
+            }
+            if position < previous || position > sb.bytes_used {
+                self.invalid(0, "superblock", field, position);
+            } else {
+                previous = position;
+            }
+        }
+    }
+
+    /// Read all metadata blocks from `start` to `end`
+    fn metadata(&mut self, structure: &'static str, start: u64, end: u64) -> MetadataStore {
+        let mut store = MetadataStore::default();
+        if let Err(e) = self.reader.seek(SeekFrom::Start(start)) {
+            self.problem(start, structure, e);
+            return store;
+        }
+        loop {
+            let position = match self.reader.stream_position() {
+                Ok(position) => position,
+                Err(e) => {
+                    self.problem(start, structure, e);
+                    break;
+                }
+            };
+            if position >= end {
+                if position > end {
+                    self.invalid(position, structure, "metadata block end", position);
+                }
+                break;
+            }
+            match metadata::read_block(&mut self.reader, &self.superblock, &self.kind) {
+                Ok(bytes) => {
+                    self.report.metadata_blocks += 1;
+                    store.push(position - start, &bytes);
+                }
+                Err(e) => {
+                    // the position of the following blocks is unknown
+                    self.problem(position, structure, e);
+                    break;
+                }
+            }
+        }
+        store
+    }
+
+    /// Image position of byte `index` of the concatenated blocks of `store`
+    fn position(store: &MetadataStore, start: u64, index: usize) -> u64 {
+        let mut end = 0;
+        for (position, block) in store.blocks() {
+            end += block.len();
+            if index < end {
+                return start + position;
+            }
+        }
+        start
+    }
+
+    /// Parse every inode of the inode table
+    fn inodes(&mut self, store: &MetadataStore, id_count: usize) {
+        let sb = self.superblock;
+        let bytes = store.as_bytes();
+        let ctx = (sb.bytes_used, sb.block_size, sb.block_log, self.kind.inner.type_endian);
+        let mut input_bits = bytes.view_bits::<Msb0>();
+        while !input_bits.is_empty() {
+            let index = bytes.len() - input_bits.len() / 8;
+            let position = Self::position(store, sb.inode_table, index);
+            let (rest, inode) = match Inode::read(input_bits, ctx) {
+                Ok(ok) => ok,
+                // the last inode was read
+                Err(DekuError::Incomplete(_)) => break,
+                Err(e) => {
+                    // the position of the following inodes is unknown
+                    self.problem(position, "inode table", e);
+                    break;
+                }
+            };
+            input_bits = rest;
+            self.report.inodes += 1;
+
+            let number = inode.header.inode_number;
+            if number == 0 || number > sb.inode_count {
+                self.invalid(position, "inode", "inode_number", u64::from(number));
+            }
+            for index in [inode.header.uid, inode.header.gid] {
+                if usize::from(index) >= id_count {
+                    let error =
+                        BackhandError::InvalidIdIndex(index).context(ErrorContext::Inode(number));
+                    self.problem(position, "inode", error);
+                }
+            }
+            if self.inodes.insert(number, (position, inode)).is_some() {
+                self.invalid(position, "inode", "duplicated inode_number", u64::from(number));
+            }
+        }
+        if self.report.inodes != u64::from(sb.inode_count) {
+            self.invalid(0, "superblock", "inode_count", u64::from(sb.inode_count));
+        }
+    }
+
+    fn is_dir(&self, inode: &Inode) -> bool {
+        matches!(inode.inner, InodeInner::BasicDirectory(_) | InodeInner::ExtendedDirectory(_))
+    }
+
+    /// Parse the listing of every directory inode
+    fn dirs(&mut self, store: &MetadataStore) {
+        let sb = self.superblock;
+        let mut dirs: Vec<_> = self
+            .inodes
+            .values()
+            .filter_map(|(position, inode)| match &inode.inner {
+                InodeInner::BasicDirectory(dir) => Some((
+                    *position,
+                    inode.header.inode_number,
+                    dir.block_index,
+                    u32::from(dir.file_size),
+                    dir.block_offset,
+                )),
+                InodeInner::ExtendedDirectory(dir) => Some((
+                    *position,
+                    inode.header.inode_number,
+                    dir.block_index,
+                    dir.file_size,
+                    dir.block_offset,
+                )),
+                _ => None,
+            })
+            .collect();
+        dirs.sort_unstable();
+
+        for (position, number, block_index, file_size, block_offset) in dirs {
+            self.report.directories += 1;
+            let dir_position = sb.dir_table + u64::from(block_index);
+            // empty dir
+            if file_size < 4 {
+                continue;
+            }
+            let bytes = match store
+                .get(u64::from(block_index), usize::from(block_offset))
+                .and_then(|bytes| bytes.get(..file_size as usize - 3))
+            {
+                Some(bytes) => bytes,
+                None => {
+                    self.invalid(position, "directory inode", "block_index", dir_position);
+                    continue;
+                }
+            };
+
+            let mut all_bytes = bytes.view_bits::<Msb0>();
+            while !all_bytes.is_empty() {
+                let dir = match Dir::read(all_bytes, self.kind.inner.type_endian) {
+                    Ok((rest, dir)) => {
+                        all_bytes = rest;
+                        dir
+                    }
+                    Err(e) => {
+                        let error = BackhandError::from(e).context(ErrorContext::Inode(number));
+                        self.problem(dir_position, "directory header", error);
+                        break;
+                    }
+                };
+                for entry in &dir.dir_entries {
+                    self.dir_entry(dir_position, number, &dir, entry);
+                }
+            }
+        }
+    }
+
+    /// Check that `entry` of the directory with inode `parent` points to an inode of its type
+    fn dir_entry(&mut self, dir_position: u64, parent: u32, dir: &Dir, entry: &DirEntry) {
+        let name = match entry.name() {
+            Ok(name) => name.to_path_buf(),
+            Err(e) => {
+                self.problem(dir_position, "directory entry", e);
+                return;
+            }
+        };
+        let number = i64::from(dir.inode_num) + i64::from(entry.inode_offset);
+        let inode = u32::try_from(number).ok().and_then(|number| self.inodes.get(&number));
+        let (position, id, parent_inode) = match inode {
+            Some((position, inode)) => {
+                let parent_inode = match &inode.inner {
+                    InodeInner::BasicDirectory(dir) => Some(dir.parent_inode),
+                    InodeInner::ExtendedDirectory(dir) => Some(dir.parent_inode),
+                    _ => None,
+                };
+                (*position, inode.id, parent_inode)
+            }
+            None => {
+                let inode = number as u32;
+                let error = BackhandError::InodeNotFound { path: name, inode };
+                self.problem(dir_position, "directory entry", error);
+                return;
+            }
+        };
+        if id.into_base_type() != entry.t {
+            self.invalid(dir_position, "directory entry", "type", entry.t as u64);
+        }
+        let parent_inode = match parent_inode {
+            Some(parent_inode) => parent_inode,
+            None => return,
+        };
+        if parent_inode != parent {
+            let error = BackhandError::InvalidParentInode {
+                path: name,
+                expected: parent,
+                found: parent_inode,
+            };
+            self.problem(position, "directory inode", error);
+        }
+    }
+
+    /// Decompress every fragment of the fragment table
+    fn fragments(&mut self, table: &[Fragment]) {
+        for fragment in table {
+            self.report.fragments += 1;
+            let size =
+                self.block(fragment.start, fragment.size, "fragment").map(|bytes| bytes.len());
+            self.fragments.push(size);
+        }
+    }
+
+    /// Decompress every data block of every file, and check the fragment of the file
+    fn files(&mut self) {
+        let block_size = u64::from(self.superblock.block_size);
+        let mut files: Vec<_> = self
+            .inodes
+            .values()
+            .filter_map(|(position, inode)| match &inode.inner {
+                InodeInner::BasicFile(file) => Some((
+                    *position,
+                    u64::from(file.blocks_start),
+                    u64::from(file.file_size),
+                    file.frag_index,
+                    file.block_offset,
+                    file.block_sizes.clone(),
+                )),
+                InodeInner::ExtendedFile(file) => Some((
+                    *position,
+                    file.blocks_start,
+                    file.file_size,
+                    file.frag_index,
+                    file.block_offset,
+                    file.block_sizes.clone(),
+                )),
+                _ => None,
+            })
+            .collect();
+        files.sort_unstable_by_key(|file| file.0);
+
+        for (position, blocks_start, file_size, frag_index, block_offset, block_sizes) in files {
+            let mut start = blocks_start;
+            let mut remaining = file_size;
+            for size in block_sizes {
+                let expected = remaining.min(block_size);
+                remaining -= expected;
+                // sparse block
+                if size.size() == 0 {
+                    continue;
+                }
+                self.report.data_blocks += 1;
+                if let Some(bytes) = self.block(start, size, "data block") {
+                    if bytes.len() as u64 != expected {
+                        self.invalid(start, "data block", "size", bytes.len() as u64);
+                    }
+                }
+                start += u64::from(size.size());
+            }
+
+            // no fragment
+            if frag_index == u32::MAX || remaining == 0 {
+                continue;
+            }
+            match self.fragments.get(frag_index as usize) {
+                Some(Some(size)) => {
+                    if u64::from(block_offset) + remaining > *size as u64 {
+                        self.invalid(position, "file inode", "block_offset", block_offset.into());
+                    }
+                }
+                // already reported with the fragment table
+                Some(None) => (),
+                None => self.invalid(position, "file inode", "frag_index", frag_index.into()),
+            }
+        }
+    }
+
+    /// Read and decompress the block at `start`
+    fn block(&mut self, start: u64, size: DataSize, structure: &'static str) -> Option<Vec<u8>> {
+        let len = u64::from(size.size());
+        if start.checked_add(len).map_or(true, |end| end > self.superblock.bytes_used) {
+            self.invalid(start, structure, "position", start);
+            return None;
+        }
+        let mut raw = vec![0; len as usize];
+        let read =
+            self.reader.seek(SeekFrom::Start(start)).and_then(|_| self.reader.read_exact(&mut raw));
+        if let Err(e) = read {
+            self.problem(start, structure, e);
+            return None;
+        }
+        if size.uncompressed() {
+            return Some(raw);
+        }
+        let mut out = Vec::with_capacity(self.superblock.block_size as usize);
+        let compressor = self.superblock.compressor;
+        match self.kind.inner.compressor.decompress(&raw, &mut out, compressor) {
+            Ok(()) => Some(out),
+            Err(e) => {
+                self.problem(start, structure, e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+#![cfg(feature = "tokio")]
+
+use std::io::{Cursor, SeekFrom};
+
+use backhand::{AsyncFilesystemReader, BackhandError, FilesystemWriter, NodeHeader};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+#[tokio::test]
+#[cfg(feature = "xz")]
+async fn test_async_file() {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+    let data: Vec<u8> = (0..0x20000 * 2 + 1000).map(|i| (i % 251) as u8).collect();
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_file(Cursor::new(data.clone()), "large", header).unwrap();
+    fs.push_file(Cursor::new(vec![0x01, 0x02]), "small", header).unwrap();
+    fs.push_symlink("large", "link", header).unwrap();
+
+    // image not starting at the beginning of the reader
+    let mut output = Cursor::new(vec![]);
+    fs.write_with_offset(&mut output, 0x1000).unwrap();
+
+    let fs =
+        AsyncFilesystemReader::from_reader_with_offset(Cursor::new(output.into_inner()), 0x1000)
+            .await
+            .unwrap();
+    assert_eq!(fs.filesystem().files().count(), 4);
+
+    let mut all = vec![];
+    fs.open("/link").unwrap().read_to_end(&mut all).await.unwrap();
+    assert_eq!(all, data);
+
+    let mut file = fs.open("/large").unwrap();
+    let mut tail = [0; 100];
+    file.seek(SeekFrom::End(-100)).await.unwrap();
+    file.read_exact(&mut tail).await.unwrap();
+    assert_eq!(tail, data[data.len() - 100..]);
+
+    let mut small = vec![];
+    fs.open("/small").unwrap().read_to_end(&mut small).await.unwrap();
+    assert_eq!(small, [0x01, 0x02]);
+
+    assert!(matches!(fs.open("/nothing"), Err(BackhandError::FileNotFound)));
+}
@@ -1,7 +1,13 @@
-use std::process::Command;
+// not every test uses every helper
+#![allow(dead_code)]
+
+use std::io::Cursor;
+use std::process::{Command, Output};
 
 use assert_cmd::prelude::*;
-use tempfile::tempdir;
+use backhand::kind::{self, Kind};
+use backhand::{FilesystemWriter, NodeHeader};
+use tempfile::{tempdir, TempDir};
 
 /// test the new squashfs vs the original squashfs with squashfs-tool/unsquashfs
 /// by extract
@@ -98,3 +104,58 @@ pub fn get_base_command(base: &str) -> Command {
     }
     cmd
 }
+
+/// Run `bin` with `args`, without logs
+pub fn run(bin: &str, args: &[&str]) -> Output {
+    get_base_command(bin).env("RUST_LOG", "none").args(args).output().unwrap()
+}
+
+/// Image with `usr/bin/heyo`, a file stored in a fragment, `usr/bin/big`, a file stored in data
+/// blocks right after the superblock, and the symlink `usr/bin/link`
+pub fn image() -> Vec<u8> {
+    image_with_kind(Kind::from_const(kind::LE_V4_0).unwrap())
+}
+
+/// Same as [`image`], written with `kind`
+pub fn image_with_kind(kind: Kind) -> Vec<u8> {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+
+    let mut fs = FilesystemWriter::default();
+    fs.set_kind(kind);
+    fs.push_dir_all("usr/bin", header).unwrap();
+    fs.push_file(Cursor::new(vec![0x00; 0x100]), "usr/bin/heyo", header).unwrap();
+    fs.push_file(Cursor::new(vec![0x01; 0x30000]), "usr/bin/big", header).unwrap();
+    fs.push_symlink("heyo", "usr/bin/link", header).unwrap();
+
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    output.into_inner()
+}
+
+/// Image with a setuid file, a symlink and a device, listed by the unsquashfs tests
+pub fn cli_image() -> Vec<u8> {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+    let mut fs = FilesystemWriter::default();
+    fs.set_root_mode(0o755);
+    fs.push_dir("usr", header).unwrap();
+    let file_header = NodeHeader { permissions: 0o4755, uid: 1000, gid: 100, mtime: 1700000000 };
+    fs.push_file(Cursor::new(vec![0x00, 0x01]), "usr/heyo", file_header).unwrap();
+    fs.push_symlink("usr", "bin", NodeHeader { permissions: 0o777, ..header }).unwrap();
+    fs.push_dir("dev", header).unwrap();
+    fs.push_char_device((1 << 8) | 3, "dev/null", NodeHeader { permissions: 0o666, ..header })
+        .unwrap();
+
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    output.into_inner()
+}
+
+/// Write `image` to `image.squashfs` in a new temporary directory, returning the directory and
+/// the path of the image
+pub fn temp_image(image: &[u8]) -> (TempDir, String) {
+    let tmp_dir = tempdir().unwrap();
+    let path = tmp_dir.path().join("image.squashfs");
+    std::fs::write(&path, image).unwrap();
+    let path = path.to_str().unwrap().to_string();
+    (tmp_dir, path)
+}
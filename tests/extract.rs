@@ -0,0 +1,372 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use backhand::{
+    ExtractEvent, ExtractOptions, FilesystemReader, FilesystemWriter, MemoryNode, MemorySink,
+    NodeHeader, TarSink, DEFAULT_BLOCK_SIZE,
+};
+use tempfile::tempdir;
+
+fn image() -> FilesystemReader<'static> {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_dir_all("usr/bin", header).unwrap();
+    fs.push_file(Cursor::new(vec![0x00, 0x01]), "usr/bin/heyo", header).unwrap();
+    fs.push_file(Cursor::new(vec![0x02]), "usr/other", header).unwrap();
+    fs.push_symlink("usr/bin", "bin", header).unwrap();
+
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    FilesystemReader::from_read_at(output.into_inner()).unwrap()
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_extract_memory() {
+    let fs = image();
+
+    let sink = MemorySink::new();
+    let report = fs.extract_with_sink(&sink, &ExtractOptions::new()).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.extracted, 6);
+
+    let nodes = sink.into_nodes();
+    let paths: Vec<&Path> = nodes.keys().map(|p| p.as_path()).collect();
+    assert_eq!(
+        paths,
+        [
+            Path::new(""),
+            Path::new("bin"),
+            Path::new("usr"),
+            Path::new("usr/bin"),
+            Path::new("usr/bin/heyo"),
+            Path::new("usr/other"),
+        ]
+    );
+    assert_eq!(nodes[Path::new("usr/bin/heyo")].1, MemoryNode::File(vec![0x00, 0x01]));
+    assert_eq!(nodes[Path::new("bin")].1, MemoryNode::Symlink(PathBuf::from("usr/bin")));
+
+    // filter to a single file, only creating the dirs leading to it
+    let sink = MemorySink::new();
+    let options = ExtractOptions::new().parallel(false).path_filter("/usr/bin/heyo");
+    fs.extract_with_sink(&sink, &options).unwrap();
+    let nodes = sink.into_nodes();
+    let paths: Vec<&Path> = nodes.keys().map(|p| p.as_path()).collect();
+    assert_eq!(
+        paths,
+        [Path::new(""), Path::new("usr"), Path::new("usr/bin"), Path::new("usr/bin/heyo")]
+    );
+
+    let options = ExtractOptions::new().path_filter("/nothing");
+    assert!(fs.extract_with_sink(&MemorySink::new(), &options).is_err());
+}
+
+#[test]
+#[cfg(all(feature = "xz", feature = "regex"))]
+fn test_extract_patterns() {
+    use backhand::Pattern;
+
+    let fs = image();
+    let tmp_dir = tempdir().unwrap();
+    let extract = |options: ExtractOptions| {
+        let sink = MemorySink::new();
+        let report = fs.extract_with_sink(&sink, &options).unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors);
+        sink.into_nodes()
+    };
+    let paths = |nodes: &BTreeMap<PathBuf, (NodeHeader, MemoryNode)>| -> Vec<String> {
+        nodes.keys().map(|p| p.display().to_string()).collect()
+    };
+
+    let nodes = extract(ExtractOptions::new().path_filter("/usr/other").path_filter("/bin"));
+    assert_eq!(paths(&nodes), ["", "bin", "usr", "usr/other"]);
+    assert_eq!(nodes[Path::new("usr")].0.permissions, 0o755);
+
+    let nodes = extract(ExtractOptions::new().exclude(Pattern::glob("heyo").unwrap()));
+    assert_eq!(paths(&nodes), ["", "bin", "usr", "usr/bin", "usr/other"]);
+
+    // excluding a dir excludes the nodes under it
+    let nodes = extract(ExtractOptions::new().exclude(Pattern::regex("^/usr/b").unwrap()));
+    assert_eq!(paths(&nodes), ["", "bin", "usr", "usr/other"]);
+
+    let options = ExtractOptions::new()
+        .include(Pattern::glob("/usr/**").unwrap())
+        .exclude(Pattern::glob("other").unwrap());
+    assert_eq!(paths(&extract(options)), ["", "usr", "usr/bin", "usr/bin/heyo"]);
+
+    let patterns = tmp_dir.path().join("patterns");
+    std::fs::write(&patterns, "# comment\n\nregex:^/bin$\nglob:other\n").unwrap();
+    let mut options = ExtractOptions::new();
+    for pattern in Pattern::from_file(&patterns).unwrap() {
+        options = options.exclude(pattern);
+    }
+    assert_eq!(paths(&extract(options)), ["", "usr", "usr/bin", "usr/bin/heyo"]);
+
+    assert!(Pattern::glob("[").is_err());
+}
+
+/// Name, type and data of the entries of `tar`, with the path of pax extended headers
+fn tar_entries(tar: &[u8]) -> Vec<(String, u8, Vec<u8>)> {
+    let field = |block: &[u8]| {
+        let end = block.iter().position(|b| *b == 0).unwrap_or(block.len());
+        String::from_utf8(block[..end].to_vec()).unwrap()
+    };
+    let mut entries = vec![];
+    let mut pax_path = None;
+    let mut blocks = tar.chunks(512);
+    while let Some(header) = blocks.next() {
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let checksum: u32 = header
+            .iter()
+            .enumerate()
+            .map(|(i, b)| if (148..156).contains(&i) { u32::from(b' ') } else { u32::from(*b) })
+            .sum();
+        assert_eq!(u32::from_str_radix(&field(&header[148..154]), 8).unwrap(), checksum);
+        assert_eq!(&header[257..265], b"ustar\x0000");
+
+        let size = usize::from_str_radix(field(&header[124..136]).trim(), 8).unwrap();
+        let mut data = vec![];
+        for _ in 0..(size + 511) / 512 {
+            data.extend_from_slice(blocks.next().unwrap());
+        }
+        data.truncate(size);
+        if header[156] == b'x' {
+            let records = String::from_utf8(data).unwrap();
+            let path = records.lines().find_map(|record| record.split_once(" path="));
+            pax_path = path.map(|(_, path)| path.to_string());
+            continue;
+        }
+        let prefix = field(&header[345..500]);
+        let name = match pax_path.take() {
+            Some(path) => path,
+            None if prefix.is_empty() => field(&header[..100]),
+            None => format!("{prefix}/{}", field(&header[..100])),
+        };
+        entries.push((name, header[156], data));
+    }
+    entries
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_extract_tar() {
+    let fs = image();
+    let sink = TarSink::new(vec![]);
+    let report = fs.extract_with_sink(&sink, &ExtractOptions::new().parallel(false)).unwrap();
+    assert!(report.is_ok(), "{:?}", report.errors);
+    let tar = sink.finish().unwrap();
+    assert_eq!(tar.len() % 512, 0);
+    assert_eq!(
+        tar_entries(&tar),
+        [
+            ("usr/".to_string(), b'5', vec![]),
+            ("usr/bin/".to_string(), b'5', vec![]),
+            ("bin".to_string(), b'2', vec![]),
+            ("usr/bin/heyo".to_string(), b'0', vec![0x00, 0x01]),
+            ("usr/other".to_string(), b'0', vec![0x02]),
+        ]
+    );
+
+    // paths longer than the ustar name field, split into the prefix field or in pax headers
+    let header = NodeHeader { permissions: 0o644, uid: 0, gid: 0, mtime: 0 };
+    let long_name = "a".repeat(120);
+    let long_dir = format!("{}/{}", "d".repeat(150), "e".repeat(50));
+    let mut fs = FilesystemWriter::default();
+    fs.push_file(Cursor::new(vec![0x03; 600]), &long_name, header).unwrap();
+    fs.push_dir_all(&long_dir, header).unwrap();
+    fs.push_file(Cursor::new(vec![]), format!("{long_dir}/f"), header).unwrap();
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    let fs = FilesystemReader::from_read_at(output.into_inner()).unwrap();
+
+    let sink = TarSink::new(vec![]);
+    fs.extract_with_sink(&sink, &ExtractOptions::new().parallel(false)).unwrap();
+    let entries = tar_entries(&sink.finish().unwrap());
+    let names: Vec<&str> = entries.iter().map(|(name, _, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            format!("{}/", "d".repeat(150)),
+            format!("{long_dir}/"),
+            long_name.clone(),
+            format!("{long_dir}/f"),
+        ]
+    );
+    assert_eq!(entries[2].2, vec![0x03; 600]);
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_extract_tar_parallel() {
+    // files with enough blocks to be read with read-ahead on the rayon pool by other sinks
+    let header = NodeHeader { permissions: 0o644, uid: 0, gid: 0, mtime: 0 };
+    let len = 17 * DEFAULT_BLOCK_SIZE as usize + 100;
+    let files: Vec<(String, Vec<u8>)> = (0..4_u8)
+        .map(|i| (format!("file{i}"), (0..len).map(|n| (n % 251) as u8 ^ i).collect()))
+        .collect();
+    let mut fs = FilesystemWriter::default();
+    for (name, data) in &files {
+        fs.push_file(Cursor::new(data.clone()), name, header).unwrap();
+    }
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    let fs = FilesystemReader::from_read_at(output.into_inner()).unwrap();
+
+    let sink = TarSink::new(vec![]);
+    let report = fs.extract_with_sink(&sink, &ExtractOptions::new().parallel(true)).unwrap();
+    assert!(report.is_ok(), "{:?}", report.errors);
+    let mut entries = tar_entries(&sink.finish().unwrap());
+    entries.sort();
+    let expected: Vec<(String, u8, Vec<u8>)> =
+        files.into_iter().map(|(name, data)| (name, b'0', data)).collect();
+    assert!(entries == expected);
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_extract_host() {
+    let fs = image();
+    let tmp_dir = tempdir().unwrap();
+    let dest = tmp_dir.path().join("squashfs-root");
+
+    let report = fs.extract(&dest, &ExtractOptions::new()).unwrap();
+    assert!(report.is_ok(), "{:?}", report.errors);
+    assert_eq!(std::fs::read(dest.join("bin/heyo")).unwrap(), [0x00, 0x01]);
+    assert_eq!(std::fs::read_link(dest.join("bin")).unwrap(), Path::new("usr/bin"));
+
+    // existing nodes are skipped without force, and reported as events
+    let existing = Arc::new(Mutex::new(vec![]));
+    let events = existing.clone();
+    let options = ExtractOptions::new().on_event(move |event| {
+        if let ExtractEvent::Exists { path, .. } = event {
+            events.lock().unwrap().push(path.to_path_buf());
+        }
+    });
+    let report = fs.extract(&dest, &options).unwrap();
+    assert_eq!(report.skipped, 3);
+    assert_eq!(existing.lock().unwrap().len(), 3);
+
+    let report = fs.extract(&dest, &ExtractOptions::new().force(true)).unwrap();
+    assert!(report.is_ok(), "{:?}", report.errors);
+    assert_eq!(report.skipped, 0);
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_extract_host_dir_without_read_permission() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+    let mut fs = FilesystemWriter::default();
+    fs.push_dir("dir", NodeHeader { permissions: 0o311, ..header }).unwrap();
+    fs.push_file(Cursor::new(vec![0x00]), "dir/file", header).unwrap();
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    let fs = FilesystemReader::from_read_at(output.into_inner()).unwrap();
+
+    let tmp_dir = tempdir().unwrap();
+    let dest = tmp_dir.path().join("squashfs-root");
+    let report = fs.extract(&dest, &ExtractOptions::new()).unwrap();
+    assert!(report.is_ok(), "{:?}", report.errors);
+    let mode = std::fs::metadata(dest.join("dir")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o7777, 0o311);
+    assert_eq!(std::fs::read(dest.join("dir/file")).unwrap(), [0x00]);
+
+    // allow removing the temporary dir
+    std::fs::set_permissions(dest.join("dir"), std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_extract_host_symlink_escape() {
+    let fs = image();
+    let tmp_dir = tempdir().unwrap();
+    let dest = tmp_dir.path().join("squashfs-root");
+    let outside = tmp_dir.path().join("outside");
+    std::fs::create_dir_all(&dest).unwrap();
+    std::fs::create_dir_all(&outside).unwrap();
+
+    // symlink already in dest, where the image has a directory
+    std::os::unix::fs::symlink(&outside, dest.join("usr")).unwrap();
+
+    let report = fs.extract(&dest, &ExtractOptions::new().force(true)).unwrap();
+    assert!(!report.is_ok());
+    let failed: Vec<&Path> = report.errors.iter().map(|(path, _)| path.as_path()).collect();
+    assert!(failed.contains(&Path::new("/usr/other")));
+    assert!(failed.contains(&Path::new("/usr/bin/heyo")));
+    assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+}
+
+/// Image containing `file` at `/app.img`, and a text file
+fn nested_image(file: Vec<u8>) -> Vec<u8> {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_file(Cursor::new(file), "app.img", header).unwrap();
+    fs.push_file(Cursor::new(b"hsqs, not an image".to_vec()), "readme", header).unwrap();
+
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    output.into_inner()
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_extract_recursive() {
+    let inner = nested_image(b"data".to_vec());
+    let outer = nested_image(nested_image(inner));
+    let fs = FilesystemReader::from_read_at(outer).unwrap();
+
+    let extract = |options: ExtractOptions| {
+        let sink = MemorySink::new();
+        let report = fs.extract_with_sink(&sink, &options).unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors);
+        (report, sink.into_nodes())
+    };
+
+    // images in files are only extracted if asked for
+    let (report, nodes) = extract(ExtractOptions::new());
+    assert_eq!(report.extracted, 3);
+    assert!(!nodes.contains_key(Path::new("app.img.extracted")));
+
+    let (report, nodes) = extract(ExtractOptions::new().recursive(1));
+    assert_eq!(report.extracted, 6);
+    assert_eq!(nodes[Path::new("app.img.extracted")].1, MemoryNode::Dir);
+    assert!(nodes.contains_key(Path::new("app.img.extracted/app.img")));
+    assert!(nodes.contains_key(Path::new("app.img.extracted/readme")));
+    assert!(!nodes.contains_key(Path::new("app.img.extracted/app.img.extracted")));
+
+    let (report, nodes) = extract(ExtractOptions::new().recursive(8));
+    assert_eq!(report.extracted, 9);
+    let innermost = Path::new("app.img.extracted/app.img.extracted/app.img");
+    assert_eq!(nodes[innermost].1, MemoryNode::File(b"data".to_vec()));
+    assert!(!nodes.contains_key(Path::new("readme.extracted")));
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_extract_recursive_limits() {
+    use backhand::kind::{self, Kind};
+    use backhand::{BackhandError, ReadLimits};
+
+    // the nested image is small, but not the file in it
+    let inner = nested_image(vec![0; 0x10000]);
+    assert!(inner.len() < 0x8000);
+    let outer = nested_image(inner);
+    let kind = Kind::from_const(kind::LE_V4_0).unwrap();
+    let limits = ReadLimits { max_file_size: 0x8000, ..ReadLimits::default() };
+    let fs = FilesystemReader::from_read_at_with_limits(outer, 0, kind, limits).unwrap();
+
+    // the limits of the outer image also apply to the nested image
+    let sink = MemorySink::new();
+    let report = fs.extract_with_sink(&sink, &ExtractOptions::new().recursive(1)).unwrap();
+    assert_eq!(report.errors.len(), 1, "{:?}", report.errors);
+    let (path, error) = &report.errors[0];
+    assert_eq!(path, Path::new("/app.img.extracted"));
+    assert!(matches!(error.root_cause(), BackhandError::LimitExceeded { .. }), "{error:?}");
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use std::sync::Arc;
+
+use backhand::kind::{Kind, LE_V4_0};
+use backhand::{BackhandError, FilesystemReader, LazyFilesystemReader, ReadLimits};
+
+fn read(image: &[u8], limits: ReadLimits) -> Result<FilesystemReader<'static>, BackhandError> {
+    let kind = Kind { inner: Arc::new(LE_V4_0) };
+    FilesystemReader::from_read_at_with_limits(image.to_vec(), 0, kind, limits)
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_read_limits() {
+    let image = common::image();
+    read(&image, ReadLimits::default()).unwrap();
+    read(&image, ReadLimits::unlimited()).unwrap();
+
+    let limits = [
+        ReadLimits { max_inodes: 2, ..ReadLimits::default() },
+        ReadLimits { max_allocation: 0x10, ..ReadLimits::default() },
+        ReadLimits { max_dir_depth: 2, ..ReadLimits::default() },
+        ReadLimits { max_block_size: 0x1000, ..ReadLimits::default() },
+        ReadLimits { max_file_size: 0xff, ..ReadLimits::default() },
+    ];
+    for limits in limits {
+        let err = read(&image, limits).unwrap_err();
+        assert!(
+            matches!(err.root_cause(), BackhandError::LimitExceeded { .. }),
+            "{limits:?}: {err:?}"
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_lazy_read_limits() {
+    let image = common::image();
+    let read = |limits: ReadLimits| -> Result<Vec<_>, BackhandError> {
+        let kind = Kind { inner: Arc::new(LE_V4_0) };
+        let fs = LazyFilesystemReader::from_read_at_with_limits(&image[..], 0, kind, limits)?;
+        let nodes = fs.files().collect::<Result<_, _>>()?;
+        Ok(nodes)
+    };
+    assert_eq!(read(ReadLimits::default()).unwrap().len(), 6);
+
+    // inodes are not counted, as they are only decoded when requested
+    let limits = [
+        ReadLimits { max_dir_depth: 2, ..ReadLimits::default() },
+        ReadLimits { max_block_size: 0x1000, ..ReadLimits::default() },
+        ReadLimits { max_file_size: 0xff, ..ReadLimits::default() },
+    ];
+    for limits in limits {
+        let err = read(limits).unwrap_err();
+        assert!(
+            matches!(err.root_cause(), BackhandError::LimitExceeded { .. }),
+            "{limits:?}: {err:?}"
+        );
+    }
+}
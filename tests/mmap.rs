@@ -0,0 +1,117 @@
+#![cfg(all(feature = "mmap", feature = "xz"))]
+
+use std::io::{BufRead, Cursor, Read, Write};
+
+use backhand::{
+    BasicFile, FilesystemReader, FilesystemWriter, InnerNode, NodeHeader, DEFAULT_BLOCK_SIZE,
+};
+use tempfile::tempfile;
+
+const BLOCK_SIZE: usize = DEFAULT_BLOCK_SIZE as usize;
+
+/// Bytes that don't compress, stored as uncompressed blocks
+fn random(len: usize) -> Vec<u8> {
+    let mut state = 1_u32;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+/// Bytes that compress, stored as compressed blocks
+fn text(len: usize) -> Vec<u8> {
+    b"squashfs".iter().copied().cycle().take(len).collect()
+}
+
+/// Image with an uncompressed and a compressed file, both with a fragment tail
+fn image() -> Vec<u8> {
+    let header = NodeHeader { permissions: 0o644, uid: 0, gid: 0, mtime: 0 };
+    let mut fs = FilesystemWriter::default();
+    fs.push_file(Cursor::new(random(3 * BLOCK_SIZE + 100)), "random", header).unwrap();
+    fs.push_file(Cursor::new(text(3 * BLOCK_SIZE + 100)), "text", header).unwrap();
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    output.into_inner()
+}
+
+fn basic<'a>(fs: &'a FilesystemReader, path: &str) -> &'a BasicFile {
+    match &fs.node(path).unwrap().inner {
+        InnerNode::File(file) => &file.basic,
+        _ => panic!("{path} is not a file"),
+    }
+}
+
+#[test]
+fn test_from_mmap() {
+    let mut file = tempfile().unwrap();
+    file.write_all(&image()).unwrap();
+
+    let fs = unsafe { FilesystemReader::from_mmap(&file) }.unwrap();
+    for (path, expected) in
+        [("/random", random(3 * BLOCK_SIZE + 100)), ("/text", text(3 * BLOCK_SIZE + 100))]
+    {
+        let mut bytes = vec![];
+        fs.open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        assert!(bytes == expected, "{path}");
+
+        // also through the sequential reader, with and without read-ahead
+        for read_ahead in [0, 4] {
+            let (mut buf_read, mut buf_decompress) = (vec![], vec![]);
+            let mut reader = fs
+                .file(basic(&fs, path))
+                .reader(&mut buf_read, &mut buf_decompress)
+                .read_ahead(read_ahead);
+            let mut bytes = vec![];
+            reader.read_to_end(&mut bytes).unwrap();
+            assert!(bytes == expected, "{path} read_ahead({read_ahead})");
+        }
+    }
+}
+
+#[test]
+fn test_mmap_borrowed_blocks() {
+    let mut file = tempfile().unwrap();
+    file.write_all(&image()).unwrap();
+    let mmap = unsafe { memmap2::Mmap::map(&file) }.unwrap();
+    let image = mmap.as_ptr_range();
+
+    let fs = FilesystemReader::from_read_at(&mmap).unwrap();
+    for (path, expected, borrowed) in [
+        ("/random", random(3 * BLOCK_SIZE + 100), true),
+        ("/text", text(3 * BLOCK_SIZE + 100), false),
+    ] {
+        assert_eq!(basic(&fs, path).block_sizes[0].uncompressed(), borrowed);
+
+        let (mut buf_read, mut buf_decompress) = (vec![], vec![]);
+        let mut reader = fs.file(basic(&fs, path)).reader(&mut buf_read, &mut buf_decompress);
+        let mut bytes = vec![];
+        let mut blocks = 0;
+        loop {
+            let buf = reader.fill_buf().unwrap();
+            if buf.is_empty() {
+                break;
+            }
+            // uncompressed data blocks are returned directly from the mapped image, the
+            // fragment tail is always copied
+            let in_image = image.contains(&buf.as_ptr());
+            assert_eq!(in_image, borrowed && blocks < 3, "{path} block {blocks}");
+            if blocks < 3 {
+                assert_eq!(buf.len(), BLOCK_SIZE);
+            }
+
+            // consume in two steps, the rest of the block is returned by the next fill_buf
+            let half = buf.len() / 2;
+            bytes.extend_from_slice(&buf[..half]);
+            reader.consume(half);
+            let buf = reader.fill_buf().unwrap();
+            let len = buf.len();
+            bytes.extend_from_slice(buf);
+            reader.consume(len);
+            blocks += 1;
+        }
+        assert_eq!(blocks, 4);
+        assert!(bytes == expected, "{path}");
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use backhand::{BackhandError, FilesystemReader, FilesystemWriter, InnerNode, NodeHeader};
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_path_lookup() {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_dir_all("usr/bin", header).unwrap();
+    fs.push_file(Cursor::new(vec![0x00, 0x01]), "usr/bin/heyo", header).unwrap();
+    fs.push_file(Cursor::new(vec![0x02]), "usr/bin/other", header).unwrap();
+    fs.push_symlink("usr/bin", "bin", header).unwrap();
+    fs.push_symlink("heyo", "usr/bin/link", header).unwrap();
+    fs.push_symlink("../../bin/link", "usr/bin/relative", header).unwrap();
+    fs.push_symlink("/loop_b", "loop_a", header).unwrap();
+    fs.push_symlink("/loop_a", "loop_b", header).unwrap();
+
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    output.set_position(0);
+    let fs = FilesystemReader::from_reader(output).unwrap();
+
+    // node doesn't follow the last symlink, metadata does
+    let node = fs.node("/bin/link").unwrap();
+    assert_eq!(node.fullpath, Path::new("/usr/bin/link"));
+    assert!(matches!(node.inner, InnerNode::Symlink(_)));
+    let node = fs.metadata("bin/link").unwrap();
+    assert_eq!(node.fullpath, Path::new("/usr/bin/heyo"));
+    assert!(matches!(node.inner, InnerNode::File(_)));
+
+    assert_eq!(fs.canonicalize("/usr/bin/relative").unwrap(), Path::new("/usr/bin/heyo"));
+    assert_eq!(fs.canonicalize("/usr/../usr/./bin").unwrap(), Path::new("/usr/bin"));
+
+    // `..` after a symlink is the parent of its target, same as POSIX path resolution
+    assert_eq!(fs.canonicalize("/bin/..").unwrap(), Path::new("/usr"));
+    assert_eq!(fs.canonicalize("/bin/../bin/./heyo").unwrap(), Path::new("/usr/bin/heyo"));
+    assert!(matches!(fs.node("/bin/../usr"), Err(BackhandError::FileNotFound)));
+    assert_eq!(fs.read_link("/usr/bin/link").unwrap(), Path::new("heyo"));
+
+    let names: Vec<PathBuf> = fs.read_dir("/bin").unwrap().map(|n| n.fullpath.clone()).collect();
+    assert_eq!(
+        names,
+        [
+            Path::new("/usr/bin/heyo"),
+            Path::new("/usr/bin/link"),
+            Path::new("/usr/bin/other"),
+            Path::new("/usr/bin/relative"),
+        ]
+    );
+
+    assert!(matches!(fs.node("/usr/nothing"), Err(BackhandError::FileNotFound)));
+    assert!(matches!(fs.node("/usr/bin/heyo/file"), Err(BackhandError::NotADirectory)));
+    assert!(matches!(fs.read_dir("/usr/bin/heyo"), Err(BackhandError::NotADirectory)));
+    assert!(matches!(fs.read_link("/usr/bin/heyo"), Err(BackhandError::NotASymlink)));
+    assert!(matches!(fs.metadata("/loop_a"), Err(BackhandError::TooManySymlinks)));
+}
@@ -90,3 +90,99 @@ fn test_raw_00() {
         test_unsquashfs(&new_path, &control_new_path, None);
     }
 }
+
+/// Bytes that compress, but not to nothing
+fn data(seed: u32, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            b"abcdefgh"[(state >> 16) as usize % 8]
+        })
+        .collect()
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_write_threads_identical() {
+    use std::io::{Cursor, Read};
+
+    use backhand::FilesystemReader;
+
+    let write = |threads: usize| {
+        let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+        let mut fs = FilesystemWriter::default();
+        fs.set_threads(threads);
+        fs.push_dir("small", header).unwrap();
+        fs.push_dir("big", header).unwrap();
+        // enough small files for multiple batches of fragment blocks
+        for i in 0..1000 {
+            fs.push_file(Cursor::new(data(i, 3000)), format!("small/{i}"), header).unwrap();
+        }
+        // files with many data blocks and a fragment tail
+        for i in 0..3 {
+            let file = data(i, 20 * DEFAULT_BLOCK_SIZE as usize + 100);
+            fs.push_file(Cursor::new(file), format!("big/{i}"), header).unwrap();
+        }
+        let mut output = Cursor::new(vec![]);
+        fs.write(&mut output).unwrap();
+        output.into_inner()
+    };
+
+    let single = write(1);
+    assert!(single == write(4), "image differs with 4 threads");
+    assert!(single == write(0), "image differs with the default amount of threads");
+
+    let fs = FilesystemReader::from_read_at(single).unwrap();
+    for (path, expected) in [("/small/999", data(999, 3000)), ("/big/2", data(2, 2_621_540))] {
+        let mut file = fs.open(path).unwrap();
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes).unwrap();
+        assert!(bytes == expected, "{path}");
+    }
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_read_ahead_identical() {
+    use std::io::{Cursor, Read};
+
+    use backhand::{DataSize, FilesystemReader, InnerNode};
+
+    // data blocks, a block of zeros made sparse below, and a fragment tail
+    let block_size = DEFAULT_BLOCK_SIZE as usize;
+    let mut file = data(0, 20 * block_size);
+    file.resize(21 * block_size, 0);
+    file.extend(data(1, 100));
+
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+    let mut fs = FilesystemWriter::default();
+    fs.push_file(Cursor::new(file.clone()), "file", header).unwrap();
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+
+    let fs = FilesystemReader::from_read_at(output.into_inner()).unwrap();
+    let basic = fs
+        .files()
+        .find_map(|node| match &node.inner {
+            InnerNode::File(file) => Some(file.basic.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(basic.block_sizes.len(), 21);
+    assert_ne!(basic.frag_index, u32::MAX);
+    // the writer stores blocks of zeros, same as squashfs-tools with -no-sparse
+    let mut sparse = basic.clone();
+    sparse.block_sizes[20] = DataSize::new(0, false);
+
+    for basic in [&basic, &sparse] {
+        for read_ahead in [0, 1, 4, 64] {
+            let (mut buf_read, mut buf_decompress) = (vec![], vec![]);
+            let mut reader =
+                fs.file(basic).reader(&mut buf_read, &mut buf_decompress).read_ahead(read_ahead);
+            let mut bytes = vec![];
+            reader.read_to_end(&mut bytes).unwrap();
+            assert!(bytes == file, "read_ahead({read_ahead})");
+        }
+    }
+}
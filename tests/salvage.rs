@@ -0,0 +1,120 @@
+use std::io::{Cursor, Read};
+use std::sync::Arc;
+
+use backhand::kind::{Kind, LE_V4_0};
+use backhand::{FilesystemReader, FilesystemWriter, InnerNode, NodeHeader, Squashfs};
+
+fn kind() -> Kind {
+    Kind { inner: Arc::new(LE_V4_0) }
+}
+
+fn image() -> Vec<u8> {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_dir_all("usr/bin", header).unwrap();
+    // enough entries for the directory table to be compressed
+    for i in 0..50 {
+        let path = format!("usr/bin/file_{i}");
+        fs.push_file(Cursor::new(vec![i as u8; 0x100]), path, header).unwrap();
+    }
+    fs.push_file(Cursor::new(vec![0x01; 0x30000]), "big", header).unwrap();
+
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    output.into_inner()
+}
+
+fn read_file(filesystem: &FilesystemReader, path: &str) -> std::io::Result<Vec<u8>> {
+    let InnerNode::File(file) = &filesystem.node(path).unwrap().inner else {
+        panic!("{path} isn't a file");
+    };
+    let (mut buf_read, mut buf_decompress) = filesystem.alloc_read_buffers();
+    let file = filesystem.file(&file.basic).with_path(path.as_ref());
+    let mut reader = file.reader(&mut buf_read, &mut buf_decompress);
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_salvage_data_block() {
+    let mut image = image();
+    let filesystem =
+        FilesystemReader::from_read_at_with_offset_and_kind(&image, 0, kind()).unwrap();
+    let InnerNode::File(big) = &filesystem.node("/big").unwrap().inner else { panic!() };
+    let blocks_start = big.basic.blocks_start as usize;
+    drop(filesystem);
+
+    image[blocks_start..blocks_start + 0x10].fill(0xff);
+    let filesystem =
+        FilesystemReader::from_read_at_with_offset_and_kind(&image, 0, kind()).unwrap();
+    assert!(read_file(&filesystem, "/big").is_err());
+    assert!(filesystem.take_damage().is_empty());
+
+    let squashfs = Squashfs::from_read_at_salvage(&image, 0, kind()).unwrap();
+    let filesystem = squashfs.into_filesystem_reader().unwrap();
+    assert!(filesystem.take_damage().is_empty());
+    let bytes = read_file(&filesystem, "/big").unwrap();
+    assert_eq!(bytes.len(), 0x30000);
+    assert!(bytes[..0x20000].iter().all(|b| *b == 0x00));
+    assert!(bytes[0x20000..].iter().all(|b| *b == 0x01));
+    assert_eq!(read_file(&filesystem, "/usr/bin/file_3").unwrap(), vec![3; 0x100]);
+
+    let damage = filesystem.take_damage();
+    assert_eq!(damage.len(), 1, "{damage:?}");
+    assert_eq!(damage[0].offset, blocks_start as u64);
+    assert_eq!(damage[0].structure, "data block");
+    assert_eq!(damage[0].path.as_deref(), Some("/big".as_ref()));
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_salvage_directory_table() {
+    let mut image = image();
+    let squashfs = Squashfs::from_reader(Cursor::new(&image)).unwrap();
+    let dir_table = squashfs.superblock.dir_table as usize;
+    drop(squashfs);
+
+    // keep the length of the metadata block, corrupting its compressed data
+    image[dir_table + 2..dir_table + 0x12].fill(0xff);
+    assert!(Squashfs::from_reader(Cursor::new(&image)).is_err());
+
+    let squashfs = Squashfs::from_read_at_salvage(&image, 0, kind()).unwrap();
+    let filesystem = squashfs.into_filesystem_reader().unwrap();
+    // only the root is left
+    assert_eq!(filesystem.files().count(), 1);
+
+    let damage = filesystem.take_damage();
+    assert!(damage.iter().any(|damage| damage.structure == "directory table"), "{damage:?}");
+    assert!(damage.iter().any(|damage| damage.path.as_deref() == Some("/".as_ref())));
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_salvage_reader_path() {
+    let mut image = image();
+    let filesystem =
+        FilesystemReader::from_read_at_with_offset_and_kind(&image, 0, kind()).unwrap();
+    let InnerNode::File(big) = &filesystem.node("/big").unwrap().inner else { panic!() };
+    let blocks_start = big.basic.blocks_start as usize;
+    drop(filesystem);
+    image[blocks_start..blocks_start + 0x10].fill(0xff);
+
+    let squashfs = Squashfs::from_read_at_salvage(&image, 0, kind()).unwrap();
+    let filesystem = squashfs.into_filesystem_reader().unwrap();
+    let InnerNode::File(big) = &filesystem.node("/big").unwrap().inner else { panic!() };
+    for read_ahead in [0, 4] {
+        let (mut buf_read, mut buf_decompress) = filesystem.alloc_read_buffers();
+        let file = filesystem.file(&big.basic).with_path("/big".as_ref());
+        let mut reader = file.reader(&mut buf_read, &mut buf_decompress).read_ahead(read_ahead);
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 0x30000);
+
+        let damage = filesystem.take_damage();
+        assert_eq!(damage.len(), 1, "read_ahead({read_ahead}): {damage:?}");
+        assert_eq!(damage[0].path.as_deref(), Some("/big".as_ref()));
+    }
+}
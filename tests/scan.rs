@@ -0,0 +1,37 @@
+mod common;
+
+use backhand::kind::{self, Kind};
+use backhand::{FilesystemReader, Squashfs};
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_scan() {
+    let le = common::image_with_kind(Kind::from_const(kind::LE_V4_0).unwrap());
+    let be = common::image_with_kind(Kind::from_const(kind::BE_V4_0).unwrap());
+
+    // magics without a valid superblock, and images at unaligned offsets
+    let mut blob = b"junk hsqs junk sqsh".to_vec();
+    let le_offset = blob.len() as u64;
+    blob.extend_from_slice(&le);
+    blob.extend_from_slice(b"hsqs\x00\x00\x00");
+    let be_offset = blob.len() as u64;
+    blob.extend_from_slice(&be);
+    blob.extend_from_slice(b"sqsh");
+
+    let found = Squashfs::scan(&blob).unwrap();
+    let found: Vec<_> = found.iter().filter(|image| image.name != "avm_be_v4_0").collect();
+    assert_eq!(found.len(), 2, "{found:?}");
+    assert_eq!((found[0].offset, found[0].name), (le_offset, "le_v4_0"));
+    assert_eq!((found[1].offset, found[1].name), (be_offset, "be_v4_0"));
+    assert!(found[0].bytes_used <= le.len() as u64);
+    assert!(found[1].bytes_used <= be.len() as u64);
+
+    for image in found {
+        let kind = Kind::from_kind(&image.kind);
+        let filesystem =
+            FilesystemReader::from_read_at_with_offset_and_kind(&blob, image.offset, kind).unwrap();
+        assert!(filesystem.node("/usr/bin/heyo").is_ok());
+    }
+
+    assert!(Squashfs::scan(&b"no images"[..]).unwrap().is_empty());
+}
@@ -0,0 +1,107 @@
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use backhand::{
+    BackhandError, FilesystemReader, FilesystemWriter, InnerNode, NodeHeader, OwnedFile,
+    OwnedFilesystemReader,
+};
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_seek_file() {
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+
+    // two full blocks and a tail end stored in a fragment
+    let data: Vec<u8> = (0..0x20000 * 2 + 1000).map(|i| (i % 251) as u8).collect();
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_file(Cursor::new(data.clone()), "large", header).unwrap();
+    fs.push_file(Cursor::new(vec![]), "empty", header).unwrap();
+    fs.push_symlink("large", "link", header).unwrap();
+    fs.push_dir("dir", header).unwrap();
+
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+    output.set_position(0);
+    let fs = FilesystemReader::from_reader(output).unwrap();
+
+    let mut file = fs.open("/link").unwrap();
+    assert_eq!(file.len(), data.len() as u64);
+
+    // tail end, inside the fragment
+    let mut buf = [0; 100];
+    file.seek(SeekFrom::End(-100)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data[data.len() - 100..]);
+    assert_eq!(file.read(&mut buf).unwrap(), 0);
+
+    // crossing from the first to the second block
+    let mut buf = [0; 200];
+    file.read_exact_at(0x20000 - 100, &mut buf).unwrap();
+    assert_eq!(buf, data[0x20000 - 100..0x20000 + 100]);
+
+    // read_at doesn't move the position used by Read
+    file.seek(SeekFrom::Start(10)).unwrap();
+    assert_eq!(file.read_at(0x20000 - 10, &mut buf).unwrap(), 10);
+    let mut buf = [0; 4];
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data[10..14]);
+    assert!(file.seek(SeekFrom::Current(-15)).is_err());
+
+    let mut all = vec![];
+    file.rewind().unwrap();
+    file.read_to_end(&mut all).unwrap();
+    assert_eq!(all, data);
+
+    // same as the streaming reader
+    let node = fs.node("/large").unwrap();
+    let InnerNode::File(file) = &node.inner else { panic!() };
+    let mut streamed = vec![];
+    let (mut buf_read, mut buf_decompress) = fs.alloc_read_buffers();
+    fs.file(&file.basic)
+        .reader(&mut buf_read, &mut buf_decompress)
+        .read_to_end(&mut streamed)
+        .unwrap();
+    assert_eq!(streamed, all);
+
+    let mut empty = fs.open("/empty").unwrap();
+    assert!(empty.is_empty());
+    assert_eq!(empty.read(&mut [0; 4]).unwrap(), 0);
+
+    assert!(matches!(fs.open("/dir"), Err(BackhandError::NotAFile)));
+    assert!(matches!(fs.open("/nothing"), Err(BackhandError::FileNotFound)));
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_owned_file() {
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<OwnedFilesystemReader>();
+    assert_send_sync::<OwnedFile>();
+
+    let header = NodeHeader { permissions: 0o755, uid: 0, gid: 0, mtime: 0 };
+    let data: Vec<u8> = (0..0x20000 + 10).map(|i| (i % 251) as u8).collect();
+
+    let mut fs = FilesystemWriter::default();
+    fs.push_file(Cursor::new(data.clone()), "file", header).unwrap();
+    let mut output = Cursor::new(vec![]);
+    fs.write(&mut output).unwrap();
+
+    let fs = OwnedFilesystemReader::from_read_at(output.into_inner()).unwrap();
+    let mut file = fs.open("/file").unwrap();
+    drop(fs);
+
+    file.seek(SeekFrom::Start(0x20000)).unwrap();
+    let mut clone = file.clone();
+    let handle = std::thread::spawn(move || {
+        let mut tail = vec![];
+        clone.read_to_end(&mut tail).unwrap();
+        tail
+    });
+    assert_eq!(handle.join().unwrap(), data[0x20000..]);
+
+    let mut all = vec![];
+    file.rewind().unwrap();
+    file.read_to_end(&mut all).unwrap();
+    assert_eq!(all, data);
+    assert_eq!(file.filesystem().files().count(), 2);
+}
@@ -4,7 +4,7 @@ use std::io::{BufReader, BufWriter};
 
 use assert_cmd::prelude::*;
 use assert_cmd::Command;
-use backhand::{FilesystemReader, FilesystemWriter};
+use backhand::{FilesystemReader, FilesystemWriter, LazyFilesystemReader};
 use common::{test_unsquashfs, test_unsquashfs_list};
 use tempfile::tempdir;
 use test_assets::TestAssetDef;
@@ -99,6 +99,24 @@ fn full_test(
     let new_comp_opts = written_new_filesystem.compression_options;
     assert_eq!(og_comp_opts, new_comp_opts);
 
+    // assert that positional reads see the same filesystem
+    info!("calling from_read_at");
+    let created_file = File::open(&new_path).unwrap();
+    let read_at_filesystem =
+        FilesystemReader::from_read_at_with_offset(created_file, offset).unwrap();
+    assert_eq!(written_new_filesystem.files().count(), read_at_filesystem.files().count());
+
+    // assert that lazy reading finds the same nodes
+    info!("calling lazy from_reader");
+    let created_file = BufReader::new(File::open(&new_path).unwrap());
+    let lazy_filesystem =
+        LazyFilesystemReader::from_reader_with_offset(created_file, offset).unwrap();
+    let mut lazy_paths: Vec<_> =
+        lazy_filesystem.files().map(|node| node.unwrap().fullpath).collect();
+    lazy_paths.sort();
+    let paths: Vec<_> = written_new_filesystem.files().map(|node| node.fullpath.clone()).collect();
+    assert_eq!(paths, lazy_paths);
+
     match verify {
         Verify::Extract => {
             #[cfg(feature = "__test_unsquashfs")]
@@ -79,3 +79,236 @@ fn test_unsquashfs_cli_auto_offset() {
         cmd.assert().code(&[0] as &[i32]);
     }
 }
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_unsquashfs_cli_long_list_json() {
+    let (_tmp_dir, image_path) = common::temp_image(&common::cli_image());
+    let image_path = image_path.as_str();
+
+    let stdout = |args: &[&str]| {
+        let output = common::run("unsquashfs", &[args, &["--quiet", image_path][..]].concat());
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let long = stdout(&["-ll"]);
+    let lines: Vec<&str> = long.lines().collect();
+    assert!(lines.contains(&"-rwsr-xr-x 1000/100             2 2023-11-14 22:13 /usr/heyo"));
+    assert!(lines.contains(&"crw-rw-rw- 0/0             1,   3 1970-01-01 00:00 /dev/null"));
+    assert!(lines.contains(&"lrwxrwxrwx 0/0                  3 1970-01-01 00:00 /bin -> usr"));
+
+    let json = stdout(&["-l", "--json"]);
+    let lines: Vec<&str> = json.lines().collect();
+    assert_eq!(lines.len(), 6);
+    assert!(lines.contains(
+        &r#"{"path":"/usr/heyo","type":"file","mode":"-rwsr-xr-x","permissions":2541,"uid":1000,"gid":100,"mtime":1700000000,"size":2}"#
+    ));
+    assert!(lines.contains(
+        &r#"{"path":"/dev/null","type":"char_device","mode":"crw-rw-rw-","permissions":438,"uid":0,"gid":0,"mtime":0,"major":1,"minor":3}"#
+    ));
+    assert!(lines.contains(
+        &r#"{"path":"/bin","type":"symlink","mode":"lrwxrwxrwx","permissions":511,"uid":0,"gid":0,"mtime":0,"target":"usr"}"#
+    ));
+
+    let stat = stdout(&["--stat", "--json"]);
+    assert!(stat.starts_with(
+        r#"{"offset":0,"kind":"le_v4_0","superblock":{"inode_count":6,"mod_time":0,"#
+    ));
+    assert!(stat.contains(r#""compressor":"xz""#));
+    assert_eq!(stat.lines().count(), 1);
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_unsquashfs_cli_list_json_escaping() {
+    use std::ffi::OsStr;
+    use std::io::Cursor;
+    use std::os::unix::ffi::OsStrExt;
+
+    use backhand::{FilesystemWriter, NodeHeader};
+    use serde_json::Value;
+    let header = NodeHeader { permissions: 0o644, uid: 0, gid: 0, mtime: 0 };
+    let mut fs = FilesystemWriter::default();
+    fs.push_file(Cursor::new(vec![]), "quote\"back\\slash\ttab\nnewline\u{1}", header).unwrap();
+    fs.push_file(Cursor::new(vec![]), OsStr::from_bytes(b"latin1-\xe9"), header).unwrap();
+    fs.push_symlink(OsStr::from_bytes(b"\xff"), "link", header).unwrap();
+    let mut image = Cursor::new(vec![]);
+    fs.write(&mut image).unwrap();
+    let (_tmp_dir, image_path) = common::temp_image(image.get_ref());
+
+    let output = common::run("unsquashfs", &["-l", "--json", "--quiet", &image_path]);
+    assert!(output.status.success());
+    let records: Vec<Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let record = |path: &str| records.iter().find(|record| record["path"] == path).unwrap();
+
+    // escaped names round-trip
+    let escaped = record("/quote\"back\\slash\ttab\nnewline\u{1}");
+    assert!(escaped.get("path_bytes").is_none());
+
+    // names that aren't UTF-8 are lossy, with their bytes kept
+    let latin1 = record("/latin1-\u{fffd}");
+    assert_eq!(latin1["path_bytes"], serde_json::json!(b"/latin1-\xe9"));
+    let link = record("/link");
+    assert_eq!(link["target"], "\u{fffd}");
+    assert_eq!(link["target_bytes"], serde_json::json!([0xff]));
+}
+
+#[test]
+#[cfg(all(feature = "xz", feature = "regex"))]
+fn test_unsquashfs_cli_patterns() {
+    let (tmp_dir, image_path) = common::temp_image(&common::cli_image());
+    let image_path = image_path.as_str();
+
+    let list = |args: &[&str]| {
+        let output =
+            common::run("unsquashfs", &[args, &["-l", "--quiet", image_path][..]].concat());
+        (output.status.success(), String::from_utf8(output.stdout).unwrap())
+    };
+
+    let (success, stdout) =
+        list(&["--path-filter", "/usr", "--path-filter", "/dev", "--exclude", "null"]);
+    assert!(success);
+    assert_eq!(stdout, "/\n/dev\n/usr\n/usr/heyo\n");
+
+    let (success, stdout) = list(&["--exclude-regex", "^/(usr|dev)"]);
+    assert!(success);
+    assert_eq!(stdout, "/\n/bin\n");
+
+    let patterns = tmp_dir.path().join("patterns");
+    std::fs::write(&patterns, "# devices\n/dev/*\nregex:^/bin$\n").unwrap();
+    let (success, stdout) = list(&["--exclude-from", patterns.to_str().unwrap()]);
+    assert!(success);
+    assert_eq!(stdout, "/\n/dev\n/usr\n/usr/heyo\n");
+
+    let (success, _) = list(&["--path-filter", "/usr", "--path-filter", "/nothing"]);
+    assert!(!success);
+    let (success, _) = list(&["--exclude-regex", "("]);
+    assert!(!success);
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_unsquashfs_cli_compat() {
+    let (tmp_dir, image_path) = common::temp_image(&common::cli_image());
+    let image_path = image_path.as_str();
+
+    let compat = |args: &[&str]| {
+        let output = common::run("unsquashfs", &[&["--compat"][..], args].concat());
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // files to extract after the filesystem, listed relative to the destination
+    let list = compat(&["-ls", image_path, "usr"]);
+    assert_eq!(list, "squashfs-root\nsquashfs-root/usr\nsquashfs-root/usr/heyo\n");
+    let list = compat(&["-lls", "-d", "out", image_path, "/bin"]);
+    assert!(list.ends_with("1970-01-01 00:00 out/bin -> usr\n"), "{list}");
+
+    let stat = compat(&["-s", image_path]);
+    let lines: Vec<&str> = stat.lines().collect();
+    assert_eq!(lines[0], format!("Found a valid SQUASHFS 4:0 superblock on {image_path}."));
+    assert_eq!(lines[1], "Creation or last append time Thu Jan  1 00:00:00 1970");
+    assert!(lines.contains(&"Compression xz"));
+    assert!(lines.contains(&"Number of inodes 6"));
+    assert_eq!(compat(&["-mkfs-time", image_path]), "0\n");
+
+    // extract the files listed in an extract file
+    let extract_file = tmp_dir.path().join("extract");
+    std::fs::write(&extract_file, "usr/heyo\n\n").unwrap();
+    let dest = tmp_dir.path().join("squashfs-root");
+    compat(&[
+        "-q",
+        "-ef",
+        extract_file.to_str().unwrap(),
+        "-d",
+        dest.to_str().unwrap(),
+        "-ignore-errors",
+        "-p",
+        "2",
+        image_path,
+    ]);
+    assert_eq!(std::fs::read(dest.join("usr/heyo")).unwrap(), [0x00, 0x01]);
+    assert!(!dest.join("bin").exists());
+
+    assert!(!common::run("unsquashfs", &["--compat", "-unknown", image_path]).status.success());
+    let output = common::run("unsquashfs", &["--compat", "-p", "0", "-ls", image_path]);
+    assert!(!output.status.success());
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_unsquashfs_cli_stdout() {
+    let (_tmp_dir, image_path) = common::temp_image(&common::cli_image());
+    let image_path = image_path.as_str();
+
+    let run = |args: &[&str]| {
+        let output = common::run("unsquashfs", args);
+        (output.status.success(), output.stdout)
+    };
+
+    // symlinks are followed
+    assert_eq!(run(&["cat", image_path, "/usr/heyo", "bin/heyo"]), (true, vec![0, 1, 0, 1]));
+    assert!(!run(&["cat", image_path, "/usr"]).0);
+    assert!(!run(&["cat", image_path, "/nothing"]).0);
+
+    let stdout = run(&["--stdout", "--path-filter", "/usr/heyo", "--quiet", image_path]);
+    assert_eq!(stdout, (true, vec![0, 1]));
+
+    let (success, tar) = run(&["--tar", "--path-filter", "/usr", image_path]);
+    assert!(success);
+    assert_eq!(tar.len() % 512, 0);
+    assert_eq!(&tar[..5], b"usr/\0");
+    assert_eq!(&tar[512..521], b"usr/heyo\0");
+    assert_eq!(&tar[512 + 257..512 + 262], b"ustar");
+    assert_eq!(&tar[1024..1026], [0, 1]);
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_unsquashfs_cli_scan_failed_image() {
+    use std::io::Cursor;
+
+    use backhand::Squashfs;
+
+    // an image with a valid superblock but a corrupted directory table, followed by a valid image
+    let mut broken = common::cli_image();
+    let squashfs = Squashfs::from_reader(Cursor::new(&broken)).unwrap();
+    let dir_table = squashfs.superblock.dir_table as usize;
+    drop(squashfs);
+    broken[dir_table + 2..dir_table + 0x12].fill(0xff);
+    let (_tmp_dir, image_path) = common::temp_image(&[broken, common::cli_image()].concat());
+
+    let output = common::run("unsquashfs", &["--scan", "-l", "--quiet", &image_path]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("/usr/heyo"), "{stdout}");
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Failed"));
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_unsquashfs_cli_scan_json() {
+    use serde_json::Value;
+
+    let image = common::cli_image();
+    let (_tmp_dir, image_path) = common::temp_image(&[image.clone(), image.clone()].concat());
+
+    let output = common::run("unsquashfs", &["--scan", "-l", "--json", "--quiet", &image_path]);
+    assert!(output.status.success());
+    let records: Vec<Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    // only records, each with the offset of its image
+    assert_eq!(records.len(), 12);
+    let heyo: Vec<&Value> = records.iter().filter(|record| record["path"] == "/usr/heyo").collect();
+    assert_eq!(heyo.len(), 2);
+    assert_eq!(heyo[0]["image_offset"], 0);
+    assert_eq!(heyo[1]["image_offset"], image.len());
+}
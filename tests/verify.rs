@@ -0,0 +1,29 @@
+mod common;
+
+use std::io::Cursor;
+use std::sync::Arc;
+
+use backhand::kind::{Kind, LE_V4_0};
+use backhand::Squashfs;
+
+#[test]
+#[cfg(feature = "xz")]
+fn test_verify() {
+    let kind = Kind { inner: Arc::new(LE_V4_0) };
+    let mut image = common::image();
+
+    let report = Squashfs::verify(Cursor::new(&image), 0, Kind::from_kind(&kind)).unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!(report.inodes, 6);
+    assert_eq!(report.directories, 3);
+    assert_ne!(report.data_blocks, 0);
+
+    // corrupt the first data block, right after the superblock
+    image[0x60..0x70].fill(0xff);
+    let report = Squashfs::verify(Cursor::new(&image), 0, Kind::from_kind(&kind)).unwrap();
+    assert!(!report.is_ok());
+    assert!(report.problems.iter().all(|p| p.offset >= 0x60), "{:?}", report.problems);
+
+    // a missing superblock can't be verified
+    assert!(Squashfs::verify(Cursor::new(&image[..0x10]), 0, kind).is_err());
+}